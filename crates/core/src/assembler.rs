@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::ast::{self, Statement};
+use crate::instruction_set::{InstructionSet, OperandKind, Operation};
 
 pub const OPCODE_ADD: usize = 1;
 pub const OPCODE_SUB: usize = 2;
@@ -12,97 +13,346 @@ pub const OPCODE_BRP: usize = 8;
 pub const OPCODE_INP: usize = 9;
 pub const OPCODE_OUT: usize = 9;
 pub const OPCODE_HLT: usize = 0;
+pub const OPCODE_INA: usize = 9;
+pub const OPCODE_OTC: usize = 9;
+pub const OPCODE_LDA_INDIRECT: usize = 4;
+pub const OPCODE_STA_INDIRECT: usize = 0;
+pub const OPCODE_CAL: usize = 4;
+pub const OPCODE_RET: usize = 9;
 
-pub const ASSEMBLED_OPCODE_ADD: usize = OPCODE_ADD * 100;
-pub const ASSEMBLED_OPCODE_SUB: usize = OPCODE_SUB * 100;
-pub const ASSEMBLED_OPCODE_STA: usize = OPCODE_STA * 100;
-pub const ASSEMBLED_OPCODE_LDA: usize = OPCODE_LDA * 100;
-pub const ASSEMBLED_OPCODE_BRA: usize = OPCODE_BRA * 100;
-pub const ASSEMBLED_OPCODE_BRZ: usize = OPCODE_BRZ * 100;
-pub const ASSEMBLED_OPCODE_BRP: usize = OPCODE_BRP * 100;
-pub const ASSEMBLED_OPCODE_INP: usize = OPCODE_OUT * 100 + 1;
-pub const ASSEMBLED_OPCODE_OUT: usize = OPCODE_OUT * 100 + 2;
-pub const ASSEMBLED_OPCODE_HLT: usize = OPCODE_HLT * 100;
-
-pub fn extract_opcode_from_assembled(assembled: usize) -> usize {
-    assembled / 100
+#[derive(Debug)]
+pub enum AssemblerError<'a> {
+    TooManyInstructions {
+        expected: usize,
+        actual: usize,
+    },
+    LabelAlreadyDefined {
+        name: &'a str,
+        line: usize,
+    },
+    LabelNotDefined {
+        name: &'a str,
+        line: usize,
+    },
+    UnsupportedOperation {
+        operation: Operation,
+        line: usize,
+    },
+    AddressOutOfRange {
+        address: usize,
+        memory_size: usize,
+        line: usize,
+    },
+    ValueOutOfRange {
+        value: usize,
+        max_value: usize,
+        line: usize,
+    },
+    IndirectNotSupported {
+        operation: Operation,
+        line: usize,
+    },
+    /// Pseudo-instructions must go through [`crate::expand`] first
+    PseudoInstructionNotExpanded {
+        operation: Operation,
+        line: usize,
+    },
+    /// The encoded word would execute as a different instruction
+    AmbiguousEncoding {
+        word: usize,
+        line: usize,
+    },
 }
 
-pub fn extract_value_from_assembled(assembled: usize) -> usize {
-    assembled % 100
+impl AssemblerError<'_> {
+    /// Source line the error was found on, if it belongs to one
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Self::TooManyInstructions { .. } => None,
+            Self::LabelAlreadyDefined { line, .. }
+            | Self::LabelNotDefined { line, .. }
+            | Self::UnsupportedOperation { line, .. }
+            | Self::AddressOutOfRange { line, .. }
+            | Self::ValueOutOfRange { line, .. }
+            | Self::IndirectNotSupported { line, .. }
+            | Self::PseudoInstructionNotExpanded { line, .. }
+            | Self::AmbiguousEncoding { line, .. } => Some(*line),
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum AssemblerError<'a> {
-    TooManyInstructions { expected: usize, actual: usize },
-    LabelAlreadyDefined { name: &'a str, index: usize },
-    LabelNotDefined(&'a str),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Code,
+    /// Label of a `DAT` statement
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub address: usize,
+    pub kind: SymbolKind,
+}
+
+/// An assembled program along with what is needed to relate it back to its source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    /// The full memory image, sized by the machine config
+    pub memory: Vec<usize>,
+    /// Labels by name
+    pub symbols: BTreeMap<String, Symbol>,
+    /// Source line of each assembled statement, indexed by address
+    pub source_map: Vec<usize>,
+}
+
+impl Assembly {
+    /// Number of mailboxes used by the program
+    pub fn program_size(&self) -> usize {
+        self.source_map.len()
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).map(|v| v.address)
+    }
+
+    /// Name of a label at `address`, the first by name when there are several
+    pub fn symbol_at(&self, address: usize) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|(_, v)| v.address == address)
+            .map(|(k, _)| k.as_str())
+    }
+
+    pub fn line_of(&self, address: usize) -> Option<usize> {
+        self.source_map.get(address).copied()
+    }
+
+    /// Like [`InstructionSet::disassemble`], naming operands which have a label
+    pub fn disassemble(&self, word: usize, instruction_set: &InstructionSet) -> Option<String> {
+        instruction_set.decode(word).map(|(definition, operand)| {
+            let name = self
+                .symbol_at(operand)
+                .map_or_else(|| operand.to_string(), |v| v.to_string());
+            match definition.operand {
+                OperandKind::Address => format!("{} {}", definition.mnemonic, name),
+                OperandKind::Indirect => format!("{} @{}", definition.mnemonic, name),
+                _ => definition.mnemonic.to_string(),
+            }
+        })
+    }
 }
 
 fn memory_location_to_addr<'a>(
-    labels: &HashMap<&str, u8>,
+    labels: &HashMap<&str, usize>,
     memory_location: &'a ast::MemoryLocation,
-) -> Result<u8, AssemblerError<'a>> {
+    memory_size: usize,
+    line: usize,
+) -> Result<usize, AssemblerError<'a>> {
     match memory_location {
+        ast::MemoryLocation::Address(addr) if *addr >= memory_size => {
+            Err(AssemblerError::AddressOutOfRange {
+                address: *addr,
+                memory_size,
+                line,
+            })
+        }
         ast::MemoryLocation::Address(addr) => Ok(*addr),
+        ast::MemoryLocation::Indirect(memory_location) => {
+            memory_location_to_addr(labels, memory_location, memory_size, line)
+        }
         ast::MemoryLocation::Label(label) => labels
-            .get(label)
-            .map(|v| *v)
-            .ok_or(AssemblerError::LabelNotDefined(label)),
+            .get(label.as_ref())
+            .copied()
+            .ok_or(AssemblerError::LabelNotDefined { name: label, line }),
     }
 }
 
-pub fn assemble_from_ast<'a>(
+fn resolve_labels<'a>(
     ast: &'a [ast::Statement<'a>],
-    memory: &mut [usize; 100],
-) -> Result<(), AssemblerError<'a>> {
-    if ast.len() > memory.len() {
-        return Err(AssemblerError::TooManyInstructions {
-            expected: memory.len(),
-            actual: ast.len(),
-        });
-    }
+) -> Result<HashMap<&'a str, usize>, AssemblerError<'a>> {
     let mut labels = HashMap::new();
     for (addr, stmt) in ast.iter().enumerate() {
-        if let Statement::Labeled { label, .. } = stmt {
-            if labels.insert(label.label, addr as u8).is_some() {
+        if let Statement::Labeled { label, instruction } = stmt {
+            if labels.insert(label.label.as_ref(), addr).is_some() {
                 return Err(AssemblerError::LabelAlreadyDefined {
-                    name: label.label,
-                    index: addr,
+                    name: &label.label,
+                    line: instruction.line,
                 });
             }
         }
     }
+    Ok(labels)
+}
+
+pub fn assemble_from_ast<'a>(
+    ast: &'a [ast::Statement<'a>],
+    instruction_set: &InstructionSet,
+) -> Result<Assembly, AssemblerError<'a>> {
+    let mut memory = vec![0; instruction_set.config().memory_size()];
+    if ast.len() > memory.len() {
+        return Err(AssemblerError::TooManyInstructions {
+            expected: memory.len(),
+            actual: ast.len(),
+        });
+    }
+    let memory_size = memory.len();
+    let max_value = instruction_set.config().max_value();
+    let labels = resolve_labels(ast)?;
     for (addr, stmt) in memory.iter_mut().zip(ast.iter()) {
         let instruction: &ast::Instruction = stmt.into();
-        *addr = match &instruction.instruction {
-            ast::InstructionType::Add(mem_location) => {
-                ASSEMBLED_OPCODE_ADD + memory_location_to_addr(&labels, mem_location)? as usize
-            }
-            ast::InstructionType::Subtract(mem_location) => {
-                ASSEMBLED_OPCODE_SUB + memory_location_to_addr(&labels, mem_location)? as usize
-            }
-            ast::InstructionType::Store(mem_location) => {
-                ASSEMBLED_OPCODE_STA + memory_location_to_addr(&labels, mem_location)? as usize
-            }
-            ast::InstructionType::Load(mem_location) => {
-                ASSEMBLED_OPCODE_LDA + memory_location_to_addr(&labels, mem_location)? as usize
-            }
-            ast::InstructionType::BranchAlways(mem_location) => {
-                ASSEMBLED_OPCODE_BRA + memory_location_to_addr(&labels, mem_location)? as usize
-            }
-            ast::InstructionType::BranchIfZero(mem_location) => {
-                ASSEMBLED_OPCODE_BRZ + memory_location_to_addr(&labels, mem_location)? as usize
-            }
-            ast::InstructionType::BranchIfPositive(mem_location) => {
-                ASSEMBLED_OPCODE_BRP + memory_location_to_addr(&labels, mem_location)? as usize
+        let line = instruction.line;
+        let operation = instruction.instruction.operation();
+        let indirect = matches!(
+            instruction.instruction.memory_location(),
+            Some(ast::MemoryLocation::Indirect(_))
+        );
+        let definition =
+            instruction_set
+                .by_operation(operation, indirect)
+                .ok_or(match indirect {
+                    true => AssemblerError::IndirectNotSupported { operation, line },
+                    false => AssemblerError::UnsupportedOperation { operation, line },
+                })?;
+        if definition.operand == OperandKind::Indexed {
+            return Err(AssemblerError::PseudoInstructionNotExpanded { operation, line });
+        }
+        let operand = match &instruction.instruction {
+            ast::InstructionType::Data(v) if *v > max_value => {
+                return Err(AssemblerError::ValueOutOfRange {
+                    value: *v,
+                    max_value,
+                    line,
+                })
             }
-            ast::InstructionType::Input => ASSEMBLED_OPCODE_INP,
-            ast::InstructionType::Output => ASSEMBLED_OPCODE_OUT,
-            ast::InstructionType::Halt => ASSEMBLED_OPCODE_HLT,
             ast::InstructionType::Data(v) => *v,
+            v => match v.memory_location() {
+                Some(mem_location) => {
+                    memory_location_to_addr(&labels, mem_location, memory_size, line)?
+                }
+                None => 0,
+            },
+        };
+        *addr = instruction_set.encode(definition, operand);
+        if definition.operand != OperandKind::Value
+            && instruction_set.decode(*addr).map(|(v, _)| v) != Some(definition)
+        {
+            return Err(AssemblerError::AmbiguousEncoding { word: *addr, line });
         }
     }
 
-    Ok(())
+    let symbols = ast
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Statement::Labeled { label, instruction } => Some((label, instruction)),
+            Statement::UnLabeled { .. } => None,
+        })
+        .map(|(label, instruction)| {
+            let kind = match instruction.instruction {
+                ast::InstructionType::Data(_) => SymbolKind::Data,
+                _ => SymbolKind::Code,
+            };
+            let address = labels[label.label.as_ref()];
+            (label.label.to_string(), Symbol { address, kind })
+        })
+        .collect();
+    let source_map = ast
+        .iter()
+        .map(|v| <&ast::Instruction>::from(v).line)
+        .collect();
+    Ok(Assembly {
+        memory,
+        symbols,
+        source_map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::{InstructionSet, MachineConfig};
+
+    use super::{assemble_from_ast, AssemblerError, Symbol, SymbolKind};
+
+    #[test]
+    fn test_assemble() {
+        let mut parsed = pass_program("INP\nADD a\nOUT\nHLT\na: DAT 5\n").unwrap();
+        let instruction_set = InstructionSet::standard();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        assert_eq!(assembly.memory[..5], [901, 104, 902, 0, 5]);
+        assert_eq!(assembly.memory.len(), 100);
+        assert_eq!(
+            assembly.symbols["a"],
+            Symbol {
+                address: 4,
+                kind: SymbolKind::Data
+            }
+        );
+        assert_eq!(assembly.source_map, [1, 2, 3, 4, 5]);
+        assert_eq!(
+            assembly.disassemble(104, &instruction_set).as_deref(),
+            Some("ADD a")
+        );
+    }
+
+    #[test]
+    fn test_assemble_big() {
+        let mut parsed = pass_program("LDA 999\nOUT\nDAT 9999\n").unwrap();
+        let instruction_set = InstructionSet::standard().with_config(MachineConfig::big());
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let memory = assemble_from_ast(&ast, &instruction_set).unwrap().memory;
+        assert_eq!(memory[..3], [5999, 9002, 9999]);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let instruction_set = InstructionSet::standard();
+
+        let mut parsed = pass_program("LDA 100").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        assert!(matches!(
+            assemble_from_ast(&ast, &instruction_set),
+            Err(AssemblerError::AddressOutOfRange {
+                address: 100,
+                memory_size: 100,
+                line: 1
+            })
+        ));
+
+        let mut parsed = pass_program("DAT 1000").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        assert!(matches!(
+            assemble_from_ast(&ast, &instruction_set),
+            Err(AssemblerError::ValueOutOfRange {
+                value: 1000,
+                max_value: 999,
+                line: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_indirect() {
+        let instruction_set = InstructionSet::standard().with_indirect();
+
+        let mut parsed = pass_program("LDA @ptr\nSTA @5\nHLT\nptr: DAT 4\n").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let memory = assemble_from_ast(&ast, &instruction_set).unwrap().memory;
+        assert_eq!(memory[..4], [403, 5, 0, 4]);
+
+        let mut parsed = pass_program("STA @0").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        assert!(matches!(
+            assemble_from_ast(&ast, &instruction_set),
+            Err(AssemblerError::AmbiguousEncoding { word: 0, line: 1 })
+        ));
+
+        let mut parsed = pass_program("ADD @5").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        assert!(matches!(
+            assemble_from_ast(&ast, &instruction_set),
+            Err(AssemblerError::IndirectNotSupported { line: 1, .. })
+        ));
+    }
 }
@@ -1,6 +1,10 @@
+use std::borrow::Cow;
+use std::fmt::{self, Display};
+
 use pest::iterators::Pairs;
 
 use crate::grammar::Rule;
+use crate::instruction_set::{InstructionSet, Operation};
 
 pub const MNEMONIC_ADD: &str = "ADD";
 pub const MNEMONIC_SUB: &str = "SUB";
@@ -13,41 +17,161 @@ pub const MNEMONIC_INP: &str = "INP";
 pub const MNEMONIC_OUT: &str = "OUT";
 pub const MNEMONIC_HLT: &str = "HLT";
 pub const MNEMONIC_DAT: &str = "DAT";
+pub const MNEMONIC_OTC: &str = "OTC";
+pub const MNEMONIC_INA: &str = "INA";
+pub const MNEMONIC_CAL: &str = "CAL";
+pub const MNEMONIC_RET: &str = "RET";
+pub const MNEMONIC_LDI: &str = "LDI";
+pub const MNEMONIC_STI: &str = "STI";
+
+/// Prefix of labels created by the toolchain, which names in the structured language
+/// can't start with, see [`crate::compiler`]
+pub const GENERATED_LABEL_PREFIX: &str = "__";
 
-#[derive(Debug, PartialEq, Eq)]
+pub fn is_generated_label(label: &str) -> bool {
+    label.starts_with(GENERATED_LABEL_PREFIX)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MemoryLocation<'a> {
-    Address(u8),
-    Label(&'a str),
+    Address(usize),
+    Label(Cow<'a, str>),
+    /// Location holding the address to use
+    Indirect(Box<MemoryLocation<'a>>),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Display for MemoryLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(addr) => write!(f, "{}", addr),
+            Self::Label(label) => write!(f, "{}", label),
+            Self::Indirect(memory_location) => write!(f, "@{}", memory_location),
+        }
+    }
+}
+
+fn parse_memory_location(text: &str) -> MemoryLocation<'_> {
+    if let Some(text) = text.strip_prefix('@') {
+        MemoryLocation::Indirect(Box::new(parse_memory_location(text)))
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        // out of range addresses are rejected by the assembler
+        MemoryLocation::Address(text.parse().unwrap_or(usize::MAX))
+    } else {
+        MemoryLocation::Label(Cow::Borrowed(text))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InstructionType<'a> {
     Add(MemoryLocation<'a>),
     Subtract(MemoryLocation<'a>),
+    Multiply(MemoryLocation<'a>),
     Store(MemoryLocation<'a>),
     Load(MemoryLocation<'a>),
     BranchAlways(MemoryLocation<'a>),
     BranchIfZero(MemoryLocation<'a>),
     BranchIfPositive(MemoryLocation<'a>),
+    Call(MemoryLocation<'a>),
+    Return,
+    /// Pseudo-instruction loading `table + index`, see [`crate::expand`]
+    LoadIndexed(MemoryLocation<'a>, MemoryLocation<'a>),
+    /// Pseudo-instruction storing to `table + index`, see [`crate::expand`]
+    StoreIndexed(MemoryLocation<'a>, MemoryLocation<'a>),
     Input,
     Output,
+    InputCharacter,
+    OutputCharacter,
     Halt,
     Data(usize),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl<'a> InstructionType<'a> {
+    /// Create from a runtime behaviour, taking the operand if one is needed,
+    /// or `None` for the indexed pseudo-instructions which need a second one
+    pub fn from_operation(
+        operation: Operation,
+        memory_location: MemoryLocation<'a>,
+        value: usize,
+    ) -> Option<Self> {
+        Some(match operation {
+            Operation::Add => Self::Add(memory_location),
+            Operation::Subtract => Self::Subtract(memory_location),
+            Operation::Multiply => Self::Multiply(memory_location),
+            Operation::Store => Self::Store(memory_location),
+            Operation::Load => Self::Load(memory_location),
+            Operation::BranchAlways => Self::BranchAlways(memory_location),
+            Operation::BranchIfZero => Self::BranchIfZero(memory_location),
+            Operation::BranchIfPositive => Self::BranchIfPositive(memory_location),
+            Operation::Call => Self::Call(memory_location),
+            Operation::Return => Self::Return,
+            Operation::LoadIndexed | Operation::StoreIndexed => return None,
+            Operation::Input => Self::Input,
+            Operation::Output => Self::Output,
+            Operation::InputCharacter => Self::InputCharacter,
+            Operation::OutputCharacter => Self::OutputCharacter,
+            Operation::Halt => Self::Halt,
+            Operation::Data => Self::Data(value),
+        })
+    }
+
+    pub fn operation(&self) -> Operation {
+        match self {
+            Self::Add(_) => Operation::Add,
+            Self::Subtract(_) => Operation::Subtract,
+            Self::Multiply(_) => Operation::Multiply,
+            Self::Store(_) => Operation::Store,
+            Self::Load(_) => Operation::Load,
+            Self::BranchAlways(_) => Operation::BranchAlways,
+            Self::BranchIfZero(_) => Operation::BranchIfZero,
+            Self::BranchIfPositive(_) => Operation::BranchIfPositive,
+            Self::Call(_) => Operation::Call,
+            Self::Return => Operation::Return,
+            Self::LoadIndexed(..) => Operation::LoadIndexed,
+            Self::StoreIndexed(..) => Operation::StoreIndexed,
+            Self::Input => Operation::Input,
+            Self::Output => Operation::Output,
+            Self::InputCharacter => Operation::InputCharacter,
+            Self::OutputCharacter => Operation::OutputCharacter,
+            Self::Halt => Operation::Halt,
+            Self::Data(_) => Operation::Data,
+        }
+    }
+
+    pub fn memory_location(&self) -> Option<&MemoryLocation<'a>> {
+        match self {
+            Self::Add(v)
+            | Self::Subtract(v)
+            | Self::Multiply(v)
+            | Self::Store(v)
+            | Self::Load(v)
+            | Self::BranchAlways(v)
+            | Self::BranchIfZero(v)
+            | Self::BranchIfPositive(v)
+            | Self::Call(v)
+            | Self::LoadIndexed(v, _)
+            | Self::StoreIndexed(v, _) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Label<'a> {
-    pub label: &'a str,
+    pub label: Cow<'a, str>,
     pub comments: Box<[&'a str]>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Instruction<'a> {
     pub instruction: InstructionType<'a>,
     pub comments: Box<[&'a str]>,
+    /// Comment after the instruction on its own line
+    pub end_comment: Option<&'a str>,
+    /// Source line of the mnemonic, starting from 1
+    pub line: usize,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Statement<'a> {
     Labeled {
         label: Label<'a>,
@@ -61,13 +185,101 @@ pub enum Statement<'a> {
 impl<'a, 'b> From<&'b Statement<'a>> for &'b Instruction<'a> {
     fn from(value: &'b Statement<'a>) -> &'b Instruction<'a> {
         match value {
-            Statement::Labeled { instruction, .. } => &instruction,
-            Statement::UnLabeled { instruction } => &instruction,
+            Statement::Labeled { instruction, .. } => instruction,
+            Statement::UnLabeled { instruction } => instruction,
+        }
+    }
+}
+
+/// Render statements back into LMC source, which parses back into the same statements
+/// apart from comments before a label's instruction, which move before the label
+pub fn ast_to_source(ast: &[Statement], instruction_set: &InstructionSet) -> String {
+    let mut source = String::new();
+    for stmt in ast {
+        let instruction: &Instruction = stmt.into();
+        let (label, indent) = match stmt {
+            Statement::Labeled { label, .. } => {
+                for comment in label.comments.iter() {
+                    source.push_str(&format!("; {}\n", comment));
+                }
+                (format!("{}: ", label.label), "")
+            }
+            Statement::UnLabeled { .. } => ("    ".to_string(), "    "),
+        };
+        for comment in instruction.comments.iter() {
+            source.push_str(&format!("{}; {}\n", indent, comment));
+        }
+        let operation = instruction.instruction.operation();
+        let indirect = matches!(
+            instruction.instruction.memory_location(),
+            Some(MemoryLocation::Indirect(_))
+        );
+        let mnemonic = instruction_set
+            .by_operation(operation, indirect)
+            .map_or("???", |v| v.mnemonic);
+        let operand = match &instruction.instruction {
+            InstructionType::LoadIndexed(table, index)
+            | InstructionType::StoreIndexed(table, index) => format!(" {}, {}", table, index),
+            InstructionType::Data(value) => format!(" {}", value),
+            v => v
+                .memory_location()
+                .map(|v| format!(" {}", v))
+                .unwrap_or_default(),
+        };
+        let end_comment = instruction
+            .end_comment
+            .map(|v| format!(" ; {}", v))
+            .unwrap_or_default();
+        source.push_str(&format!(
+            "{}{}{}{}\n",
+            label, mnemonic, operand, end_comment
+        ));
+    }
+    source
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AstError {
+    /// A mnemonic the instruction set doesn't define
+    UnknownInstruction { name: String, line: usize },
+    /// A second operand given to an instruction which only takes one
+    UnexpectedOperand { name: String, line: usize },
+    /// An indexed instruction given no index
+    MissingOperand { name: String, line: usize },
+}
+
+impl Display for AstError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownInstruction { name, line } => write!(
+                f,
+                "unknown instruction type found: '{}' on line {}",
+                name, line
+            ),
+            Self::UnexpectedOperand { name, line } => write!(
+                f,
+                "unexpected second operand for '{}' on line {}",
+                name, line
+            ),
+            Self::MissingOperand { name, line } => {
+                write!(f, "missing index operand for '{}' on line {}", name, line)
+            }
         }
     }
 }
 
-pub fn parsed_to_ast<'a>(parsed: &mut Pairs<'a, Rule>) -> Vec<Statement<'a>> {
+/// Like [`try_parsed_to_ast`], panicking on invalid instructions
+pub fn parsed_to_ast<'a>(
+    parsed: &mut Pairs<'a, Rule>,
+    instruction_set: &InstructionSet,
+) -> Vec<Statement<'a>> {
+    try_parsed_to_ast(parsed, instruction_set).unwrap_or_else(|err| panic!("{}", err))
+}
+
+pub fn try_parsed_to_ast<'a>(
+    parsed: &mut Pairs<'a, Rule>,
+    instruction_set: &InstructionSet,
+) -> Result<Vec<Statement<'a>>, AstError> {
     let mut ast = vec![];
     for pair in parsed {
         let rule = pair.as_rule();
@@ -84,7 +296,7 @@ pub fn parsed_to_ast<'a>(parsed: &mut Pairs<'a, Rule>) -> Vec<Statement<'a>> {
                                 match token.as_rule() {
                                     Rule::labelName => {
                                         label = Some(Label {
-                                            label: token.as_span().as_str(),
+                                            label: Cow::Borrowed(token.as_span().as_str()),
                                             comments: vec![].into_boxed_slice(),
                                         })
                                     }
@@ -106,15 +318,19 @@ pub fn parsed_to_ast<'a>(parsed: &mut Pairs<'a, Rule>) -> Vec<Statement<'a>> {
                         }
                         Rule::instruction => {
                             let mut instruction_comments = vec![];
+                            let mut end_comment = None;
                             let mut instruction_type = String::new();
-                            let mut instruction_memory = "";
+                            let mut instruction_line = 0;
+                            let mut operands = vec![];
                             for token in token.into_inner() {
                                 match token.as_rule() {
                                     Rule::instructionName => {
                                         instruction_type = token.as_span().as_str().to_uppercase();
+                                        instruction_line = token.as_span().start_pos().line_col().0;
                                     }
                                     Rule::memoryLocation => {
-                                        instruction_memory = token.as_span().as_str();
+                                        operands
+                                            .push(parse_memory_location(token.as_span().as_str()));
                                     }
                                     Rule::comment => {
                                         let v = token
@@ -123,42 +339,62 @@ pub fn parsed_to_ast<'a>(parsed: &mut Pairs<'a, Rule>) -> Vec<Statement<'a>> {
                                             .strip_prefix(';')
                                             .unwrap()
                                             .trim();
-                                        instruction_comments.push(v);
+                                        // comments after the mnemonic end its line
+                                        match instruction_type.is_empty() {
+                                            true => instruction_comments.push(v),
+                                            false => end_comment = Some(v),
+                                        }
                                     }
                                     _ => panic!("invalid parsed token rule"),
                                 }
                             }
-                            let memory_location = match (
-                                instruction_memory.parse::<u8>(),
-                                instruction_memory.is_empty(),
-                            ) {
-                                (Ok(addr), false) => MemoryLocation::Address(addr),
-                                (Err(_), false) => MemoryLocation::Label(instruction_memory),
-                                (_, true) => MemoryLocation::Address(0),
+                            let mut operands = operands.into_iter();
+                            let memory_location =
+                                operands.next().unwrap_or(MemoryLocation::Address(0));
+                            let index = operands.next();
+                            let value = match memory_location {
+                                MemoryLocation::Address(value) => value,
+                                _ => 0,
+                            };
+                            let indirect = matches!(memory_location, MemoryLocation::Indirect(_));
+                            let definition = instruction_set
+                                .by_mnemonic(&instruction_type, indirect)
+                                // unsupported addressing is reported by the assembler
+                                .or_else(|| {
+                                    instruction_set.by_mnemonic(&instruction_type, !indirect)
+                                })
+                                .ok_or_else(|| AstError::UnknownInstruction {
+                                    name: instruction_type.clone(),
+                                    line: instruction_line,
+                                })?;
+                            let instruction_type = match (definition.operation, index) {
+                                (Operation::LoadIndexed, Some(index)) => {
+                                    InstructionType::LoadIndexed(memory_location, index)
+                                }
+                                (Operation::StoreIndexed, Some(index)) => {
+                                    InstructionType::StoreIndexed(memory_location, index)
+                                }
+                                (operation, None) => InstructionType::from_operation(
+                                    operation,
+                                    memory_location,
+                                    value,
+                                )
+                                .ok_or_else(|| AstError::MissingOperand {
+                                    name: instruction_type.clone(),
+                                    line: instruction_line,
+                                })?,
+                                (_, Some(_)) => {
+                                    return Err(AstError::UnexpectedOperand {
+                                        name: instruction_type,
+                                        line: instruction_line,
+                                    })
+                                }
                             };
                             instruction = Some(Instruction {
-                                instruction: match &*instruction_type {
-                                    MNEMONIC_ADD => InstructionType::Add(memory_location),
-                                    MNEMONIC_SUB => InstructionType::Subtract(memory_location),
-                                    MNEMONIC_STA => InstructionType::Store(memory_location),
-                                    MNEMONIC_LDA => InstructionType::Load(memory_location),
-                                    MNEMONIC_BRA => InstructionType::BranchAlways(memory_location),
-                                    MNEMONIC_BRZ => InstructionType::BranchIfZero(memory_location),
-                                    MNEMONIC_BRP => {
-                                        InstructionType::BranchIfPositive(memory_location)
-                                    }
-                                    MNEMONIC_INP => InstructionType::Input,
-                                    MNEMONIC_OUT => InstructionType::Output,
-                                    MNEMONIC_HLT => InstructionType::Halt,
-                                    MNEMONIC_DAT => InstructionType::Data(
-                                        instruction_memory.parse::<usize>().unwrap_or(0),
-                                    ),
-                                    _ => panic!(
-                                        "unknown instruction type found: '{}'",
-                                        instruction_type
-                                    ),
-                                },
+                                instruction: instruction_type,
                                 comments: instruction_comments.into_boxed_slice(),
+                                end_comment,
+                                line: instruction_line,
                             });
                         }
                         _ => panic!("invalid parsed token rule"),
@@ -179,14 +415,18 @@ pub fn parsed_to_ast<'a>(parsed: &mut Pairs<'a, Rule>) -> Vec<Statement<'a>> {
             _ => panic!("invalid parsed rules"),
         }
     }
-    ast
+    Ok(ast)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
 
-    use super::{parsed_to_ast, Instruction, InstructionType, Label, MemoryLocation, Statement};
+    use super::{
+        parsed_to_ast, try_parsed_to_ast, AstError, Instruction, InstructionType, Label,
+        MemoryLocation, Statement,
+    };
 
     #[test]
     fn test_simple_add() {
@@ -203,52 +443,112 @@ b: DAT 4
         )
         .unwrap();
 
-        let ast_actual = parsed_to_ast(&mut parsed);
+        let ast_actual = parsed_to_ast(&mut parsed, &InstructionSet::standard());
         let ast_expected = vec![
             Statement::Labeled {
                 label: Label {
-                    label: "start",
+                    label: "start".into(),
                     comments: Box::new(["add two numbers"]),
                 },
                 instruction: Instruction {
-                    instruction: InstructionType::Load(MemoryLocation::Label("a")),
+                    instruction: InstructionType::Load(MemoryLocation::Label("a".into())),
                     comments: Box::new([]),
+                    end_comment: None,
+                    line: 4,
                 },
             },
             Statement::UnLabeled {
                 instruction: Instruction {
-                    instruction: InstructionType::Add(MemoryLocation::Label("b")),
+                    instruction: InstructionType::Add(MemoryLocation::Label("b".into())),
                     comments: Box::new([]),
+                    end_comment: None,
+                    line: 5,
                 },
             },
             Statement::UnLabeled {
                 instruction: Instruction {
                     instruction: InstructionType::Output,
                     comments: Box::new([]),
+                    end_comment: None,
+                    line: 6,
                 },
             },
             Statement::Labeled {
                 label: Label {
-                    label: "a",
+                    label: "a".into(),
                     comments: Box::new([]),
                 },
                 instruction: Instruction {
                     instruction: InstructionType::Data(2),
                     comments: Box::new([]),
+                    end_comment: None,
+                    line: 7,
                 },
             },
             Statement::Labeled {
                 label: Label {
-                    label: "b",
+                    label: "b".into(),
                     comments: Box::new([]),
                 },
                 instruction: Instruction {
                     instruction: InstructionType::Data(4),
                     comments: Box::new([]),
+                    end_comment: None,
+                    line: 8,
                 },
             },
         ];
 
         assert_eq!(ast_expected, ast_actual);
     }
+
+    #[test]
+    fn test_extended_io() {
+        let mut parsed = pass_program("INA\nOTC\n").unwrap();
+        let instruction_set = InstructionSet::standard().with_extended_io();
+
+        let ast_actual = parsed_to_ast(&mut parsed, &instruction_set);
+        let ast_expected = vec![
+            Statement::UnLabeled {
+                instruction: Instruction {
+                    instruction: InstructionType::InputCharacter,
+                    comments: Box::new([]),
+                    end_comment: None,
+                    line: 1,
+                },
+            },
+            Statement::UnLabeled {
+                instruction: Instruction {
+                    instruction: InstructionType::OutputCharacter,
+                    comments: Box::new([]),
+                    end_comment: None,
+                    line: 2,
+                },
+            },
+        ];
+
+        assert_eq!(ast_expected, ast_actual);
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown instruction type found: 'OTC'")]
+    fn test_extended_io_not_enabled() {
+        let mut parsed = pass_program("OTC").unwrap();
+        parsed_to_ast(&mut parsed, &InstructionSet::standard());
+    }
+
+    #[test]
+    fn test_indexed_without_index() {
+        let instruction_set = InstructionSet::standard();
+        for (source, name) in [("LDI table\n", "LDI"), ("x: DAT\n    STI table\n", "STI")] {
+            let mut parsed = pass_program(source).unwrap();
+            assert_eq!(
+                try_parsed_to_ast(&mut parsed, &instruction_set),
+                Err(AstError::MissingOperand {
+                    name: name.to_string(),
+                    line: source.lines().count(),
+                })
+            );
+        }
+    }
 }
@@ -0,0 +1,877 @@
+//! Compiler from a small structured language to LMC.
+//!
+//! Programs are made of assignments, `print`, `if`/`else` and `while`
+//! over integer variables, reading values with `input`.
+//! `*`, `/`, `%`, negation and `compare(a, b)`, which is 0, 1 or 2 when `a`
+//! is less than, equal to or greater than `b`, call routines linked from
+//! [`crate::library`]:
+//!
+//! ```text
+//! // print the numbers from the input down to one
+//! n = input;
+//! while n > 0 {
+//!     print n;
+//!     n = n - 1;
+//! }
+//! ```
+//!
+//! Values are those of a mailbox, addition and multiplication saturate,
+//! subtraction below zero wraps like the machine does.
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+
+use pest::iterators::Pair;
+use pest::Parser;
+use pest_derive::Parser;
+
+use crate::ast::{
+    Instruction, InstructionType, Label, MemoryLocation, Statement, GENERATED_LABEL_PREFIX,
+};
+use crate::instruction_set::InstructionSet;
+use crate::library::{self, exit_label, LHS, RHS};
+
+#[derive(Parser)]
+#[grammar = "compiler.pest"]
+struct StructuredParser;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    /// The program doesn't match the grammar, with pest's description of where
+    Syntax { message: String },
+    /// A variable read before anything was assigned to it
+    UndefinedVariable { name: String, line: usize },
+    /// A number too large for a mailbox
+    ValueOutOfRange { value: String, line: usize },
+    /// A variable named like one of the library's labels
+    ReservedName { name: String, line: usize },
+    /// More mailboxes needed than the machine has
+    TooLarge { required: usize, available: usize },
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { message } => write!(f, "syntax error: {}", message),
+            Self::UndefinedVariable { name, line } => write!(
+                f,
+                "variable '{}' is used before it is assigned on line {}",
+                name, line
+            ),
+            Self::ValueOutOfRange { value, line } => {
+                write!(
+                    f,
+                    "value {} does not fit in a mailbox on line {}",
+                    value, line
+                )
+            }
+            Self::ReservedName { name, line } => write!(
+                f,
+                "variable '{}' on line {} is named like a library routine",
+                name, line
+            ),
+            Self::TooLarge {
+                required,
+                available,
+            } => write!(
+                f,
+                "program needs {} mailboxes but only {} are available",
+                required, available
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expression<'a> {
+    Number { text: &'a str, line: usize },
+    Variable { name: &'a str, line: usize },
+    Input,
+    Negate(Box<Expression<'a>>),
+    Binary(Box<Expression<'a>>, Operator, Box<Expression<'a>>),
+    Compare(Box<Expression<'a>>, Box<Expression<'a>>),
+}
+
+impl Expression<'_> {
+    /// Whether evaluating reads input, so has to happen in source order
+    fn reads_input(&self) -> bool {
+        match self {
+            Self::Input => true,
+            Self::Negate(value) => value.reads_input(),
+            Self::Binary(left, _, right) | Self::Compare(left, right) => {
+                left.reads_input() || right.reads_input()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition<'a> {
+    left: Expression<'a>,
+    comparison: Comparison,
+    right: Expression<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node<'a> {
+    Assign {
+        name: &'a str,
+        value: Expression<'a>,
+    },
+    Print(Expression<'a>),
+    If {
+        condition: Condition<'a>,
+        then: Vec<(Node<'a>, Source<'a>)>,
+        otherwise: Vec<(Node<'a>, Source<'a>)>,
+    },
+    While {
+        condition: Condition<'a>,
+        body: Vec<(Node<'a>, Source<'a>)>,
+    },
+}
+
+/// Where a statement came from, kept as comments and the source map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Source<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+fn source_of<'a>(pair: &Pair<'a, Rule>) -> Source<'a> {
+    // control statements are described by their header
+    let text = pair.as_str().split('{').next().unwrap_or_default().trim();
+    Source {
+        text,
+        line: pair.line_col().0,
+    }
+}
+
+fn parse_expression(pair: Pair<Rule>) -> Expression {
+    let line = pair.line_col().0;
+    match pair.as_rule() {
+        Rule::kwInput => Expression::Input,
+        Rule::number => Expression::Number {
+            text: pair.as_str(),
+            line,
+        },
+        Rule::identifier => Expression::Variable {
+            name: pair.as_str(),
+            line,
+        },
+        Rule::negation => Expression::Negate(Box::new(parse_expression(
+            pair.into_inner().next().unwrap(),
+        ))),
+        Rule::compareCall => {
+            let mut inner = pair.into_inner().skip(1);
+            Expression::Compare(
+                Box::new(parse_expression(inner.next().unwrap())),
+                Box::new(parse_expression(inner.next().unwrap())),
+            )
+        }
+        Rule::expression | Rule::term => {
+            let mut inner = pair.into_inner();
+            let mut expression = parse_expression(inner.next().unwrap());
+            while let (Some(operator), Some(right)) = (inner.next(), inner.next()) {
+                let operator = match operator.as_str() {
+                    "+" => Operator::Add,
+                    "-" => Operator::Subtract,
+                    "*" => Operator::Multiply,
+                    "/" => Operator::Divide,
+                    _ => Operator::Modulo,
+                };
+                expression = Expression::Binary(
+                    Box::new(expression),
+                    operator,
+                    Box::new(parse_expression(right)),
+                );
+            }
+            expression
+        }
+        rule => unreachable!("unexpected expression rule {:?}", rule),
+    }
+}
+
+fn parse_condition(pair: Pair<Rule>) -> Condition {
+    let mut inner = pair.into_inner();
+    let left = parse_expression(inner.next().unwrap());
+    let comparison = match inner.next().unwrap().as_str() {
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessEqual,
+        ">" => Comparison::Greater,
+        _ => Comparison::GreaterEqual,
+    };
+    let right = parse_expression(inner.next().unwrap());
+    Condition {
+        left,
+        comparison,
+        right,
+    }
+}
+
+fn parse_block(pair: Pair<Rule>) -> Vec<(Node, Source)> {
+    pair.into_inner().map(parse_statement).collect()
+}
+
+fn parse_statement(pair: Pair<Rule>) -> (Node, Source) {
+    let source = source_of(&pair);
+    let rule = pair.as_rule();
+    let mut inner = pair.into_inner().filter(|v| {
+        !matches!(
+            v.as_rule(),
+            Rule::kwIf | Rule::kwElse | Rule::kwWhile | Rule::kwPrint
+        )
+    });
+    let node = match rule {
+        Rule::assignment => Node::Assign {
+            name: inner.next().unwrap().as_str(),
+            value: parse_expression(inner.next().unwrap()),
+        },
+        Rule::printStatement => Node::Print(parse_expression(inner.next().unwrap())),
+        Rule::ifStatement => Node::If {
+            condition: parse_condition(inner.next().unwrap()),
+            then: parse_block(inner.next().unwrap()),
+            otherwise: match inner.next() {
+                Some(v) if v.as_rule() == Rule::ifStatement => vec![parse_statement(v)],
+                Some(v) => parse_block(v),
+                None => vec![],
+            },
+        },
+        Rule::whileStatement => Node::While {
+            condition: parse_condition(inner.next().unwrap()),
+            body: parse_block(inner.next().unwrap()),
+        },
+        rule => unreachable!("unexpected statement rule {:?}", rule),
+    };
+    (node, source)
+}
+
+enum Item<'a> {
+    Label(String),
+    Instruction(Instruction<'a>),
+}
+
+fn generated_label(kind: &str, count: usize) -> String {
+    format!("{}{}{}", GENERATED_LABEL_PREFIX, kind, count)
+}
+
+fn location<'a>(label: &str) -> MemoryLocation<'a> {
+    MemoryLocation::Label(Cow::Owned(label.to_string()))
+}
+
+struct Generator<'a> {
+    items: Vec<Item<'a>>,
+    max_value: usize,
+    /// Variables in order of first assignment, with the line of it
+    variables: Vec<(&'a str, usize)>,
+    /// Pooled constants, with the line they were first used on
+    constants: BTreeMap<usize, usize>,
+    /// Cells holding the branch back from each library call, to its label
+    returns: Vec<(String, String, usize)>,
+    /// Scratch cells in use while evaluating an expression
+    temps: usize,
+    max_temps: usize,
+    labels: usize,
+    source: Source<'a>,
+    /// Comment for the next instruction, the source of the statement being compiled
+    comment: Option<&'a str>,
+}
+
+impl<'a> Generator<'a> {
+    fn emit(&mut self, instruction: InstructionType<'a>) {
+        self.items.push(Item::Instruction(Instruction {
+            instruction,
+            comments: self.comment.take().into_iter().collect(),
+            end_comment: None,
+            line: self.source.line,
+        }));
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        generated_label(kind, self.labels)
+    }
+
+    fn place(&mut self, label: &str) {
+        self.items.push(Item::Label(label.to_string()));
+    }
+
+    fn push_temp(&mut self) -> MemoryLocation<'a> {
+        self.temps += 1;
+        self.max_temps = self.max_temps.max(self.temps);
+        location(&generated_label("temp", self.temps - 1))
+    }
+
+    fn pop_temp(&mut self) {
+        self.temps -= 1;
+    }
+
+    fn constant(&mut self, text: &str, line: usize) -> Result<MemoryLocation<'a>, CompileError> {
+        let value = text
+            .parse()
+            .ok()
+            .filter(|v| *v <= self.max_value)
+            .ok_or_else(|| CompileError::ValueOutOfRange {
+                value: text.to_string(),
+                line,
+            })?;
+        self.constants.entry(value).or_insert(line);
+        Ok(location(&generated_label("const", value)))
+    }
+
+    /// The cell holding `expression`, when it needs no evaluating
+    fn operand(
+        &mut self,
+        expression: &Expression<'a>,
+    ) -> Result<Option<MemoryLocation<'a>>, CompileError> {
+        match expression {
+            Expression::Number { text, line } => self.constant(text, *line).map(Some),
+            Expression::Variable { name, line } => {
+                if !self.variables.iter().any(|(v, _)| v == name) {
+                    return Err(CompileError::UndefinedVariable {
+                        name: name.to_string(),
+                        line: *line,
+                    });
+                }
+                Ok(Some(MemoryLocation::Label(Cow::Borrowed(*name))))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Evaluate `expression` into the accumulator
+    fn load(&mut self, expression: &Expression<'a>) -> Result<(), CompileError> {
+        match expression {
+            Expression::Input => self.emit(InstructionType::Input),
+            Expression::Negate(value) => {
+                self.load(value)?;
+                self.emit(InstructionType::Store(location(LHS)));
+                self.call(library::NEGATE);
+            }
+            Expression::Binary(left, operator, right) => self.binary(left, *operator, right)?,
+            Expression::Compare(left, right) => self.call_binary(library::COMPARE, left, right)?,
+            _ => {
+                let operand = self.operand(expression)?.unwrap();
+                self.emit(InstructionType::Load(operand));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, operator: Operator, operand: MemoryLocation<'a>) {
+        self.emit(match operator {
+            Operator::Add => InstructionType::Add(operand),
+            _ => InstructionType::Subtract(operand),
+        });
+    }
+
+    /// Branch to a library routine, which returns to the next instruction
+    fn call(&mut self, routine: &str) {
+        let back = self.new_label("back");
+        let cell = generated_label("return", self.labels);
+        self.emit(InstructionType::Load(location(&cell)));
+        self.emit(InstructionType::Store(location(&exit_label(routine))));
+        self.emit(InstructionType::BranchAlways(location(routine)));
+        self.returns.push((cell, back.clone(), self.source.line));
+        self.place(&back);
+    }
+
+    /// Evaluate `left` into [`LHS`] and `right` into [`RHS`] and call `routine`
+    fn call_binary(
+        &mut self,
+        routine: &str,
+        left: &Expression<'a>,
+        right: &Expression<'a>,
+    ) -> Result<(), CompileError> {
+        if let Some(operand) = self.operand(right)? {
+            self.load(left)?;
+            self.emit(InstructionType::Store(location(LHS)));
+            self.emit(InstructionType::Load(operand));
+            self.emit(InstructionType::Store(location(RHS)));
+        } else if let Some(operand) = self.operand(left)? {
+            self.load(right)?;
+            self.emit(InstructionType::Store(location(RHS)));
+            self.emit(InstructionType::Load(operand));
+            self.emit(InstructionType::Store(location(LHS)));
+        } else {
+            // `right` may call routines too, overwriting the arguments
+            let temp = self.push_temp();
+            self.load(left)?;
+            self.emit(InstructionType::Store(temp.clone()));
+            self.load(right)?;
+            self.emit(InstructionType::Store(location(RHS)));
+            self.emit(InstructionType::Load(temp));
+            self.emit(InstructionType::Store(location(LHS)));
+            self.pop_temp();
+        }
+        self.call(routine);
+        Ok(())
+    }
+
+    fn binary(
+        &mut self,
+        left: &Expression<'a>,
+        operator: Operator,
+        right: &Expression<'a>,
+    ) -> Result<(), CompileError> {
+        let routine = match operator {
+            Operator::Multiply => Some(library::MULTIPLY),
+            Operator::Divide => Some(library::DIVIDE),
+            Operator::Modulo => Some(library::MODULO),
+            Operator::Add | Operator::Subtract => None,
+        };
+        if let Some(routine) = routine {
+            return self.call_binary(routine, left, right);
+        }
+        if let Some(operand) = self.operand(right)? {
+            self.load(left)?;
+            self.apply(operator, operand);
+        } else if !(left.reads_input() && right.reads_input()) {
+            let temp = self.push_temp();
+            self.load(right)?;
+            self.emit(InstructionType::Store(temp.clone()));
+            self.load(left)?;
+            self.apply(operator, temp);
+            self.pop_temp();
+        } else {
+            // both read input, which has to happen left to right
+            let first = self.push_temp();
+            self.load(left)?;
+            self.emit(InstructionType::Store(first.clone()));
+            let second = self.push_temp();
+            self.load(right)?;
+            self.emit(InstructionType::Store(second.clone()));
+            self.emit(InstructionType::Load(first));
+            self.apply(operator, second);
+            self.pop_temp();
+            self.pop_temp();
+        }
+        Ok(())
+    }
+
+    /// Evaluate `right - left` into the accumulator, reading `left` first
+    fn reversed_difference(
+        &mut self,
+        left: &Expression<'a>,
+        right: &Expression<'a>,
+    ) -> Result<(), CompileError> {
+        if left.reads_input() && right.reads_input() {
+            let temp = self.push_temp();
+            self.load(left)?;
+            self.emit(InstructionType::Store(temp.clone()));
+            self.load(right)?;
+            self.apply(Operator::Subtract, temp);
+            self.pop_temp();
+            Ok(())
+        } else {
+            self.binary(right, Operator::Subtract, left)
+        }
+    }
+
+    /// Branch to `otherwise` when `condition` is false, falling through when it's true.
+    /// Comparisons subtract, `BRP` checks the sign and `BRZ` equality.
+    fn branch_unless(
+        &mut self,
+        condition: &Condition<'a>,
+        otherwise: &str,
+    ) -> Result<(), CompileError> {
+        let Condition {
+            left,
+            comparison,
+            right,
+        } = condition;
+        match comparison {
+            Comparison::Equal
+            | Comparison::NotEqual
+            | Comparison::Less
+            | Comparison::GreaterEqual => self.binary(left, Operator::Subtract, right)?,
+            Comparison::Greater | Comparison::LessEqual => self.reversed_difference(left, right)?,
+        }
+        match comparison {
+            Comparison::NotEqual => self.emit(InstructionType::BranchIfZero(location(otherwise))),
+            Comparison::Less | Comparison::Greater => {
+                self.emit(InstructionType::BranchIfPositive(location(otherwise)))
+            }
+            Comparison::Equal | Comparison::GreaterEqual | Comparison::LessEqual => {
+                // branch over the jump to `otherwise` when true
+                let taken = location(&self.new_label("true"));
+                self.emit(match comparison {
+                    Comparison::Equal => InstructionType::BranchIfZero(taken.clone()),
+                    _ => InstructionType::BranchIfPositive(taken.clone()),
+                });
+                self.emit(InstructionType::BranchAlways(location(otherwise)));
+                self.place(&taken.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[(Node<'a>, Source<'a>)]) -> Result<(), CompileError> {
+        for (node, source) in statements {
+            self.source = *source;
+            self.comment = Some(source.text);
+            self.statement(node)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, node: &Node<'a>) -> Result<(), CompileError> {
+        match node {
+            Node::Assign { name, value } => {
+                if library::is_library_label(name) {
+                    return Err(CompileError::ReservedName {
+                        name: name.to_string(),
+                        line: self.source.line,
+                    });
+                }
+                self.load(value)?;
+                if !self.variables.iter().any(|(v, _)| v == name) {
+                    self.variables.push((name, self.source.line));
+                }
+                self.emit(InstructionType::Store(MemoryLocation::Label(
+                    Cow::Borrowed(*name),
+                )));
+            }
+            Node::Print(value) => {
+                self.load(value)?;
+                self.emit(InstructionType::Output);
+            }
+            Node::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let end = self.new_label("endif");
+                let skip = match otherwise.is_empty() {
+                    true => end.clone(),
+                    false => self.new_label("else"),
+                };
+                self.branch_unless(condition, &skip)?;
+                self.statements(then)?;
+                if !otherwise.is_empty() {
+                    self.emit(InstructionType::BranchAlways(location(&end)));
+                    self.place(&skip);
+                    self.statements(otherwise)?;
+                }
+                self.place(&end);
+            }
+            Node::While { condition, body } => {
+                let start = self.new_label("while");
+                let end = self.new_label("endwhile");
+                let source = self.source;
+                self.place(&start);
+                self.branch_unless(condition, &end)?;
+                self.statements(body)?;
+                self.source = source;
+                self.emit(InstructionType::BranchAlways(location(&start)));
+                self.place(&end);
+            }
+        }
+        Ok(())
+    }
+
+    fn data(&mut self, label: String, value: usize, line: usize) {
+        self.items.push(Item::Label(label));
+        self.items.push(Item::Instruction(Instruction {
+            instruction: InstructionType::Data(value),
+            comments: Box::new([]),
+            end_comment: None,
+            line,
+        }));
+    }
+
+    /// Attach every label to the instruction after it, merging labels of the same address
+    fn finish(self) -> Vec<Statement<'a>> {
+        let mut aliases = HashMap::new();
+        let mut pending: Vec<String> = vec![];
+        for item in &self.items {
+            match item {
+                Item::Label(label) => pending.push(label.clone()),
+                Item::Instruction(_) => {
+                    for alias in pending.iter().skip(1) {
+                        aliases.insert(alias.clone(), pending[0].clone());
+                    }
+                    pending.clear();
+                }
+            }
+        }
+        let resolve = |memory_location: MemoryLocation<'a>| match memory_location {
+            MemoryLocation::Label(label) => match aliases.get(label.as_ref()) {
+                Some(target) => location(target),
+                None => MemoryLocation::Label(label),
+            },
+            v => v,
+        };
+        let mut statements = vec![];
+        let mut label = None;
+        for item in self.items {
+            let mut instruction = match item {
+                Item::Label(v) => {
+                    label.get_or_insert(v);
+                    continue;
+                }
+                Item::Instruction(v) => v,
+            };
+            instruction.instruction = match instruction.instruction {
+                InstructionType::BranchAlways(v) => InstructionType::BranchAlways(resolve(v)),
+                InstructionType::BranchIfZero(v) => InstructionType::BranchIfZero(resolve(v)),
+                InstructionType::BranchIfPositive(v) => {
+                    InstructionType::BranchIfPositive(resolve(v))
+                }
+                v => v,
+            };
+            statements.push(match label.take() {
+                Some(label) => Statement::Labeled {
+                    label: Label {
+                        label: Cow::Owned(label),
+                        comments: Box::new([]),
+                    },
+                    instruction,
+                },
+                None => Statement::UnLabeled { instruction },
+            });
+        }
+        statements
+    }
+}
+
+/// Compile `source` into LMC statements, ready for [`crate::assembler::assemble_from_ast`].
+///
+/// Each variable, constant and scratch cell gets one `DAT` after the code,
+/// constants are shared between every use of the same value.
+/// The library routines used are linked after them.
+pub fn compile<'a>(
+    source: &'a str,
+    instruction_set: &InstructionSet,
+) -> Result<Vec<Statement<'a>>, CompileError> {
+    let program =
+        StructuredParser::parse(Rule::program, source).map_err(|err| CompileError::Syntax {
+            message: err.to_string(),
+        })?;
+    let statements: Vec<_> = program
+        .flat_map(|v| v.into_inner())
+        .filter(|v| v.as_rule() != Rule::EOI)
+        .map(parse_statement)
+        .collect();
+
+    let mut generator = Generator {
+        items: vec![],
+        max_value: instruction_set.config().max_value(),
+        variables: vec![],
+        constants: BTreeMap::new(),
+        returns: vec![],
+        temps: 0,
+        max_temps: 0,
+        labels: 0,
+        source: Source { text: "", line: 1 },
+        comment: None,
+    };
+    generator.statements(&statements)?;
+    generator.emit(InstructionType::Halt);
+    for (name, line) in generator.variables.clone() {
+        generator.data(name.to_string(), 0, line);
+    }
+    for (value, line) in generator.constants.clone() {
+        generator.data(generated_label("const", value), value, line);
+    }
+    for index in 0..generator.max_temps {
+        generator.data(generated_label("temp", index), 0, generator.source.line);
+    }
+    for (cell, back, line) in generator.returns.clone() {
+        generator.place(&cell);
+        generator.items.push(Item::Instruction(Instruction {
+            instruction: InstructionType::BranchAlways(location(&back)),
+            comments: Box::new([]),
+            end_comment: None,
+            line,
+        }));
+    }
+    let statements = library::link(generator.finish());
+
+    let available = instruction_set.config().memory_size();
+    if statements.len() > available {
+        return Err(CompileError::TooLarge {
+            required: statements.len(),
+            available,
+        });
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::{ast_to_source, try_parsed_to_ast, Statement};
+    use crate::expand::expand_pseudo_instructions;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::library;
+    use crate::runtime::{Headless, Runtime};
+
+    use super::{compile, CompileError};
+
+    fn run(source: &str, inputs: &[usize]) -> Vec<usize> {
+        let instruction_set = InstructionSet::standard();
+        let ast = compile(source, &instruction_set).unwrap();
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let mut memory = assembly.memory.clone();
+        let mut runtime = Headless::load_assembled(&mut memory, &instruction_set)
+            .with_inputs(inputs.iter().copied())
+            .with_step_limit(10_000);
+        runtime.run().unwrap();
+        runtime.outputs().iter().map(|v| v.value()).collect()
+    }
+
+    #[test]
+    fn test_countdown() {
+        let source = "// count down
+n = input;
+while n > 0 {
+    print n;
+    n = n - 1;
+}
+";
+        assert_eq!(run(source, &[3]), [3, 2, 1]);
+        assert_eq!(run(source, &[0]), []);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let source = "a = input; b = input;
+if a == b { print 1; } else { print 0; }
+if a != b { print 1; } else { print 0; }
+if a < b { print 1; } else { print 0; }
+if a <= b { print 1; } else { print 0; }
+if a > b { print 1; } else { print 0; }
+if a >= b { print 1; } else { print 0; }
+";
+        assert_eq!(run(source, &[2, 5]), [0, 1, 1, 1, 0, 0]);
+        assert_eq!(run(source, &[5, 5]), [1, 0, 0, 1, 0, 1]);
+        assert_eq!(run(source, &[7, 5]), [0, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        // nested if/else chains, parentheses and input read left to right
+        let source = "x = input - input;
+print x + (10 - (x + 1));
+print (input + 1) - (input - 2);
+if x > 5 { print 100; } else if x > 2 { print 50; } else { print 0; }
+";
+        assert_eq!(run(source, &[9, 5, 20, 7]), [9, 16, 50]);
+    }
+
+    #[test]
+    fn test_library_calls() {
+        let source = "a = input; b = input;
+print a * b; print a / b; print a % b; print -a;
+";
+        assert_eq!(run(source, &[7, 3]), [21, 2, 1, 993]);
+        let source = "a = input; b = input; print (a * b) / (a % b + 2) - -1;";
+        assert_eq!(run(source, &[7, 3]), [8]);
+        let source = "a = input; print compare(a, 5); print compare(input, a) + 10;";
+        assert_eq!(run(source, &[3, 4]), [0, 12]);
+        assert_eq!(run(source, &[5, 5]), [1, 11]);
+
+        let instruction_set = InstructionSet::standard();
+        let ast = compile("print 3 * 4;", &instruction_set).unwrap();
+        assert!(ast.iter().any(
+            |v| matches!(v, Statement::Labeled { label, .. } if label.label == library::MULTIPLY)
+        ));
+        assert!(!ast.iter().any(
+            |v| matches!(v, Statement::Labeled { label, .. } if label.label == library::DIVIDE)
+        ));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // the printed code, generated and library labels included, parses and runs the same
+        let source = "a = input; b = input;
+print a * b; print a / b; print compare(a, b);
+while a > 0 { a = a - 1; print a; }
+";
+        let instruction_set = InstructionSet::standard();
+        let ast = compile(source, &instruction_set).unwrap();
+        let printed = ast_to_source(&ast, &instruction_set);
+        let mut parsed = pass_program(&printed).unwrap();
+        let ast = try_parsed_to_ast(&mut parsed, &instruction_set).unwrap();
+        let expanded = expand_pseudo_instructions(ast);
+        let mut memory = assemble_from_ast(&expanded, &instruction_set)
+            .unwrap()
+            .memory;
+        let mut runtime = Headless::load_assembled(&mut memory, &instruction_set)
+            .with_inputs([3, 2])
+            .with_step_limit(10_000);
+        runtime.run().unwrap();
+        let outputs: Vec<usize> = runtime.outputs().iter().map(|v| v.value()).collect();
+        assert_eq!(outputs, run(source, &[3, 2]));
+        assert_eq!(outputs, [6, 1, 2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_allocation() {
+        let instruction_set = InstructionSet::standard();
+        let ast = compile("a = 5; b = a + 5; print b - 1;", &instruction_set).unwrap();
+        // LDA STA LDA ADD STA LDA SUB OUT HLT, then a, b, 1 and 5
+        assert_eq!(ast.len(), 13);
+    }
+
+    #[test]
+    fn test_errors() {
+        let instruction_set = InstructionSet::standard();
+        assert_eq!(
+            compile("a = 1;\nprint b;", &instruction_set),
+            Err(CompileError::UndefinedVariable {
+                name: "b".to_string(),
+                line: 2
+            })
+        );
+        assert_eq!(
+            compile("a = 1000;", &instruction_set),
+            Err(CompileError::ValueOutOfRange {
+                value: "1000".to_string(),
+                line: 1
+            })
+        );
+        assert!(matches!(
+            compile("print 1", &instruction_set),
+            Err(CompileError::Syntax { .. })
+        ));
+        assert_eq!(
+            compile("x = 1;\nlibmul = 2;", &instruction_set),
+            Err(CompileError::ReservedName {
+                name: "libmul".to_string(),
+                line: 2
+            })
+        );
+        let source = "a = input;\n".repeat(60);
+        assert_eq!(
+            compile(&source, &instruction_set),
+            Err(CompileError::TooLarge {
+                required: 122,
+                available: 100
+            })
+        );
+    }
+}
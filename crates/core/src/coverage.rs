@@ -0,0 +1,171 @@
+//! Which instructions and branch directions a run exercised.
+use std::collections::BTreeMap;
+
+use crate::ast::{Instruction, Statement};
+use crate::instruction_set::Operation;
+use crate::runtime::{Hook, Machine};
+
+/// Coverage of the instructions on one source line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub hits: usize,
+    /// Times a conditional branch on this line was taken and not taken
+    pub branches: Option<(usize, usize)>,
+}
+
+/// Collects coverage when used as a [`Hook`],
+/// sharing one between several runs merges their coverage
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    hits: BTreeMap<usize, usize>,
+    /// Taken and not taken counts of conditional branches, by address
+    branches: BTreeMap<usize, (usize, usize)>,
+}
+
+impl Hook for Coverage {
+    fn on_step(&mut self, address: usize, machine: &Machine) {
+        *self.hits.entry(address).or_default() += 1;
+        let operation = machine
+            .instruction_set()
+            .decode(machine.memory()[address])
+            .map(|(v, _)| v.operation);
+        // branches leave the accumulator and flag untouched, so the condition can be checked after
+        let taken = match operation {
+            Some(Operation::BranchIfZero) => machine.accumulator() == 0,
+            Some(Operation::BranchIfPositive) => !machine.negative_flag(),
+            _ => return,
+        };
+        let counts = self.branches.entry(address).or_default();
+        match taken {
+            true => counts.0 += 1,
+            false => counts.1 += 1,
+        }
+    }
+}
+
+impl Coverage {
+    /// Coverage per source line of the executable statements in `ast`
+    pub fn lines(&self, ast: &[Statement]) -> BTreeMap<usize, LineCoverage> {
+        let mut lines: BTreeMap<usize, LineCoverage> = BTreeMap::new();
+        for (address, stmt) in ast.iter().enumerate() {
+            let instruction: &Instruction = stmt.into();
+            let operation = instruction.instruction.operation();
+            if operation == Operation::Data {
+                continue;
+            }
+            let line = lines.entry(instruction.line).or_default();
+            line.hits += self.hits.get(&address).copied().unwrap_or(0);
+            if matches!(
+                operation,
+                Operation::BranchIfZero | Operation::BranchIfPositive
+            ) {
+                let (taken, not_taken) = self.branches.get(&address).copied().unwrap_or_default();
+                let branches = line.branches.get_or_insert((0, 0));
+                branches.0 += taken;
+                branches.1 += not_taken;
+            }
+        }
+        lines
+    }
+
+    /// Render `source` with hit counts, marking lines never executed with `#####`
+    /// and branches never taken in one of their directions
+    pub fn annotate(&self, source: &str, ast: &[Statement]) -> String {
+        let lines = self.lines(ast);
+        let mut listing = String::new();
+        for (index, text) in source.lines().enumerate() {
+            let (hits, branches) = match lines.get(&(index + 1)) {
+                Some(v) if v.hits == 0 => ("#####".to_string(), v.branches),
+                Some(v) => (v.hits.to_string(), v.branches),
+                None => (String::new(), None),
+            };
+            let branches = match branches {
+                Some((taken, not_taken)) if taken == 0 || not_taken == 0 => {
+                    format!("  <- branch taken {}, not taken {}", taken, not_taken)
+                }
+                _ => String::new(),
+            };
+            listing.push_str(&format!("{:>8} | {}{}\n", hits, text, branches));
+        }
+        listing
+    }
+
+    /// Render in the lcov tracefile format, for `source_file`
+    pub fn lcov(&self, source_file: &str, ast: &[Statement]) -> String {
+        let lines = self.lines(ast);
+        let mut lcov = format!("TN:\nSF:{}\n", source_file);
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+        for (line, coverage) in &lines {
+            if let Some((taken, not_taken)) = coverage.branches {
+                for (index, count) in [taken, not_taken].into_iter().enumerate() {
+                    let count = match coverage.hits {
+                        0 => "-".to_string(),
+                        _ => count.to_string(),
+                    };
+                    lcov.push_str(&format!("BRDA:{},0,{},{}\n", line, index, count));
+                }
+                branches_found += 2;
+                branches_hit += (taken > 0) as usize + (not_taken > 0) as usize;
+            }
+        }
+        lcov.push_str(&format!("BRF:{}\nBRH:{}\n", branches_found, branches_hit));
+        for (line, coverage) in &lines {
+            lcov.push_str(&format!("DA:{},{}\n", line, coverage.hits));
+        }
+        let lines_hit = lines.values().filter(|v| v.hits > 0).count();
+        lcov.push_str(&format!(
+            "LF:{}\nLH:{}\nend_of_record\n",
+            lines.len(),
+            lines_hit
+        ));
+        lcov
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::testing::TestSpec;
+
+    use super::Coverage;
+
+    const SOURCE: &str = "INP
+BRZ zero
+OUT
+HLT
+zero: HLT
+";
+
+    #[test]
+    fn test_coverage() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(SOURCE).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let spec = TestSpec::from_toml(
+            "[[test]]\nname = 'a'\ninputs = [1]\nexpected_outputs = [1]\n\
+             [[test]]\nname = 'b'\ninputs = [2]\nexpected_outputs = [2]\n",
+        )
+        .unwrap();
+        let mut coverage = Coverage::default();
+        spec.run_with_hook(&assembly, &instruction_set, &mut coverage);
+        assert_eq!(
+            coverage.annotate(SOURCE, &ast),
+            "       2 | INP
+       2 | BRZ zero  <- branch taken 0, not taken 2
+       2 | OUT
+       2 | HLT
+   ##### | zero: HLT
+"
+        );
+        assert_eq!(
+            coverage.lcov("a.lmc", &ast),
+            "TN:\nSF:a.lmc\nBRDA:2,0,0,0\nBRDA:2,0,1,2\nBRF:2\nBRH:1\n\
+             DA:1,2\nDA:2,2\nDA:3,2\nDA:4,2\nDA:5,0\nLF:5\nLH:4\nend_of_record\n"
+        );
+    }
+}
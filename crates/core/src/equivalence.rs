@@ -0,0 +1,310 @@
+//! Searching for inputs on which a program behaves differently to a reference solution.
+//!
+//! Input sequences are tried from boundary values, then every sequence short
+//! enough to try all of, then at random. Sequences the reference can't finish with are
+//! skipped, so a difference is always something the reference does properly.
+use std::mem::discriminant;
+
+use crate::assembler::Assembly;
+use crate::instruction_set::InstructionSet;
+use crate::runtime::{Headless, Runtime, RuntimeError};
+use crate::testing::DEFAULT_STEP_LIMIT;
+
+/// Default number of random input sequences tried
+pub const DEFAULT_RANDOM_TESTS: usize = 1000;
+/// Default length of the longest input sequence tried
+pub const DEFAULT_MAX_INPUTS: usize = 6;
+/// Most sequences each length is searched exhaustively with
+const EXHAUSTIVE_LIMIT: usize = 100_000;
+/// Values every sequence of is tried, before random ones
+const SMALL_VALUES: usize = 10;
+
+/// The nth of a set of input values, generated rather than listed as there can be many
+type Nth<'a> = &'a dyn Fn(usize) -> usize;
+
+/// What a program did with a sequence of inputs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Behaviour {
+    pub outputs: Vec<usize>,
+    pub error: Option<RuntimeError>,
+    /// Number of the inputs read
+    pub inputs_read: usize,
+}
+
+impl Behaviour {
+    /// Whether the program ran out of inputs or steps rather than finishing
+    fn is_unfinished(&self) -> bool {
+        matches!(
+            self.error,
+            Some(RuntimeError::InputExhausted { .. } | RuntimeError::StepLimitExceeded { .. })
+        )
+    }
+}
+
+/// Inputs on which the programs differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    /// Only as many as either program read
+    pub inputs: Vec<usize>,
+    pub reference: Behaviour,
+    pub candidate: Behaviour,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    /// Number of input sequences both programs ran with
+    pub tests: usize,
+    pub counterexample: Option<Counterexample>,
+}
+
+/// Compares a candidate program with a reference solution
+pub struct Checker<'a> {
+    reference: &'a Assembly,
+    candidate: &'a Assembly,
+    instruction_set: &'a InstructionSet,
+    step_limit: usize,
+    max_inputs: usize,
+    random_tests: usize,
+    seed: u64,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(
+        reference: &'a Assembly,
+        candidate: &'a Assembly,
+        instruction_set: &'a InstructionSet,
+    ) -> Self {
+        Self {
+            reference,
+            candidate,
+            instruction_set,
+            step_limit: DEFAULT_STEP_LIMIT,
+            max_inputs: DEFAULT_MAX_INPUTS,
+            random_tests: DEFAULT_RANDOM_TESTS,
+            seed: 0,
+        }
+    }
+
+    /// Stop each run after this many instructions
+    pub fn with_step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = step_limit;
+        self
+    }
+
+    pub fn with_max_inputs(mut self, max_inputs: usize) -> Self {
+        self.max_inputs = max_inputs;
+        self
+    }
+
+    pub fn with_random_tests(mut self, random_tests: usize) -> Self {
+        self.random_tests = random_tests;
+        self
+    }
+
+    /// Seed of the random sequences, the same seed always tries the same ones
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn run(&self, assembly: &Assembly, inputs: &[usize]) -> Behaviour {
+        let mut memory = assembly.memory.clone();
+        let mut runtime = Headless::load_assembled(&mut memory, self.instruction_set)
+            .with_inputs(inputs.iter().copied())
+            .with_step_limit(self.step_limit);
+        let error = runtime.run().err();
+        Behaviour {
+            outputs: runtime.outputs().iter().map(|v| v.value()).collect(),
+            error,
+            inputs_read: inputs.len() - runtime.inputs().count(),
+        }
+    }
+
+    /// Every sequence of `length` values out of `count`, the nth given by `value`
+    fn sequences(
+        count: usize,
+        value: Nth<'_>,
+        length: usize,
+    ) -> impl Iterator<Item = Vec<usize>> + '_ {
+        let sequences = count.pow(length as u32);
+        (0..sequences).map(move |mut v| {
+            (0..length)
+                .map(|_| {
+                    let nth = value(v % count);
+                    v /= count;
+                    nth
+                })
+                .collect()
+        })
+    }
+
+    /// Run both programs with `inputs`, returning how they differ when they do
+    fn test(&self, inputs: &[usize], tests: &mut usize) -> Option<Counterexample> {
+        let reference = self.run(self.reference, inputs);
+        if reference.is_unfinished() {
+            return None;
+        }
+        *tests += 1;
+        let candidate = self.run(self.candidate, inputs);
+        // addresses differ between programs, so only the kind of error has to match
+        let same = candidate.outputs == reference.outputs
+            && candidate.error.as_ref().map(discriminant)
+                == reference.error.as_ref().map(discriminant);
+        if same {
+            return None;
+        }
+        let read = reference.inputs_read.max(candidate.inputs_read);
+        Some(Counterexample {
+            inputs: inputs[..read].to_vec(),
+            reference,
+            candidate,
+        })
+    }
+
+    /// Search for inputs on which the candidate differs from the reference, trying
+    /// boundary values, then every value, then small values and then random ones
+    pub fn check(&self) -> Verdict {
+        let max = self.instruction_set.config().max_value();
+        let boundaries = [0, 1, max];
+        let small = SMALL_VALUES.min(max + 1);
+        let mut tests = 0;
+        let verdict = |tests, counterexample| Verdict {
+            tests,
+            counterexample,
+        };
+        let exhaustive = |count: usize, length: usize| {
+            count
+                .checked_pow(length as u32)
+                .is_some_and(|v| v <= EXHAUSTIVE_LIMIT)
+        };
+        // lengths every value was tried at needn't be tried again with small values
+        let covered = (1..=self.max_inputs)
+            .take_while(|length| exhaustive(max + 1, *length))
+            .count();
+        let passes: [(usize, Nth<'_>, usize); 3] = [
+            (boundaries.len(), &|v| boundaries[v], 1),
+            (max + 1, &|v| v, 1),
+            (small, &|v| v, covered + 1),
+        ];
+        for (count, value, first) in passes {
+            for length in first..=self.max_inputs {
+                if !exhaustive(count, length) {
+                    break;
+                }
+                for inputs in Self::sequences(count, value, length) {
+                    if let Some(counterexample) = self.test(&inputs, &mut tests) {
+                        return verdict(tests, Some(counterexample));
+                    }
+                }
+            }
+        }
+        let mut rng = fastrand::Rng::with_seed(self.seed);
+        for _ in 0..self.random_tests {
+            let inputs: Vec<usize> = (0..rng.usize(1..=self.max_inputs.max(1)))
+                .map(|_| match rng.u8(0..4) {
+                    0 => boundaries[rng.usize(0..boundaries.len())],
+                    1 => rng.usize(0..small),
+                    _ => rng.usize(0..=max),
+                })
+                .collect();
+            if let Some(counterexample) = self.test(&inputs, &mut tests) {
+                return verdict(tests, Some(counterexample));
+            }
+        }
+        verdict(tests, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble_from_ast, Assembly};
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::{InstructionSet, MachineConfig};
+    use crate::runtime::RuntimeError;
+
+    use super::Checker;
+
+    fn assemble(source: &str) -> Assembly {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        assemble_from_ast(&ast, &instruction_set).unwrap()
+    }
+
+    const MAXIMUM: &str = "INP\nSTA a\nINP\nSTA b\nSUB a\nBRP second\nLDA a\nOUT\nHLT\n\
+                           second: LDA b\nOUT\nHLT\na: DAT\nb: DAT\n";
+
+    #[test]
+    fn test_equivalent() {
+        let instruction_set = InstructionSet::standard();
+        let reference = assemble(MAXIMUM);
+        let candidate = assemble(
+            "INP\nSTA a\nINP\nSTA b\nLDA a\nSUB b\nBRP first\nLDA b\nOUT\nHLT\n\
+             first: LDA a\nOUT\nHLT\na: DAT\nb: DAT\n",
+        );
+        let verdict = Checker::new(&reference, &candidate, &instruction_set)
+            .with_max_inputs(2)
+            .with_random_tests(100)
+            .check();
+        assert_eq!(verdict.counterexample, None);
+        assert!(verdict.tests > 100);
+        // boundaries then every value once, small values are already covered
+        let echo = assemble("INP\nOUT\nHLT\n");
+        let verdict = Checker::new(&echo, &echo, &instruction_set)
+            .with_max_inputs(1)
+            .with_random_tests(0)
+            .check();
+        assert_eq!(verdict.tests, 3 + 1000);
+        // too many values to try every one, so small values are tried instead
+        let config = MachineConfig::new(100, 12).unwrap();
+        let wide = InstructionSet::standard().with_config(config);
+        let verdict = Checker::new(&echo, &echo, &wide)
+            .with_max_inputs(1)
+            .with_random_tests(10)
+            .check();
+        assert_eq!(verdict.tests, 3 + 10 + 10);
+    }
+
+    #[test]
+    fn test_counterexample() {
+        let instruction_set = InstructionSet::standard();
+        let reference = assemble(MAXIMUM);
+        // wrong when the second input is 7, found by trying every pair of small values
+        let candidate = assemble(
+            "INP\nSTA a\nINP\nSTA b\nSUB magic\nBRZ wrong\nLDA b\nSUB a\nBRP second\n\
+             wrong: LDA a\nOUT\nHLT\nsecond: LDA b\nOUT\nHLT\na: DAT\nb: DAT\nmagic: DAT 7\n",
+        );
+        let counterexample = Checker::new(&reference, &candidate, &instruction_set)
+            .check()
+            .counterexample
+            .unwrap();
+        assert_eq!(counterexample.inputs, vec![0, 7]);
+        assert_eq!(counterexample.reference.outputs, vec![7]);
+        assert_eq!(counterexample.candidate.outputs, vec![0]);
+        // asking for more inputs than the reference differs
+        let candidate = assemble(&MAXIMUM.replace("OUT\nHLT\na:", "OUT\nINP\nHLT\na:"));
+        let counterexample = Checker::new(&reference, &candidate, &instruction_set)
+            .check()
+            .counterexample
+            .unwrap();
+        assert_eq!(counterexample.inputs, vec![0, 0]);
+        assert_eq!(
+            counterexample.candidate.error,
+            Some(RuntimeError::InputExhausted { address: 11 })
+        );
+        // failing differently differs, even with the same outputs
+        let reference = assemble("INP\nOUT\nDAT 400\n");
+        let candidate = assemble("INP\nOUT\nloop: BRA loop\n");
+        let counterexample = Checker::new(&reference, &candidate, &instruction_set)
+            .with_step_limit(100)
+            .check()
+            .counterexample
+            .unwrap();
+        assert_eq!(counterexample.inputs, vec![0]);
+        assert_eq!(
+            counterexample.candidate.error,
+            Some(RuntimeError::StepLimitExceeded { limit: 100 })
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Expansion of pseudo-instructions into plain LMC.
+use std::borrow::Cow;
+
+use crate::ast::{
+    Instruction, InstructionType, Label, MemoryLocation, Statement, GENERATED_LABEL_PREFIX,
+};
+use crate::library;
+
+fn generated_label<'a>(kind: &str, count: usize, name: &str) -> Cow<'a, str> {
+    Cow::Owned(format!(
+        "{}{}{}_{}",
+        GENERATED_LABEL_PREFIX, kind, count, name
+    ))
+}
+
+fn generated_statement<'a>(
+    kind: &str,
+    count: usize,
+    name: &str,
+    instruction: InstructionType<'a>,
+    line: usize,
+) -> Statement<'a> {
+    let label = Label {
+        label: generated_label(kind, count, name),
+        comments: Box::new([]),
+    };
+    new_statement(Some(label), instruction, (Box::new([]), None), line)
+}
+
+fn generated_location<'a>(kind: &str, count: usize, name: &str) -> MemoryLocation<'a> {
+    MemoryLocation::Label(generated_label(kind, count, name))
+}
+
+fn new_statement<'a>(
+    label: Option<Label<'a>>,
+    instruction: InstructionType<'a>,
+    (comments, end_comment): (Box<[&'a str]>, Option<&'a str>),
+    line: usize,
+) -> Statement<'a> {
+    let instruction = Instruction {
+        instruction,
+        comments,
+        end_comment,
+        line,
+    };
+    match label {
+        Some(label) => Statement::Labeled { label, instruction },
+        None => Statement::UnLabeled { instruction },
+    }
+}
+
+/// Expand `LDI table, index` and `STI table, index` into the standard
+/// self-modifying pattern, which builds a `LDA`/`STA` instruction
+/// addressing `table + index` and then executes it.
+///
+/// Constants and scratch cells are placed after the program,
+/// every generated statement keeps the source line of its pseudo-instruction.
+/// Routines from [`crate::library`] the program calls are linked after those.
+pub fn expand_pseudo_instructions(ast: Vec<Statement<'_>>) -> Vec<Statement<'_>> {
+    let mut expanded = vec![];
+    let mut pool = vec![];
+    let mut count = 0;
+    for stmt in ast {
+        let (label, instruction) = match stmt {
+            Statement::Labeled { label, instruction } => (Some(label), instruction),
+            Statement::UnLabeled { instruction } => (None, instruction),
+        };
+        let Instruction {
+            instruction,
+            comments,
+            end_comment,
+            line,
+        } = instruction;
+        let (kind, sequence) = match instruction {
+            InstructionType::LoadIndexed(table, index) => {
+                pool.push(generated_statement(
+                    "ldi",
+                    count,
+                    "base",
+                    InstructionType::Load(table),
+                    line,
+                ));
+                let sequence = vec![
+                    InstructionType::Load(generated_location("ldi", count, "base")),
+                    InstructionType::Add(index),
+                    InstructionType::Store(generated_location("ldi", count, "exec")),
+                ];
+                ("ldi", sequence)
+            }
+            InstructionType::StoreIndexed(table, index) => {
+                pool.push(generated_statement(
+                    "sti",
+                    count,
+                    "base",
+                    InstructionType::Store(table),
+                    line,
+                ));
+                pool.push(generated_statement(
+                    "sti",
+                    count,
+                    "value",
+                    InstructionType::Data(0),
+                    line,
+                ));
+                let sequence = vec![
+                    InstructionType::Store(generated_location("sti", count, "value")),
+                    InstructionType::Load(generated_location("sti", count, "base")),
+                    InstructionType::Add(index),
+                    InstructionType::Store(generated_location("sti", count, "exec")),
+                    InstructionType::Load(generated_location("sti", count, "value")),
+                ];
+                ("sti", sequence)
+            }
+            instruction => {
+                expanded.push(new_statement(
+                    label,
+                    instruction,
+                    (comments, end_comment),
+                    line,
+                ));
+                continue;
+            }
+        };
+        let mut label = label;
+        let mut comments = Some((comments, end_comment));
+        for instruction in sequence {
+            expanded.push(new_statement(
+                label.take(),
+                instruction,
+                comments.take().unwrap_or_default(),
+                line,
+            ));
+        }
+        // overwritten with the built instruction just before it runs
+        expanded.push(generated_statement(
+            kind,
+            count,
+            "exec",
+            InstructionType::Data(0),
+            line,
+        ));
+        count += 1;
+    }
+    expanded.extend(pool);
+    library::link(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::{ast_to_source, parsed_to_ast};
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::{Headless, Runtime};
+
+    use super::expand_pseudo_instructions;
+
+    const SOURCE: &str = r#"
+; copy table[1] into table[2]
+start:
+    LDI table, one
+    STI table, two
+    HLT
+one: DAT 1
+two: DAT 2
+table: DAT 10
+    DAT 20
+    DAT 30
+"#;
+
+    #[test]
+    fn test_expand() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(SOURCE).unwrap();
+        let ast = expand_pseudo_instructions(parsed_to_ast(&mut parsed, &instruction_set));
+        let source = ast_to_source(&ast, &instruction_set);
+        assert_eq!(
+            source,
+            r#"; copy table[1] into table[2]
+start: LDA __ldi0_base
+    ADD one
+    STA __ldi0_exec
+__ldi0_exec: DAT 0
+    STA __sti1_value
+    LDA __sti1_base
+    ADD two
+    STA __sti1_exec
+    LDA __sti1_value
+__sti1_exec: DAT 0
+    HLT
+one: DAT 1
+two: DAT 2
+table: DAT 10
+    DAT 20
+    DAT 30
+__ldi0_base: LDA table
+__sti1_base: STA table
+__sti1_value: DAT 0
+"#
+        );
+        let lines: Vec<usize> = ast
+            .iter()
+            .map(|v| <&crate::ast::Instruction>::from(v).line)
+            .collect();
+        assert_eq!(lines[..4], [4, 4, 4, 4]);
+        assert_eq!(lines[4..10], [5, 5, 5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_run_expanded() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(SOURCE).unwrap();
+        let ast = expand_pseudo_instructions(parsed_to_ast(&mut parsed, &instruction_set));
+        let mut memory = assemble_from_ast(&ast, &instruction_set).unwrap().memory;
+        Headless::load_assembled(&mut memory, &instruction_set)
+            .run()
+            .unwrap();
+        assert_eq!(memory[13..16], [10, 20, 20]);
+    }
+}
@@ -0,0 +1,326 @@
+//! Fuzzing the toolchain with generated source, and programs with generated inputs.
+//!
+//! The `check_*` functions are the targets, panicking only on a bug. They're
+//! driven with seeded random data here so they run as ordinary tests, and by
+//! the cargo-fuzz targets in `fuzz/` for longer searches.
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use fastrand::Rng;
+
+use crate::assembler::{assemble_from_ast, Assembly};
+use crate::ast::try_parsed_to_ast;
+use crate::expand::expand_pseudo_instructions;
+use crate::grammar::pass_program;
+use crate::instruction_set::InstructionSet;
+use crate::runtime::{Headless, Runtime, RuntimeError};
+use crate::symbolic::Explorer;
+
+/// Steps each generated program runs for
+const STEP_LIMIT: usize = 1000;
+/// Steps and paths each generated program is explored for symbolically,
+/// every step can call the solver
+const SYMBOLIC_STEP_LIMIT: usize = 200;
+const MAX_PATHS: usize = 32;
+
+/// Fragments generated source is built from, chosen to reach every rule of the grammar
+const TOKENS: &[&str] = &[
+    "INP",
+    "OUT",
+    "LDA",
+    "STA",
+    "ADD",
+    "SUB",
+    "MUL",
+    "BRA",
+    "BRZ",
+    "BRP",
+    "HLT",
+    "DAT",
+    "OTC",
+    "INA",
+    "CAL",
+    "RET",
+    "LDI",
+    "STI",
+    "lda",
+    "a",
+    "b",
+    "loop",
+    "start",
+    "0",
+    "1",
+    "99",
+    "100",
+    "999",
+    "1000",
+    "18446744073709551616",
+    "@",
+    "@a",
+    "@5",
+    ",",
+    ":",
+    ";",
+    " ",
+    "\t",
+    "\n",
+    "\r\n",
+    "a:",
+    "; comment",
+    "__",
+    "é",
+];
+
+/// Source text of random tokens
+pub fn generate_source(rng: &mut Rng) -> String {
+    let length = rng.usize(0..64);
+    let mut source = String::new();
+    for _ in 0..length {
+        source.push_str(TOKENS[rng.usize(0..TOKENS.len())]);
+        if rng.bool() {
+            source.push(' ');
+        }
+    }
+    source
+}
+
+/// Mangle `source` a few times, by character and by line
+pub fn mutate(source: &str, rng: &mut Rng) -> String {
+    let mut chars: Vec<char> = source.chars().collect();
+    for _ in 0..rng.usize(1..8) {
+        let at = rng.usize(0..=chars.len());
+        match rng.u8(0..6) {
+            0 if at < chars.len() => {
+                chars.remove(at);
+            }
+            1 if at < chars.len() => chars[at] = rng.char('\0'..='\u{7f}'),
+            2 => {
+                let token = TOKENS[rng.usize(0..TOKENS.len())];
+                chars.splice(at..at, token.chars());
+            }
+            3 => {
+                // repeat a run, making long numbers and labels
+                let end = (at + rng.usize(1..8)).min(chars.len());
+                let run: Vec<char> = chars[at..end].to_vec();
+                chars.splice(at..at, run);
+            }
+            4 => {
+                let mut lines: Vec<String> = chars
+                    .iter()
+                    .collect::<String>()
+                    .lines()
+                    .map(String::from)
+                    .collect();
+                if lines.len() > 1 {
+                    let (a, b) = (rng.usize(0..lines.len()), rng.usize(0..lines.len()));
+                    lines.swap(a, b);
+                }
+                chars = lines.join("\n").chars().collect();
+            }
+            _ => chars.insert(at.min(chars.len()), rng.char('\0'..='\u{7f}')),
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// Parse, expand and assemble `source`, then run it when it assembles.
+/// Errors are expected, panics are bugs.
+pub fn check_source(source: &str, instruction_set: &InstructionSet) {
+    let Ok(mut parsed) = pass_program(source) else {
+        return;
+    };
+    let Ok(ast) = try_parsed_to_ast(&mut parsed, instruction_set) else {
+        return;
+    };
+    let expanded = expand_pseudo_instructions(ast);
+    if let Ok(assembly) = assemble_from_ast(&expanded, instruction_set) {
+        check_memory(&assembly.memory, &[1, 0, 999, 2], instruction_set);
+        check_symbolic(&assembly.memory, instruction_set);
+    }
+}
+
+/// Run `memory` as a program with `inputs`, whatever it holds.
+/// Errors are expected, panics are bugs.
+pub fn check_memory(memory: &[usize], inputs: &[usize], instruction_set: &InstructionSet) {
+    let mut memory = memory.to_vec();
+    memory.resize(instruction_set.config().memory_size(), 0);
+    let mut runtime = Headless::load_assembled(&mut memory, instruction_set)
+        .with_inputs(inputs.iter().copied())
+        .with_step_limit(STEP_LIMIT);
+    let _ = runtime.run();
+}
+
+/// Explore `memory` symbolically as a program, whatever it holds.
+/// Abandoned paths are expected, panics are bugs.
+pub fn check_symbolic(memory: &[usize], instruction_set: &InstructionSet) {
+    let mut memory = memory.to_vec();
+    memory.resize(instruction_set.config().memory_size(), 0);
+    Explorer::new(&memory, instruction_set)
+        .with_step_limit(SYMBOLIC_STEP_LIMIT)
+        .with_max_paths(MAX_PATHS)
+        .explore();
+}
+
+/// Source which panicked the toolchain, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Crash {
+    pub source: String,
+    pub message: String,
+}
+
+/// Check `iterations` sources, generated or mutated from `seeds`, returning those which panicked.
+/// Panics are still reported by the process's panic hook, which callers may replace to quiet them.
+pub fn fuzz_toolchain(
+    seeds: &[&str],
+    iterations: usize,
+    seed: u64,
+    instruction_set: &InstructionSet,
+) -> Vec<Crash> {
+    let mut rng = Rng::with_seed(seed);
+    let mut crashes = vec![];
+    for _ in 0..iterations {
+        let source = match seeds.is_empty() || rng.bool() {
+            true => generate_source(&mut rng),
+            false => mutate(seeds[rng.usize(0..seeds.len())], &mut rng),
+        };
+        let result = catch_unwind(AssertUnwindSafe(|| check_source(&source, instruction_set)));
+        if let Err(panic) = result {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|v| v.to_string()))
+                .unwrap_or_default();
+            crashes.push(Crash { source, message });
+        }
+    }
+    crashes
+}
+
+/// Inputs on which a program stopped with a runtime error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputFailure {
+    pub inputs: Vec<usize>,
+    pub error: RuntimeError,
+}
+
+/// Run `assembly` with `iterations` random input sequences of up to `max_inputs` values,
+/// returning the first inputs found for each error. Running out of inputs isn't an error.
+pub fn fuzz_inputs(
+    assembly: &Assembly,
+    instruction_set: &InstructionSet,
+    iterations: usize,
+    max_inputs: usize,
+    step_limit: usize,
+    seed: u64,
+) -> Vec<InputFailure> {
+    let mut rng = Rng::with_seed(seed);
+    let max = instruction_set.config().max_value();
+    let mut failures: Vec<InputFailure> = vec![];
+    for _ in 0..iterations {
+        let inputs: Vec<usize> = (0..rng.usize(0..=max_inputs))
+            .map(|_| match rng.u8(0..4) {
+                0 => [0, 1, max][rng.usize(0..3)],
+                1 => rng.usize(0..10),
+                _ => rng.usize(0..=max),
+            })
+            .collect();
+        let mut memory = assembly.memory.clone();
+        let mut runtime = Headless::load_assembled(&mut memory, instruction_set)
+            .with_inputs(inputs.iter().copied())
+            .with_step_limit(step_limit);
+        match runtime.run() {
+            Ok(()) | Err(RuntimeError::InputExhausted { .. }) => {}
+            Err(error) if failures.iter().any(|v| v.error == error) => {}
+            Err(error) => failures.push(InputFailure { inputs, error }),
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use fastrand::Rng;
+
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::RuntimeError;
+
+    use super::{check_memory, check_symbolic, fuzz_inputs, fuzz_toolchain};
+
+    const SEEDS: &[&str] = &[
+        include_str!("../../../examples/add_two.lmc"),
+        include_str!("../../../examples/hello.lmc"),
+        include_str!("../../../examples/subroutine.lmc"),
+        "LDI table, i\nSTI table, i\nHLT\ntable: DAT 1\ni: DAT 0\n",
+        "LDA @ptr\nSTA @5\nHLT\nptr: DAT 4\n",
+    ];
+
+    #[test]
+    fn test_toolchain() {
+        let instruction_sets = [
+            InstructionSet::standard(),
+            InstructionSet::standard()
+                .with_extended_io()
+                .with_subroutines(),
+            InstructionSet::standard().with_indirect(),
+        ];
+        for (seed, instruction_set) in instruction_sets.iter().enumerate() {
+            let crashes = fuzz_toolchain(SEEDS, 2000, seed as u64, instruction_set);
+            assert_eq!(crashes, vec![]);
+        }
+    }
+
+    #[test]
+    fn test_random_memory() {
+        let instruction_set = InstructionSet::standard()
+            .with_extended_io()
+            .with_subroutines()
+            .with_indirect();
+        let mut rng = Rng::with_seed(0);
+        for _ in 0..500 {
+            // mostly instructions, with some words far too wide for the machine
+            let mut word = || match rng.u8(0..4) {
+                0 => rng.usize(..),
+                _ => rng.usize(0..2000),
+            };
+            let memory: Vec<usize> = (0..100).map(|_| word()).collect();
+            let inputs: Vec<usize> = (0..4).map(|_| word()).collect();
+            check_memory(&memory, &inputs, &instruction_set);
+        }
+    }
+
+    #[test]
+    fn test_random_symbolic() {
+        let instruction_set = InstructionSet::standard()
+            .with_subroutines()
+            .with_indirect();
+        let mut rng = Rng::with_seed(0);
+        for _ in 0..1000 {
+            let mut word = || match rng.u8(0..8) {
+                0 => rng.usize(..),
+                _ => rng.usize(0..1000),
+            };
+            let memory: Vec<usize> = (0..100).map(|_| word()).collect();
+            check_symbolic(&memory, &instruction_set);
+        }
+    }
+
+    #[test]
+    fn test_inputs() {
+        // divides by the input, looping forever when it's zero
+        let source =
+            "INP\nSTA d\nLDA n\nloop: SUB d\nBRP loop\nLDA n\nOUT\nHLT\nn: DAT 100\nd: DAT\n";
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let failures = fuzz_inputs(&assembly, &instruction_set, 200, 2, 1000, 0);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].inputs[0], 0);
+        assert_eq!(
+            failures[0].error,
+            RuntimeError::StepLimitExceeded { limit: 1000 }
+        );
+    }
+}
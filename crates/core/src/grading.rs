@@ -0,0 +1,223 @@
+//! Batch grading of submissions against a shared test spec.
+use std::panic::{self, AssertUnwindSafe};
+
+use serde::Serialize;
+
+use crate::assembler::assemble_from_ast;
+use crate::ast::try_parsed_to_ast;
+use crate::expand::expand_pseudo_instructions;
+use crate::grammar::pass_program;
+use crate::instruction_set::InstructionSet;
+use crate::stats::Stats;
+use crate::testing::TestSpec;
+
+/// One line of a grading report, a single test case of a single submission
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GradeRow {
+    pub file: String,
+    /// Empty when the submission could not be assembled
+    pub test: String,
+    pub passed: bool,
+    pub steps: usize,
+    /// Number of mailboxes used by the assembled program
+    pub mailboxes: Option<usize>,
+    /// Instructions completed, unlike `steps` not counting one which errored
+    pub instructions: usize,
+    pub memory_reads: usize,
+    pub memory_writes: usize,
+    pub branches_taken: usize,
+    pub branches_not_taken: usize,
+    /// Distinct mailboxes executed as instructions
+    pub code_mailboxes: usize,
+    /// Distinct mailboxes read or written as data
+    pub data_mailboxes: usize,
+    /// Parse, assembler or runtime errors and mismatches, separated by `; `
+    pub error: String,
+}
+
+pub const CSV_HEADER: &str = "file,test,passed,steps,mailboxes,instructions,memory_reads,\
+                              memory_writes,branches_taken,branches_not_taken,\
+                              code_mailboxes,data_mailboxes,error";
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+impl GradeRow {
+    /// A row for a submission which couldn't be tested at all
+    pub fn error(file: &str, error: String) -> Self {
+        Self::new(
+            file,
+            String::new(),
+            false,
+            0,
+            None,
+            &Stats::default(),
+            error,
+        )
+    }
+
+    fn new(
+        file: &str,
+        test: String,
+        passed: bool,
+        steps: usize,
+        mailboxes: Option<usize>,
+        stats: &Stats,
+        error: String,
+    ) -> Self {
+        Self {
+            file: file.to_string(),
+            test,
+            passed,
+            steps,
+            mailboxes,
+            instructions: stats.instructions,
+            memory_reads: stats.memory_reads,
+            memory_writes: stats.memory_writes,
+            branches_taken: stats.branches_taken,
+            branches_not_taken: stats.branches_not_taken,
+            code_mailboxes: stats.code_mailboxes(),
+            data_mailboxes: stats.data_mailboxes(),
+            error,
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&self.file),
+            csv_field(&self.test),
+            self.passed,
+            self.steps,
+            self.mailboxes.map(|v| v.to_string()).unwrap_or_default(),
+            self.instructions,
+            self.memory_reads,
+            self.memory_writes,
+            self.branches_taken,
+            self.branches_not_taken,
+            self.code_mailboxes,
+            self.data_mailboxes,
+            csv_field(&self.error),
+        )
+    }
+}
+
+fn grade(
+    file: &str,
+    source: &str,
+    spec: &TestSpec,
+    instruction_set: &InstructionSet,
+) -> Vec<GradeRow> {
+    let mut parsed = match pass_program(source) {
+        Ok(parsed) => parsed,
+        Err(err) => return vec![GradeRow::error(file, format!("parse error: {}", err))],
+    };
+    let ast = match try_parsed_to_ast(&mut parsed, instruction_set) {
+        Ok(ast) => expand_pseudo_instructions(ast),
+        Err(err) => return vec![GradeRow::error(file, format!("parse error: {}", err))],
+    };
+    let assembly = match assemble_from_ast(&ast, instruction_set) {
+        Ok(assembly) => assembly,
+        Err(err) => return vec![GradeRow::error(file, format!("assembler error: {:?}", err))],
+    };
+    spec.run(&assembly, instruction_set)
+        .into_iter()
+        .map(|result| {
+            let error = result
+                .failures
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("; ");
+            GradeRow::new(
+                file,
+                result.name.clone(),
+                result.passed(),
+                result.steps,
+                Some(assembly.program_size()),
+                &result.stats,
+                error,
+            )
+        })
+        .collect()
+}
+
+/// Assemble and test a single submission,
+/// any panic is caught and reported as an error row instead.
+pub fn grade_submission(
+    file: &str,
+    source: &str,
+    spec: &TestSpec,
+    instruction_set: &InstructionSet,
+) -> Vec<GradeRow> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        grade(file, source, spec, instruction_set)
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|v| v.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        vec![GradeRow::error(file, format!("panic: {}", message))]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction_set::InstructionSet;
+    use crate::testing::TestSpec;
+
+    use super::grade_submission;
+
+    #[test]
+    fn test_grade() {
+        let spec = TestSpec::from_toml(
+            "step_limit = 50\n[[test]]\nname = 'echo'\ninputs = [5]\nexpected_outputs = [5]\n",
+        )
+        .unwrap();
+        let instruction_set = InstructionSet::standard();
+        let grade = |source| grade_submission("a.lmc", source, &spec, &instruction_set);
+
+        let rows = grade("INP\nOUT\nHLT\n");
+        assert!(rows[0].passed);
+        assert_eq!((rows[0].steps, rows[0].mailboxes), (3, Some(3)));
+
+        let rows = grade("INP\nSTA x\nBRZ skip\nLDA x\nskip: OUT\nHLT\nx: DAT\n");
+        assert!(rows[0].passed);
+        let row = &rows[0];
+        assert_eq!(row.instructions, 6);
+        assert_eq!((row.memory_reads, row.memory_writes), (1, 1));
+        assert_eq!((row.branches_taken, row.branches_not_taken), (0, 1));
+        assert_eq!((row.code_mailboxes, row.data_mailboxes), (6, 1));
+
+        let rows = grade("loop: INP\nOUT\nBRA loop\n");
+        assert!(!rows[0].passed);
+        assert!(rows[0].error.contains("InputExhausted"));
+
+        let rows = grade("OUT\nnext: BRA next\n");
+        assert_eq!(
+            rows[0].error,
+            "runtime error: StepLimitExceeded { limit: 50 }; expected outputs [5] but got [0]"
+        );
+        assert_eq!(
+            rows[0].to_csv(),
+            "a.lmc,echo,false,50,2,50,0,0,49,0,2,0,runtime error: StepLimitExceeded { limit: 50 }; expected outputs [5] but got [0]"
+        );
+
+        let rows = grade("FOO 1\n");
+        assert!(rows[0]
+            .error
+            .starts_with("parse error: unknown instruction type"));
+        assert_eq!(rows[0].mailboxes, None);
+
+        let rows = grade("BRA missing\n");
+        assert!(rows[0]
+            .error
+            .starts_with("assembler error: LabelNotDefined"));
+    }
+}
@@ -6,7 +6,7 @@ use pest_derive::Parser;
 pub struct LMCParser;
 
 #[allow(clippy::result_large_err)]
-pub fn pass_program(input: &str) -> Result<Pairs<Rule>, PestError<Rule>> {
+pub fn pass_program(input: &str) -> Result<Pairs<'_, Rule>, PestError<Rule>> {
     LMCParser::parse(Rule::program, input)
 }
 
@@ -27,6 +27,8 @@ mod tests {
         LMCParser::parse(Rule::label, "myLabel:").unwrap();
         LMCParser::parse(Rule::label, "; comment one\nmyLabel:").unwrap();
         LMCParser::parse(Rule::label, "myLabel:; comment two").unwrap();
+        LMCParser::parse(Rule::label, "__temp0:").unwrap();
+        assert!(LMCParser::parse(Rule::label, "0label:").is_err());
         assert!(LMCParser::parse(Rule::label, "myLabel another:").is_err());
         assert!(LMCParser::parse(Rule::label, "myLabel").is_err());
     }
@@ -35,7 +37,11 @@ mod tests {
     fn test_memory_location() {
         LMCParser::parse(Rule::memoryLocation, "10").unwrap();
         LMCParser::parse(Rule::memoryLocation, "0").unwrap();
+        LMCParser::parse(Rule::memoryLocation, "9999").unwrap();
         LMCParser::parse(Rule::memoryLocation, "labelled").unwrap();
+        LMCParser::parse(Rule::memoryLocation, "@labelled").unwrap();
+        LMCParser::parse(Rule::memoryLocation, "__ldi0_base").unwrap();
+        LMCParser::parse(Rule::memoryLocation, "@10").unwrap();
         assert!(LMCParser::parse(Rule::memoryLocation, "").is_err());
     }
 
@@ -44,6 +50,9 @@ mod tests {
         LMCParser::parse(Rule::instruction, "ADD").unwrap();
         LMCParser::parse(Rule::instruction, "ADD ").unwrap();
         LMCParser::parse(Rule::instruction, "add").unwrap();
+        LMCParser::parse(Rule::instruction, "IN").unwrap();
+        LMCParser::parse(Rule::instruction, "MULT").unwrap();
+        assert!(LMCParser::parse(Rule::instruction, "A").is_err());
         assert!(LMCParser::parse(Rule::instruction, "ADDDD").is_err());
         assert!(LMCParser::parse(Rule::instruction, "").is_err());
     }
@@ -54,6 +63,8 @@ mod tests {
         LMCParser::parse(Rule::instruction, "ADD var ; add something").unwrap();
         LMCParser::parse(Rule::instruction, "add: ADD var").unwrap();
         LMCParser::parse(Rule::instruction, "add:\n ADD var").unwrap();
+        LMCParser::parse(Rule::instruction, "LDI table, index").unwrap();
+        LMCParser::parse(Rule::instruction, "LDI table,index ; load").unwrap();
     }
 
     #[test]
@@ -0,0 +1,558 @@
+//! Mnemonics, encodings and runtime behaviour of a LMC variant.
+use std::fmt::{self, Display};
+
+use crate::assembler;
+use crate::ast;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Memory must have at least one cell
+    MemoryEmpty,
+    /// Words must fit an opcode digit alongside every address
+    WordTooNarrow {
+        memory_size: usize,
+        word_digits: u32,
+    },
+    /// Words must leave room for arithmetic on the largest value
+    WordTooWide { word_digits: u32 },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MemoryEmpty => write!(f, "memory must have at least one mailbox"),
+            ConfigError::WordTooNarrow {
+                memory_size,
+                word_digits,
+            } => write!(
+                f,
+                "{} digit words can't hold an opcode and an address of {} mailboxes",
+                word_digits, memory_size
+            ),
+            ConfigError::WordTooWide { word_digits } => write!(
+                f,
+                "{} digit words are too wide, at most {} are supported",
+                word_digits, MAX_WORD_DIGITS
+            ),
+        }
+    }
+}
+
+/// Most digits a word can have, keeping the sum of two words within a `usize`
+pub const MAX_WORD_DIGITS: u32 = 18;
+
+/// Dimensions of the machine an instruction set runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MachineConfig {
+    memory_size: usize,
+    word_digits: u32,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl MachineConfig {
+    pub fn new(memory_size: usize, word_digits: u32) -> Result<Self, ConfigError> {
+        if memory_size == 0 {
+            return Err(ConfigError::MemoryEmpty);
+        }
+        if word_digits > MAX_WORD_DIGITS {
+            return Err(ConfigError::WordTooWide { word_digits });
+        }
+        let config = Self {
+            memory_size,
+            word_digits,
+        };
+        if word_digits <= config.address_digits() {
+            return Err(ConfigError::WordTooNarrow {
+                memory_size,
+                word_digits,
+            });
+        }
+        Ok(config)
+    }
+
+    /// The classic 100 mailboxes holding 3 digit words
+    pub const fn standard() -> Self {
+        Self {
+            memory_size: 100,
+            word_digits: 3,
+        }
+    }
+
+    /// 1000 mailboxes holding 4 digit words
+    pub const fn big() -> Self {
+        Self {
+            memory_size: 1000,
+            word_digits: 4,
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    pub fn word_digits(&self) -> u32 {
+        self.word_digits
+    }
+
+    /// Largest value a single word can hold
+    pub fn max_value(&self) -> usize {
+        10usize.pow(self.word_digits) - 1
+    }
+
+    /// Digits used for the operand, always leaving room for two digit fixed operands
+    fn address_digits(&self) -> u32 {
+        (self.memory_size - 1).checked_ilog10().unwrap_or(0).max(1) + 1
+    }
+
+    fn opcode_base(&self) -> usize {
+        10usize.pow(self.address_digits())
+    }
+
+    /// Split a word into its opcode and operand
+    pub fn split_word(&self, word: usize) -> (usize, usize) {
+        (word / self.opcode_base(), word % self.opcode_base())
+    }
+
+    /// Join an opcode and operand into a word
+    pub fn join_word(&self, opcode: usize, operand: usize) -> usize {
+        opcode * self.opcode_base() + operand
+    }
+}
+
+/// What follows a mnemonic and how it is stored in the operand digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    /// Takes no operand, any operand digits are ignored when decoding
+    None,
+    /// Takes no operand, the operand digits must hold this value
+    Fixed(usize),
+    /// A memory location, either physical or labeled
+    Address,
+    /// A memory location holding the address to use, written with a `@` prefix
+    Indirect,
+    /// A raw value stored as the whole word
+    Value,
+    /// A `table, index` pair, only used by pseudo-instructions expanded before encoding
+    Indexed,
+}
+
+/// Runtime behaviour of an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Add,
+    Subtract,
+    Multiply,
+    Store,
+    Load,
+    BranchAlways,
+    BranchIfZero,
+    BranchIfPositive,
+    Call,
+    Return,
+    LoadIndexed,
+    StoreIndexed,
+    Input,
+    Output,
+    InputCharacter,
+    OutputCharacter,
+    Halt,
+    Data,
+}
+
+/// A single mnemonic in an instruction set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionDefinition {
+    pub mnemonic: &'static str,
+    pub opcode: usize,
+    pub operand: OperandKind,
+    pub operation: Operation,
+}
+
+impl InstructionDefinition {
+    const fn new(
+        mnemonic: &'static str,
+        opcode: usize,
+        operand: OperandKind,
+        operation: Operation,
+    ) -> Self {
+        Self {
+            mnemonic,
+            opcode,
+            operand,
+            operation,
+        }
+    }
+
+    pub fn is_indirect(&self) -> bool {
+        self.operand == OperandKind::Indirect
+    }
+
+    /// Encode into a machine word, using the given operand
+    pub fn encode(&self, operand: usize, config: &MachineConfig) -> usize {
+        match self.operand {
+            OperandKind::None => config.join_word(self.opcode, 0),
+            OperandKind::Fixed(fixed) => config.join_word(self.opcode, fixed),
+            OperandKind::Address | OperandKind::Indirect => config.join_word(self.opcode, operand),
+            OperandKind::Value => operand,
+            OperandKind::Indexed => unreachable!("pseudo-instructions are never encoded"),
+        }
+    }
+}
+
+const STANDARD: [InstructionDefinition; 13] = [
+    InstructionDefinition::new(
+        ast::MNEMONIC_ADD,
+        assembler::OPCODE_ADD,
+        OperandKind::Address,
+        Operation::Add,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_SUB,
+        assembler::OPCODE_SUB,
+        OperandKind::Address,
+        Operation::Subtract,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_STA,
+        assembler::OPCODE_STA,
+        OperandKind::Address,
+        Operation::Store,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_LDA,
+        assembler::OPCODE_LDA,
+        OperandKind::Address,
+        Operation::Load,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_BRA,
+        assembler::OPCODE_BRA,
+        OperandKind::Address,
+        Operation::BranchAlways,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_BRZ,
+        assembler::OPCODE_BRZ,
+        OperandKind::Address,
+        Operation::BranchIfZero,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_BRP,
+        assembler::OPCODE_BRP,
+        OperandKind::Address,
+        Operation::BranchIfPositive,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_INP,
+        assembler::OPCODE_INP,
+        OperandKind::Fixed(1),
+        Operation::Input,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_OUT,
+        assembler::OPCODE_OUT,
+        OperandKind::Fixed(2),
+        Operation::Output,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_HLT,
+        assembler::OPCODE_HLT,
+        OperandKind::None,
+        Operation::Halt,
+    ),
+    InstructionDefinition::new(ast::MNEMONIC_DAT, 0, OperandKind::Value, Operation::Data),
+    InstructionDefinition::new(
+        ast::MNEMONIC_LDI,
+        0,
+        OperandKind::Indexed,
+        Operation::LoadIndexed,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_STI,
+        0,
+        OperandKind::Indexed,
+        Operation::StoreIndexed,
+    ),
+];
+
+const EXTENDED_IO: [InstructionDefinition; 2] = [
+    InstructionDefinition::new(
+        ast::MNEMONIC_INA,
+        assembler::OPCODE_INA,
+        OperandKind::Fixed(21),
+        Operation::InputCharacter,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_OTC,
+        assembler::OPCODE_OTC,
+        OperandKind::Fixed(22),
+        Operation::OutputCharacter,
+    ),
+];
+
+const INDIRECT: [InstructionDefinition; 3] = [
+    InstructionDefinition::new(
+        ast::MNEMONIC_LDA,
+        assembler::OPCODE_LDA_INDIRECT,
+        OperandKind::Indirect,
+        Operation::Load,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_STA,
+        assembler::OPCODE_STA_INDIRECT,
+        OperandKind::Indirect,
+        Operation::Store,
+    ),
+    // shares its opcode with indirect stores, so only `000` halts
+    InstructionDefinition::new(
+        ast::MNEMONIC_HLT,
+        assembler::OPCODE_HLT,
+        OperandKind::Fixed(0),
+        Operation::Halt,
+    ),
+];
+
+const SUBROUTINES: [InstructionDefinition; 2] = [
+    InstructionDefinition::new(
+        ast::MNEMONIC_CAL,
+        assembler::OPCODE_CAL,
+        OperandKind::Address,
+        Operation::Call,
+    ),
+    InstructionDefinition::new(
+        ast::MNEMONIC_RET,
+        assembler::OPCODE_RET,
+        OperandKind::Fixed(10),
+        Operation::Return,
+    ),
+];
+
+/// The mnemonics making up a LMC variant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionSet {
+    definitions: Vec<InstructionDefinition>,
+    config: MachineConfig,
+}
+
+impl Default for InstructionSet {
+    fn default() -> Self {
+        Self::standard()
+    }
+}
+
+impl InstructionSet {
+    pub fn new(definitions: Vec<InstructionDefinition>) -> Self {
+        Self {
+            definitions,
+            config: MachineConfig::standard(),
+        }
+    }
+
+    /// The classic ten instructions, `DAT` and the `LDI`/`STI` pseudo-instructions
+    pub fn standard() -> Self {
+        Self::new(STANDARD.to_vec())
+    }
+
+    /// Add a definition, replacing any existing one with the same mnemonic and addressing
+    pub fn with(mut self, definition: InstructionDefinition) -> Self {
+        self.definitions.retain(|v| {
+            !v.mnemonic.eq_ignore_ascii_case(definition.mnemonic)
+                || v.is_indirect() != definition.is_indirect()
+        });
+        self.definitions.push(definition);
+        self
+    }
+
+    /// Add the character I/O instructions (`INA`, `OTC`)
+    pub fn with_extended_io(self) -> Self {
+        EXTENDED_IO.into_iter().fold(self, Self::with)
+    }
+
+    /// Add indirect loads and stores (`LDA @ptr`, `STA @ptr`)
+    pub fn with_indirect(self) -> Self {
+        INDIRECT.into_iter().fold(self, Self::with)
+    }
+
+    /// Add subroutine calls using a return stack (`CAL label`, `RET`),
+    /// sharing opcode 4 with indirect loads
+    pub fn with_subroutines(self) -> Self {
+        SUBROUTINES.into_iter().fold(self, Self::with)
+    }
+
+    /// Run on a machine with different dimensions
+    pub fn with_config(mut self, config: MachineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
+    }
+
+    /// Encode a definition into a machine word for this machine
+    pub fn encode(&self, definition: &InstructionDefinition, operand: usize) -> usize {
+        definition.encode(operand, &self.config)
+    }
+
+    pub fn definitions(&self) -> &[InstructionDefinition] {
+        &self.definitions
+    }
+
+    /// Find a definition by mnemonic and addressing, ignoring case
+    pub fn by_mnemonic(&self, mnemonic: &str, indirect: bool) -> Option<&InstructionDefinition> {
+        self.definitions
+            .iter()
+            .find(|v| v.mnemonic.eq_ignore_ascii_case(mnemonic) && v.is_indirect() == indirect)
+    }
+
+    /// Find the first definition with the given behaviour and addressing
+    pub fn by_operation(
+        &self,
+        operation: Operation,
+        indirect: bool,
+    ) -> Option<&InstructionDefinition> {
+        self.definitions
+            .iter()
+            .find(|v| v.operation == operation && v.is_indirect() == indirect)
+    }
+
+    /// Find the definition a machine word executes as, along with its operand
+    pub fn decode(&self, word: usize) -> Option<(&InstructionDefinition, usize)> {
+        let (opcode, operand) = self.config.split_word(word);
+        let candidates = || self.definitions.iter().filter(move |v| v.opcode == opcode);
+        candidates()
+            .find(|v| v.operand == OperandKind::Fixed(operand))
+            .or_else(|| candidates().find(|v| v.operand == OperandKind::Address))
+            .or_else(|| candidates().find(|v| v.operand == OperandKind::Indirect))
+            .or_else(|| candidates().find(|v| v.operand == OperandKind::None))
+            .map(|v| (v, operand))
+    }
+
+    /// Render a machine word as source, if it is a valid instruction
+    pub fn disassemble(&self, word: usize) -> Option<String> {
+        self.decode(word)
+            .map(|(definition, operand)| match definition.operand {
+                OperandKind::Address => format!("{} {}", definition.mnemonic, operand),
+                OperandKind::Indirect => format!("{} @{}", definition.mnemonic, operand),
+                _ => definition.mnemonic.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConfigError, InstructionDefinition, InstructionSet, MachineConfig, OperandKind, Operation,
+    };
+
+    #[test]
+    fn test_decode_standard() {
+        let instruction_set = InstructionSet::standard();
+        let decoded = |word| {
+            instruction_set
+                .decode(word)
+                .map(|(definition, operand)| (definition.operation, operand))
+        };
+        assert_eq!(decoded(105), Some((Operation::Add, 5)));
+        assert_eq!(decoded(901), Some((Operation::Input, 1)));
+        assert_eq!(decoded(902), Some((Operation::Output, 2)));
+        assert_eq!(decoded(0), Some((Operation::Halt, 0)));
+        assert_eq!(decoded(922), None);
+        assert_eq!(decoded(450), None);
+    }
+
+    #[test]
+    fn test_extended_io() {
+        let instruction_set = InstructionSet::standard().with_extended_io();
+        assert_eq!(instruction_set.disassemble(921), Some("INA".to_string()));
+        assert_eq!(instruction_set.disassemble(922), Some("OTC".to_string()));
+        assert_eq!(instruction_set.disassemble(901), Some("INP".to_string()));
+    }
+
+    #[test]
+    fn test_custom_definition() {
+        let instruction_set = InstructionSet::standard().with(InstructionDefinition {
+            mnemonic: "mul",
+            opcode: 4,
+            operand: OperandKind::Address,
+            operation: Operation::Multiply,
+        });
+        let definition = instruction_set.by_mnemonic("MUL", false).unwrap();
+        assert_eq!(instruction_set.encode(definition, 42), 442);
+        assert_eq!(instruction_set.disassemble(442), Some("mul 42".to_string()));
+    }
+
+    #[test]
+    fn test_replace_definition() {
+        let instruction_set = InstructionSet::standard().with(InstructionDefinition {
+            mnemonic: "OUT",
+            opcode: 9,
+            operand: OperandKind::Fixed(3),
+            operation: Operation::Output,
+        });
+        assert_eq!(instruction_set.definitions().len(), 13);
+        assert_eq!(instruction_set.disassemble(903), Some("OUT".to_string()));
+        assert_eq!(instruction_set.decode(902), None);
+    }
+
+    #[test]
+    fn test_big_config() {
+        let instruction_set = InstructionSet::standard().with_config(MachineConfig::big());
+        let definition = instruction_set.by_mnemonic("LDA", false).unwrap();
+        assert_eq!(instruction_set.encode(definition, 999), 5999);
+        assert_eq!(
+            instruction_set.disassemble(5999),
+            Some("LDA 999".to_string())
+        );
+        assert_eq!(instruction_set.disassemble(9001), Some("INP".to_string()));
+        assert_eq!(instruction_set.disassemble(901), Some("HLT".to_string()));
+        assert_eq!(instruction_set.config().max_value(), 9999);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert_eq!(MachineConfig::new(100, 3), Ok(MachineConfig::standard()));
+        assert_eq!(MachineConfig::new(1000, 4), Ok(MachineConfig::big()));
+        assert!(MachineConfig::new(10, 3).is_ok());
+        assert_eq!(MachineConfig::new(0, 3), Err(ConfigError::MemoryEmpty));
+        assert_eq!(
+            MachineConfig::new(1000, 3),
+            Err(ConfigError::WordTooNarrow {
+                memory_size: 1000,
+                word_digits: 3
+            })
+        );
+        assert!(MachineConfig::new(100, 18).is_ok());
+        assert_eq!(
+            MachineConfig::new(100, 19),
+            Err(ConfigError::WordTooWide { word_digits: 19 })
+        );
+    }
+
+    #[test]
+    fn test_indirect() {
+        let instruction_set = InstructionSet::standard().with_indirect();
+        assert_eq!(
+            instruction_set.disassemble(412),
+            Some("LDA @12".to_string())
+        );
+        assert_eq!(instruction_set.disassemble(12), Some("STA @12".to_string()));
+        assert_eq!(instruction_set.disassemble(512), Some("LDA 12".to_string()));
+        assert_eq!(instruction_set.disassemble(0), Some("HLT".to_string()));
+        assert!(InstructionSet::standard()
+            .by_mnemonic("LDA", true)
+            .is_none());
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let instruction_set = InstructionSet::standard().with_subroutines();
+        assert_eq!(instruction_set.disassemble(412), Some("CAL 12".to_string()));
+        assert_eq!(instruction_set.disassemble(910), Some("RET".to_string()));
+    }
+}
@@ -1,4 +1,20 @@
 pub mod assembler;
 pub mod ast;
+pub mod compiler;
+pub mod coverage;
+pub mod equivalence;
+pub mod expand;
+pub mod fuzz;
+pub mod grading;
 pub mod grammar;
+pub mod instruction_set;
+pub mod library;
+pub mod listing;
+pub mod optimise;
+pub mod profile;
 pub mod runtime;
+pub mod solver;
+pub mod stats;
+pub mod superopt;
+pub mod symbolic;
+pub mod testing;
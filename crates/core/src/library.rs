@@ -0,0 +1,292 @@
+//! Helper routines for what LMC has no instruction for, linked into
+//! programs which reference them.
+//!
+//! Callers put the arguments in [`LHS`] and [`RHS`], store a `BRA` back to
+//! themselves in the routine's [`exit_label`] cell and branch to the routine,
+//! which returns with the result in the accumulator. The arguments are
+//! overwritten, routines never call each other so none of them nest.
+//!
+//! ```text
+//!         LDA a
+//!         STA liblhs
+//!         LDA b
+//!         STA librhs
+//!         LDA ret
+//!         STA libmulexit
+//!         BRA libmul
+//! back:   OUT
+//!         HLT
+//! ret:    BRA back
+//! ```
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::OnceLock;
+
+use crate::ast::{
+    try_parsed_to_ast, Instruction, InstructionType, MemoryLocation, Statement,
+    GENERATED_LABEL_PREFIX,
+};
+use crate::grammar::pass_program;
+use crate::instruction_set::InstructionSet;
+
+/// `LHS * RHS`, saturating
+pub const MULTIPLY: &str = "libmul";
+/// `LHS / RHS`, or zero when dividing by zero
+pub const DIVIDE: &str = "libdiv";
+/// `LHS % RHS`, or zero when dividing by zero
+pub const MODULO: &str = "libmod";
+/// 0 when `LHS < RHS`, 1 when they're equal and 2 when `LHS > RHS`
+pub const COMPARE: &str = "libcmp";
+/// The ten's complement of `LHS`, setting the negative flag unless it's zero
+pub const NEGATE: &str = "libneg";
+pub const LHS: &str = "liblhs";
+pub const RHS: &str = "librhs";
+
+const ROUTINES: [&str; 5] = [MULTIPLY, DIVIDE, MODULO, COMPARE, NEGATE];
+
+/// Whether `label` names a routine, its exit cell or an argument,
+/// the labels programs use to call the library
+pub fn is_library_label(label: &str) -> bool {
+    label == LHS
+        || label == RHS
+        || ROUTINES
+            .iter()
+            .any(|v| label == *v || label.strip_prefix(v) == Some("exit"))
+}
+
+/// Label of the cell a caller of `routine` stores its return branch in
+pub fn exit_label(routine: &str) -> String {
+    format!("{}exit", routine)
+}
+
+/// Each fragment is linked as a whole when any label it defines is referenced.
+/// Labels other than the library's own are prefixed with [`GENERATED_LABEL_PREFIX`]
+/// when linked, apart from the constants which are shared with the compiler's pool.
+const FRAGMENTS: &[&str] = &[
+    "libmul:  LDA zero
+              STA product
+     mulloop: LDA librhs
+              BRZ muldone
+              SUB one
+              STA librhs
+              LDA product
+              ADD liblhs
+              STA product
+              BRA mulloop
+     muldone: LDA product
+  libmulexit: BRA 0
+     product: DAT 0",
+    "libdiv:  LDA zero
+              STA quotient
+              LDA librhs
+              BRZ libdivexit
+     divloop: LDA liblhs
+              SUB librhs
+              BRP divnext
+              LDA quotient
+  libdivexit: BRA 0
+     divnext: STA liblhs
+              LDA quotient
+              ADD one
+              STA quotient
+              BRA divloop
+    quotient: DAT 0",
+    "libmod:  LDA librhs
+              BRZ libmodexit
+     modloop: LDA liblhs
+              SUB librhs
+              BRP modnext
+              LDA liblhs
+  libmodexit: BRA 0
+     modnext: STA liblhs
+              BRA modloop",
+    "libcmp:  LDA liblhs
+              SUB librhs
+              BRZ cmpequal
+              BRP cmpgreater
+              LDA zero
+  libcmpexit: BRA 0
+    cmpequal: LDA one
+              BRA libcmpexit
+  cmpgreater: LDA two
+              BRA libcmpexit",
+    "libneg:  LDA zero
+              SUB liblhs
+  libnegexit: BRA 0",
+    "liblhs: DAT 0",
+    "librhs: DAT 0",
+    "zero: DAT 0",
+    "one: DAT 1",
+    "two: DAT 2",
+];
+
+fn link_name(label: &str) -> String {
+    match label {
+        "zero" => format!("{}const0", GENERATED_LABEL_PREFIX),
+        "one" => format!("{}const1", GENERATED_LABEL_PREFIX),
+        "two" => format!("{}const2", GENERATED_LABEL_PREFIX),
+        label if is_library_label(label) => label.to_string(),
+        label => format!("{}{}", GENERATED_LABEL_PREFIX, label),
+    }
+}
+
+/// Name every label as it's linked, moving statements to `line`
+fn rename<'a>(ast: Vec<Statement<'a>>, line: usize) -> Vec<Statement<'a>> {
+    ast.into_iter()
+        .map(|stmt| {
+            let (label, instruction) = match stmt {
+                Statement::Labeled { label, instruction } => (Some(label), instruction),
+                Statement::UnLabeled { instruction } => (None, instruction),
+            };
+            let memory_location = instruction.instruction.memory_location().map(|v| match v {
+                MemoryLocation::Label(label) => MemoryLocation::Label(Cow::Owned(link_name(label))),
+                v => v.clone(),
+            });
+            let instruction = Instruction {
+                instruction: match memory_location {
+                    Some(v) => {
+                        InstructionType::from_operation(instruction.instruction.operation(), v, 0)
+                            .expect("library routines don't use indexed instructions")
+                    }
+                    None => instruction.instruction,
+                },
+                comments: instruction.comments,
+                end_comment: instruction.end_comment,
+                line,
+            };
+            match label {
+                Some(mut label) => {
+                    label.label = Cow::Owned(link_name(&label.label));
+                    Statement::Labeled { label, instruction }
+                }
+                None => Statement::UnLabeled { instruction },
+            }
+        })
+        .collect()
+}
+
+/// A parsed fragment, with the labels it defines once linked
+struct Fragment {
+    labels: BTreeSet<String>,
+    ast: Vec<Statement<'static>>,
+}
+
+/// Every fragment, parsed the first time anything is linked
+fn fragments() -> &'static [Fragment] {
+    static FRAGMENT_ASTS: OnceLock<Vec<Fragment>> = OnceLock::new();
+    FRAGMENT_ASTS.get_or_init(|| {
+        FRAGMENTS
+            .iter()
+            .map(|source| {
+                let mut parsed = pass_program(source).expect("library fragments parse");
+                let ast = try_parsed_to_ast(&mut parsed, &InstructionSet::standard())
+                    .expect("library fragments use standard instructions");
+                Fragment {
+                    labels: defined_labels(&rename(ast.clone(), 0)),
+                    ast,
+                }
+            })
+            .collect()
+    })
+}
+
+fn defined_labels(ast: &[Statement]) -> BTreeSet<String> {
+    ast.iter()
+        .filter_map(|v| match v {
+            Statement::Labeled { label, .. } => Some(label.label.to_string()),
+            Statement::UnLabeled { .. } => None,
+        })
+        .collect()
+}
+
+/// Append the library fragments defining labels `ast` references but doesn't define,
+/// and then any they need in turn. Linked statements take the line of their first use.
+pub fn link<'a>(mut ast: Vec<Statement<'a>>) -> Vec<Statement<'a>> {
+    let mut linked = BTreeSet::new();
+    loop {
+        let defined = defined_labels(&ast);
+        let mut undefined = BTreeMap::new();
+        for stmt in &ast {
+            let instruction: &Instruction = stmt.into();
+            if let Some(MemoryLocation::Label(label)) = instruction.instruction.memory_location() {
+                if !defined.contains(label.as_ref()) {
+                    undefined
+                        .entry(label.to_string())
+                        .or_insert(instruction.line);
+                }
+            }
+        }
+        let needed: Vec<_> = fragments()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !linked.contains(index))
+            .filter_map(|(index, fragment)| {
+                let line = fragment
+                    .labels
+                    .iter()
+                    .find_map(|v| undefined.get(v).copied())?;
+                Some((index, rename(fragment.ast.clone(), line)))
+            })
+            .collect();
+        if needed.is_empty() {
+            return ast;
+        }
+        for (index, statements) in needed {
+            linked.insert(index);
+            ast.extend(statements);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::expand::expand_pseudo_instructions;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::{Headless, Runtime};
+
+    use super::is_library_label;
+
+    /// Call `routine` from LMC code, returning the result and the program size
+    fn call(routine: &str, lhs: usize, rhs: usize) -> (usize, usize) {
+        let source = format!(
+            "LDA a\nSTA liblhs\nLDA b\nSTA librhs\nLDA ret\nSTA lib{routine}exit\n\
+             BRA lib{routine}\nback: OUT\nHLT\na: DAT {lhs}\nb: DAT {rhs}\nret: BRA back\n"
+        );
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(&source).unwrap();
+        let ast = expand_pseudo_instructions(parsed_to_ast(&mut parsed, &instruction_set));
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let mut memory = assembly.memory.clone();
+        let mut runtime = Headless::load_assembled(&mut memory, &instruction_set);
+        runtime.run().unwrap();
+        (runtime.outputs()[0].value(), assembly.program_size())
+    }
+
+    #[test]
+    fn test_routines() {
+        let result = |routine, lhs, rhs| call(routine, lhs, rhs).0;
+        assert_eq!(result("mul", 12, 11), 132);
+        assert_eq!(result("mul", 100, 20), 999);
+        assert_eq!(result("div", 100, 7), 14);
+        assert_eq!(result("div", 5, 0), 0);
+        assert_eq!(result("mod", 100, 7), 2);
+        assert_eq!(result("mod", 6, 3), 0);
+        assert_eq!(result("cmp", 3, 4), 0);
+        assert_eq!(result("cmp", 4, 4), 1);
+        assert_eq!(result("cmp", 5, 4), 2);
+        assert_eq!(result("neg", 1, 0), 999);
+        assert_eq!(result("neg", 0, 0), 0);
+    }
+
+    #[test]
+    fn test_links_only_what_is_used() {
+        // the 12 caller mailboxes, NEG, LHS, RHS and the zero constant
+        assert_eq!(call("neg", 1, 0).1, 12 + 3 + 3);
+        assert_eq!(call("mod", 1, 1).1, 12 + 9 + 2);
+        assert!(is_library_label("libcmpexit"));
+        assert!(!is_library_label("library"));
+    }
+}
@@ -0,0 +1,153 @@
+//! Classic assembler listings.
+use crate::assembler::{Assembly, SymbolKind};
+use crate::ast::{Instruction, InstructionType, MemoryLocation, Statement};
+use crate::instruction_set::InstructionSet;
+
+fn resolve(assembly: &Assembly, memory_location: &MemoryLocation) -> Option<usize> {
+    match memory_location {
+        MemoryLocation::Address(addr) => Some(*addr),
+        MemoryLocation::Label(label) => assembly.address_of(label),
+        MemoryLocation::Indirect(memory_location) => resolve(assembly, memory_location),
+    }
+}
+
+/// Render every assembled cell with its address, machine word, label, mnemonic,
+/// operand, resolved address and comments, followed by the symbol table
+/// sorted by name and by address.
+pub fn listing(ast: &[Statement], assembly: &Assembly, instruction_set: &InstructionSet) -> String {
+    let config = instruction_set.config();
+    let addr_width = (config.memory_size() - 1).to_string().len();
+    let word_width = config.word_digits() as usize;
+    let label_width = assembly
+        .symbols
+        .keys()
+        .map(|v| v.len())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    let operand_width = ast
+        .iter()
+        .map(|v| match &<&Instruction>::from(v).instruction {
+            InstructionType::Data(value) => value.to_string().len(),
+            v => v.memory_location().map_or(0, |v| v.to_string().len()),
+        })
+        .max()
+        .unwrap_or(0)
+        .max(7);
+
+    let mut listing = format!(
+        "{:<addr_width$} {:<word_width$} {:<label_width$} {:<8} {:<operand_width$} {:<8} COMMENT\n",
+        "ADDR",
+        "WORD",
+        "LABEL",
+        "MNEMONIC",
+        "OPERAND",
+        "RESOLVED",
+        addr_width = addr_width.max(4),
+        word_width = word_width.max(4),
+    );
+    for (addr, (stmt, word)) in ast.iter().zip(&assembly.memory).enumerate() {
+        let instruction: &Instruction = stmt.into();
+        let (label, mut comments) = match stmt {
+            Statement::Labeled { label, .. } => (label.label.as_ref(), label.comments.to_vec()),
+            Statement::UnLabeled { .. } => ("", vec![]),
+        };
+        comments.extend(instruction.comments.iter().chain(&instruction.end_comment));
+        let operation = instruction.instruction.operation();
+        let memory_location = instruction.instruction.memory_location();
+        let indirect = matches!(memory_location, Some(MemoryLocation::Indirect(_)));
+        let mnemonic = instruction_set
+            .by_operation(operation, indirect)
+            .map_or("???", |v| v.mnemonic);
+        let (operand, resolved) = match &instruction.instruction {
+            InstructionType::Data(value) => (value.to_string(), String::new()),
+            _ => match memory_location {
+                Some(v) => (
+                    v.to_string(),
+                    resolve(assembly, v)
+                        .map(|v| format!("{:0addr_width$}", v))
+                        .unwrap_or_default(),
+                ),
+                None => (String::new(), String::new()),
+            },
+        };
+        let line = format!(
+            "{:0addr_width$}{:pad_addr$} {:0word_width$}{:pad_word$} {:<label_width$} {:<8} {:<operand_width$} {:<8} {}",
+            addr,
+            "",
+            word,
+            "",
+            label,
+            mnemonic,
+            operand,
+            resolved,
+            comments.join("; "),
+            pad_addr = 4usize.saturating_sub(addr_width),
+            pad_word = 4usize.saturating_sub(word_width),
+        );
+        listing.push_str(line.trim_end());
+        listing.push('\n');
+    }
+
+    let kind = |kind: SymbolKind| match kind {
+        SymbolKind::Code => "code",
+        SymbolKind::Data => "data",
+    };
+    listing.push_str("\nSYMBOLS BY NAME\n");
+    for (name, symbol) in &assembly.symbols {
+        listing.push_str(&format!(
+            "{:<label_width$} {:0addr_width$} {}\n",
+            name,
+            symbol.address,
+            kind(symbol.kind)
+        ));
+    }
+    listing.push_str("\nSYMBOLS BY ADDRESS\n");
+    let mut symbols: Vec<_> = assembly.symbols.iter().collect();
+    symbols.sort_by_key(|(name, symbol)| (symbol.address, *name));
+    for (name, symbol) in symbols {
+        listing.push_str(&format!(
+            "{:0addr_width$} {:<label_width$} {}\n",
+            symbol.address,
+            name,
+            kind(symbol.kind)
+        ));
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+
+    use super::listing;
+
+    #[test]
+    fn test_listing() {
+        let instruction_set = InstructionSet::standard();
+        let source = "start: INP ; read\nSTA value\nBRA start\n; the value\nvalue: DAT 7\n";
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        assert_eq!(
+            listing(&ast, &assembly, &instruction_set),
+            "ADDR WORD LABEL MNEMONIC OPERAND RESOLVED COMMENT
+00   901  start INP                       read
+01   303        STA      value   03
+02   600        BRA      start   00
+03   007  value DAT      7                the value
+
+SYMBOLS BY NAME
+start 00 code
+value 03 data
+
+SYMBOLS BY ADDRESS
+00 start code
+03 value data
+"
+        );
+    }
+}
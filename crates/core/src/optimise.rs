@@ -0,0 +1,607 @@
+//! Peephole optimisation of LMC statements.
+//!
+//! Statements only move or go away when nothing can tell: labels move onto the
+//! next statement when theirs is removed, numeric addresses follow the statement
+//! they pointed at and cells used as data are never changed. Programs using
+//! indirect addressing could hold addresses as plain numbers, so only have
+//! their branches retargeted.
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+
+use crate::ast::{Instruction, InstructionType, Label, MemoryLocation, Statement};
+use crate::instruction_set::Operation;
+
+/// What the optimiser changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Mailboxes used before and after
+    pub before: usize,
+    pub after: usize,
+    /// Loads and stores of a value the accumulator already held
+    pub redundant_loads: usize,
+    /// Branches pointed past a chain of `BRA`s
+    pub branches_retargeted: usize,
+    /// Branches to the instruction after them
+    pub branches_removed: usize,
+    /// Unreachable instructions after `BRA`, `HLT` or `RET`
+    pub dead_instructions: usize,
+    /// `DAT` cells merged into an identical one
+    pub constants_merged: usize,
+}
+
+impl Report {
+    pub fn saved(&self) -> usize {
+        self.before - self.after
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "redundant loads removed: {}", self.redundant_loads)?;
+        writeln!(
+            f,
+            "branches retargeted: {}, removed: {}",
+            self.branches_retargeted, self.branches_removed
+        )?;
+        writeln!(f, "dead instructions removed: {}", self.dead_instructions)?;
+        writeln!(f, "constants merged: {}", self.constants_merged)?;
+        write!(
+            f,
+            "mailboxes: {} -> {} ({} saved)",
+            self.before,
+            self.after,
+            self.saved()
+        )
+    }
+}
+
+/// Labels standing in for numeric addresses while statements move
+const PIN_PREFIX: &str = "__pin";
+
+fn label_of<'b>(stmt: &'b Statement) -> Option<&'b str> {
+    match stmt {
+        Statement::Labeled { label, .. } => Some(&label.label),
+        Statement::UnLabeled { .. } => None,
+    }
+}
+
+fn instruction<'b, 'a>(stmt: &'b Statement<'a>) -> &'b InstructionType<'a> {
+    &<&Instruction>::from(stmt).instruction
+}
+
+fn instruction_mut<'b, 'a>(stmt: &'b mut Statement<'a>) -> &'b mut InstructionType<'a> {
+    match stmt {
+        Statement::Labeled { instruction, .. } | Statement::UnLabeled { instruction } => {
+            &mut instruction.instruction
+        }
+    }
+}
+
+fn is_branch(operation: Operation) -> bool {
+    matches!(
+        operation,
+        Operation::BranchAlways | Operation::BranchIfZero | Operation::BranchIfPositive
+    )
+}
+
+/// Every memory location an instruction names, including both of a pseudo-instruction
+fn locations_mut<'b, 'a>(
+    instruction: &'b mut InstructionType<'a>,
+) -> Vec<&'b mut MemoryLocation<'a>> {
+    match instruction {
+        InstructionType::LoadIndexed(table, index)
+        | InstructionType::StoreIndexed(table, index) => {
+            vec![table, index]
+        }
+        InstructionType::Add(v)
+        | InstructionType::Subtract(v)
+        | InstructionType::Multiply(v)
+        | InstructionType::Store(v)
+        | InstructionType::Load(v)
+        | InstructionType::BranchAlways(v)
+        | InstructionType::BranchIfZero(v)
+        | InstructionType::BranchIfPositive(v)
+        | InstructionType::Call(v) => vec![v],
+        _ => vec![],
+    }
+}
+
+fn locations<'b, 'a>(instruction: &'b InstructionType<'a>) -> Vec<&'b MemoryLocation<'a>> {
+    match instruction {
+        InstructionType::LoadIndexed(table, index)
+        | InstructionType::StoreIndexed(table, index) => {
+            vec![table, index]
+        }
+        v => v.memory_location().into_iter().collect(),
+    }
+}
+
+/// How each label is used
+#[derive(Debug, Default)]
+struct References {
+    /// Labels anything refers to
+    any: BTreeSet<String>,
+    /// Labels only ever read as a value by `LDA`, `ADD` and `SUB`
+    only_read: BTreeSet<String>,
+    /// Labels only ever branched or called to
+    only_branched: BTreeSet<String>,
+}
+
+impl References {
+    fn new(ast: &[Statement]) -> Self {
+        let mut uses: BTreeMap<String, BTreeSet<&'static str>> = BTreeMap::new();
+        for stmt in ast {
+            let instruction = instruction(stmt);
+            let operation = instruction.operation();
+            for location in locations(instruction) {
+                let (label, direct) = match location {
+                    MemoryLocation::Label(label) => (label, true),
+                    MemoryLocation::Indirect(v) => match v.as_ref() {
+                        MemoryLocation::Label(label) => (label, false),
+                        _ => continue,
+                    },
+                    MemoryLocation::Address(_) => continue,
+                };
+                let kind = match operation {
+                    Operation::Load | Operation::Add | Operation::Subtract if direct => "read",
+                    operation if is_branch(operation) || operation == Operation::Call => "branch",
+                    _ => "other",
+                };
+                uses.entry(label.to_string()).or_default().insert(kind);
+            }
+        }
+        let only = |kind: &str| {
+            uses.iter()
+                .filter(|(_, v)| v.len() == 1 && v.contains(kind))
+                .map(|(k, _)| k.clone())
+                .collect()
+        };
+        Self {
+            any: uses.keys().cloned().collect(),
+            only_read: only("read"),
+            only_branched: only("branch"),
+        }
+    }
+
+    /// Whether the statement can be changed or removed without a reader or writer noticing
+    fn is_code(&self, stmt: &Statement) -> bool {
+        label_of(stmt).is_none_or(|v| !self.any.contains(v) || self.only_branched.contains(v))
+    }
+}
+
+fn rename_references(ast: &mut [Statement], from: &str, to: &str) {
+    for stmt in ast.iter_mut() {
+        for location in locations_mut(instruction_mut(stmt)) {
+            let location = match location {
+                MemoryLocation::Indirect(v) => v.as_mut(),
+                v => v,
+            };
+            if matches!(location, MemoryLocation::Label(v) if v == from) {
+                *location = MemoryLocation::Label(Cow::Owned(to.to_string()));
+            }
+        }
+    }
+}
+
+/// Remove the statement at `index`, moving its comments and a label still referred to
+/// onto the next one
+fn remove(ast: &mut Vec<Statement>, index: usize, references: &References) {
+    let removed = ast.remove(index);
+    if let Some(next) = ast.get_mut(index) {
+        let removed_instruction: &Instruction = (&removed).into();
+        let comments: Vec<&str> = removed_instruction
+            .comments
+            .iter()
+            .copied()
+            .chain(removed_instruction.end_comment)
+            .collect();
+        if !comments.is_empty() {
+            let (Statement::Labeled { instruction, .. } | Statement::UnLabeled { instruction }) =
+                next;
+            instruction.comments = comments
+                .iter()
+                .chain(&*instruction.comments)
+                .copied()
+                .collect();
+        }
+    }
+    let Statement::Labeled { label, .. } = removed else {
+        return;
+    };
+    if !references.any.contains(label.label.as_ref()) {
+        return;
+    }
+    let next = ast
+        .get_mut(index)
+        .expect("referenced labels are never removed from the end");
+    let name = label.label.to_string();
+    match next {
+        Statement::Labeled { label: next, .. } => {
+            let next = next.label.to_string();
+            rename_references(ast, &name, &next);
+        }
+        Statement::UnLabeled { instruction } => {
+            let instruction = instruction.clone();
+            *next = Statement::Labeled { label, instruction };
+        }
+    }
+}
+
+/// Whether the negative flag is always set again before `BRP` reads it,
+/// following execution on from `index`
+fn flag_dead_from(ast: &[Statement], index: usize) -> bool {
+    for stmt in &ast[index..] {
+        match instruction(stmt).operation() {
+            Operation::Load
+            | Operation::Add
+            | Operation::Subtract
+            | Operation::Multiply
+            | Operation::Input
+            | Operation::InputCharacter
+            | Operation::Halt => return true,
+            Operation::Store | Operation::Output | Operation::OutputCharacter => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Replace numeric operands addressing statements with labels, so they follow them
+fn pin_addresses(ast: &mut [Statement]) {
+    let mut pins = BTreeSet::new();
+    let len = ast.len();
+    for stmt in ast.iter_mut() {
+        for location in locations_mut(instruction_mut(stmt)) {
+            let location = match location {
+                MemoryLocation::Indirect(v) => v.as_mut(),
+                v => v,
+            };
+            if let MemoryLocation::Address(address) = location {
+                if *address < len {
+                    pins.insert(*address);
+                    *location =
+                        MemoryLocation::Label(Cow::Owned(format!("{}{}", PIN_PREFIX, address)));
+                }
+            }
+        }
+    }
+    for address in pins {
+        let name = format!("{}{}", PIN_PREFIX, address);
+        match &mut ast[address] {
+            Statement::Labeled { label, .. } => {
+                let label = label.label.to_string();
+                rename_references(ast, &name, &label);
+            }
+            stmt @ Statement::UnLabeled { .. } => {
+                let Statement::UnLabeled { instruction } = stmt.clone() else {
+                    unreachable!()
+                };
+                *stmt = Statement::Labeled {
+                    label: Label {
+                        label: Cow::Owned(name),
+                        comments: Box::new([]),
+                    },
+                    instruction,
+                };
+            }
+        }
+    }
+}
+
+/// Turn the labels from [`pin_addresses`] back into the addresses they ended up at
+fn unpin_addresses(ast: &mut [Statement]) {
+    let pins: BTreeMap<String, usize> = ast
+        .iter()
+        .enumerate()
+        .filter_map(|(index, v)| label_of(v).map(|v| (v.to_string(), index)))
+        .filter(|(v, _)| v.starts_with(PIN_PREFIX))
+        .collect();
+    for stmt in ast.iter_mut() {
+        for location in locations_mut(instruction_mut(stmt)) {
+            let location = match location {
+                MemoryLocation::Indirect(v) => v.as_mut(),
+                v => v,
+            };
+            if let MemoryLocation::Label(label) = location {
+                if let Some(address) = pins.get(label.as_ref()) {
+                    *location = MemoryLocation::Address(*address);
+                }
+            }
+        }
+        if label_of(stmt).is_some_and(|v| v.starts_with(PIN_PREFIX)) {
+            let Statement::Labeled { instruction, .. } = stmt.clone() else {
+                unreachable!()
+            };
+            *stmt = Statement::UnLabeled { instruction };
+        }
+    }
+}
+
+fn redundant_loads(ast: &mut Vec<Statement>, references: &References) -> usize {
+    for index in 1..ast.len() {
+        let (previous, current) = (&ast[index - 1], &ast[index]);
+        let redundant = match (instruction(previous), instruction(current)) {
+            // the accumulator is overwritten straight away
+            (InstructionType::Load(_), InstructionType::Load(_)) => {
+                references.is_code(previous).then_some(index - 1)
+            }
+            // storing what was just loaded from the same place
+            (InstructionType::Load(a), InstructionType::Store(b)) if a == b => {
+                label_of(current).is_none().then_some(index)
+            }
+            // loading what was just stored, as long as nothing needs the flag cleared
+            (InstructionType::Store(a), InstructionType::Load(b))
+                if a == b && !matches!(a, MemoryLocation::Indirect(_)) =>
+            {
+                (label_of(current).is_none() && flag_dead_from(ast, index + 1)).then_some(index)
+            }
+            _ => None,
+        };
+        if let Some(index) = redundant {
+            remove(ast, index, references);
+            return 1;
+        }
+    }
+    0
+}
+
+fn branch_target(ast: &[Statement], label: &str) -> Option<usize> {
+    ast.iter().position(|v| label_of(v) == Some(label))
+}
+
+fn retarget_branches(ast: &mut [Statement], references: &References) -> usize {
+    let mut retargeted = 0;
+    for index in 0..ast.len() {
+        if !is_branch(instruction(&ast[index]).operation()) || !references.is_code(&ast[index]) {
+            continue;
+        }
+        let Some(MemoryLocation::Label(start)) = instruction(&ast[index]).memory_location() else {
+            continue;
+        };
+        let mut target = start.to_string();
+        let mut visited = BTreeSet::from([target.clone()]);
+        while let Some(next) = branch_target(ast, &target)
+            .filter(|v| references.is_code(&ast[*v]))
+            .and_then(|v| match instruction(&ast[v]) {
+                InstructionType::BranchAlways(MemoryLocation::Label(next)) => {
+                    Some(next.to_string())
+                }
+                _ => None,
+            })
+        {
+            // a loop of branches never gets anywhere, so is left alone
+            if !visited.insert(next.clone()) {
+                target = start.to_string();
+                break;
+            }
+            target = next;
+        }
+        if target != start.as_ref() {
+            let location = MemoryLocation::Label(Cow::Owned(target));
+            for v in locations_mut(instruction_mut(&mut ast[index])) {
+                *v = location.clone();
+            }
+            retargeted += 1;
+        }
+    }
+    retargeted
+}
+
+fn branches_to_next(ast: &mut Vec<Statement>, references: &References) -> usize {
+    for index in 0..ast.len().saturating_sub(1) {
+        let stmt = &ast[index];
+        let to_next = match instruction(stmt).memory_location() {
+            Some(MemoryLocation::Label(label)) => label_of(&ast[index + 1]) == Some(label),
+            _ => false,
+        };
+        if is_branch(instruction(stmt).operation()) && to_next && references.is_code(stmt) {
+            remove(ast, index, references);
+            return 1;
+        }
+    }
+    0
+}
+
+fn dead_code(ast: &mut Vec<Statement>, references: &References) -> usize {
+    for index in 1..ast.len() {
+        let previous = instruction(&ast[index - 1]).operation();
+        let current = &ast[index];
+        let unconditional = matches!(
+            previous,
+            Operation::BranchAlways | Operation::Halt | Operation::Return
+        );
+        let referenced = label_of(current).is_some_and(|v| references.any.contains(v));
+        if unconditional && !referenced && instruction(current).operation() != Operation::Data {
+            remove(ast, index, references);
+            return 1;
+        }
+    }
+    0
+}
+
+fn merge_constants(ast: &mut Vec<Statement>, references: &References) -> usize {
+    let mut first: BTreeMap<usize, String> = BTreeMap::new();
+    for index in 0..ast.len() {
+        let InstructionType::Data(value) = instruction(&ast[index]) else {
+            continue;
+        };
+        let Some(label) = label_of(&ast[index]) else {
+            continue;
+        };
+        // a cell followed by unlabeled ones may be the start of a table
+        let table = ast.get(index + 1).is_some_and(|v| {
+            label_of(v).is_none() && instruction(v).operation() == Operation::Data
+        });
+        // only cells execution can't reach, after code that never falls through
+        let unreachable = ast[..index]
+            .iter()
+            .rev()
+            .find(|v| instruction(v).operation() != Operation::Data)
+            .is_none_or(|v| {
+                matches!(
+                    instruction(v).operation(),
+                    Operation::BranchAlways | Operation::Halt | Operation::Return
+                )
+            });
+        if table || !unreachable || !references.only_read.contains(label) {
+            continue;
+        }
+        match first.get(value) {
+            Some(kept) => {
+                let (label, kept) = (label.to_string(), kept.clone());
+                rename_references(ast, &label, &kept);
+                ast.remove(index);
+                return 1;
+            }
+            None => {
+                first.insert(*value, label.to_string());
+            }
+        }
+    }
+    0
+}
+
+/// Apply every optimisation until none of them changes anything
+pub fn optimise(mut ast: Vec<Statement<'_>>) -> (Vec<Statement<'_>>, Report) {
+    let mut report = Report {
+        before: ast.len(),
+        ..Default::default()
+    };
+    let indirect = ast.iter().any(|v| {
+        locations(instruction(v))
+            .iter()
+            .any(|v| matches!(v, MemoryLocation::Indirect(_)))
+    });
+    pin_addresses(&mut ast);
+    loop {
+        let references = References::new(&ast);
+        let retargeted = retarget_branches(&mut ast, &references);
+        report.branches_retargeted += retargeted;
+        if retargeted > 0 {
+            continue;
+        }
+        if indirect {
+            break;
+        }
+        let changes = [
+            &mut report.redundant_loads,
+            &mut report.branches_removed,
+            &mut report.dead_instructions,
+            &mut report.constants_merged,
+        ];
+        let passes: [fn(&mut Vec<Statement>, &References) -> usize; 4] = [
+            redundant_loads,
+            branches_to_next,
+            dead_code,
+            merge_constants,
+        ];
+        let mut changed = false;
+        for (count, pass) in changes.into_iter().zip(passes) {
+            let removed = pass(&mut ast, &references);
+            *count += removed;
+            if removed > 0 {
+                changed = true;
+                break;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    unpin_addresses(&mut ast);
+    report.after = ast.len();
+    (ast, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::{ast_to_source, parsed_to_ast, Statement};
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::{Headless, Runtime};
+
+    use super::optimise;
+
+    /// Optimise `source`, checking the outputs for `inputs` are unchanged
+    fn check(source: &str, inputs: &[usize]) -> (String, usize) {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let run = |ast: &[Statement]| {
+            let assembly = assemble_from_ast(ast, &instruction_set).unwrap();
+            let mut memory = assembly.memory.clone();
+            let mut runtime = Headless::load_assembled(&mut memory, &instruction_set)
+                .with_inputs(inputs.iter().copied())
+                .with_step_limit(1000);
+            runtime.run().unwrap();
+            runtime.outputs().to_vec()
+        };
+        let expected = run(&ast);
+        let (optimised, report) = optimise(ast);
+        assert_eq!(run(&optimised), expected);
+        (ast_to_source(&optimised, &instruction_set), report.saved())
+    }
+
+    #[test]
+    fn test_redundant_loads() {
+        let (source, saved) = check(
+            "INP\nSTA x\nLDA x\nOUT\nLDA x\nLDA y\nSTA y\nOUT\nHLT\nx: DAT\ny: DAT 4\n",
+            &[3],
+        );
+        assert_eq!(
+            source,
+            "    INP\n    STA x\n    OUT\n    LDA y\n    OUT\n    HLT\nx: DAT 0\ny: DAT 4\n"
+        );
+        assert_eq!(saved, 3);
+        // the load clears the negative flag which BRP reads
+        let (_, saved) = check(
+            "INP\nSUB one\nSTA x\nLDA x\nBRP end\nOUT\nend: HLT\none: DAT 1\nx: DAT\n",
+            &[0],
+        );
+        assert_eq!(saved, 0);
+    }
+
+    #[test]
+    fn test_branches_and_dead_code() {
+        let source = "INP
+    BRZ first
+    BRA next
+next: OUT
+    BRA first
+    OUT
+    HLT
+first: BRA second
+second: BRA 9
+    HLT
+";
+        let (source, saved) = check(source, &[0]);
+        assert_eq!(source, "    INP\n    BRZ 3\nnext: OUT\n    HLT\n");
+        assert_eq!(saved, 6);
+    }
+
+    #[test]
+    fn test_merge_constants() {
+        let source = "LDA a\nADD b\nADD c\nSTA c\nOUT\nHLT\na: DAT 1\nb: DAT 1\nc: DAT 1\ntable: DAT 1\nDAT 2\n";
+        let (source, saved) = check(source, &[]);
+        assert!(source.contains("LDA a\n    ADD a\n    ADD c"));
+        assert_eq!(saved, 1);
+    }
+
+    #[test]
+    fn test_self_modifying() {
+        // the exit cell is written, so isn't a branch to follow or remove
+        let source = "LDA ret
+    STA exit
+    BRA sub
+back: OUT
+    HLT
+sub: LDA one
+exit: BRA 0
+ret: BRA back
+one: DAT 1
+";
+        assert_eq!(check(source, &[]).1, 0);
+    }
+}
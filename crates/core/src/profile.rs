@@ -0,0 +1,184 @@
+//! Execution counts per address and per source line.
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use crate::assembler::Assembly;
+use crate::instruction_set::Operation;
+use crate::runtime::{Hook, Machine};
+
+/// A loop found from a taken backwards branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// Address branched to
+    pub head: usize,
+    /// Address of the branch
+    pub tail: usize,
+    /// Times the back-edge was taken
+    pub iterations: usize,
+    /// Instructions executed between `head` and `tail`
+    pub instructions: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    counts: Vec<usize>,
+    total: usize,
+    /// Taken backwards branches, keyed by `(head, tail)`
+    back_edges: BTreeMap<(usize, usize), usize>,
+}
+
+impl Hook for Profiler {
+    fn on_step(&mut self, address: usize, machine: &Machine) {
+        if self.counts.len() <= address {
+            self.counts.resize(machine.memory().len(), 0);
+        }
+        self.counts[address] += 1;
+        self.total += 1;
+        let target = machine.program_counter();
+        let branch = machine
+            .instruction_set()
+            .decode(machine.memory()[address])
+            .map(|(v, _)| v.operation);
+        if target <= address
+            && matches!(
+                branch,
+                Some(
+                    Operation::BranchAlways | Operation::BranchIfZero | Operation::BranchIfPositive
+                )
+            )
+        {
+            *self.back_edges.entry((target, address)).or_default() += 1;
+        }
+    }
+}
+
+impl Profiler {
+    /// Total instructions executed
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Times the instruction at `address` was executed
+    pub fn count(&self, address: usize) -> usize {
+        self.counts.get(address).copied().unwrap_or(0)
+    }
+
+    /// Executions per source line, using the source map of `assembly`
+    pub fn line_counts(&self, assembly: &Assembly) -> BTreeMap<usize, usize> {
+        let mut lines = BTreeMap::new();
+        for (address, line) in assembly.source_map.iter().enumerate() {
+            *lines.entry(*line).or_default() += self.count(address);
+        }
+        lines
+    }
+
+    /// Loops ordered by the instructions executed inside them, largest first
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = self
+            .back_edges
+            .iter()
+            .map(|(&(head, tail), &iterations)| Loop {
+                head,
+                tail,
+                iterations,
+                instructions: (head..=tail).map(|v| self.count(v)).sum(),
+            })
+            .collect();
+        loops.sort_by_key(|v| Reverse(v.instructions));
+        loops
+    }
+
+    fn percent(&self, count: usize) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => count as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// Render `source` with execution counts and percentages beside each line,
+    /// followed by a summary of the loops
+    pub fn annotate(&self, source: &str, assembly: &Assembly) -> String {
+        let lines = self.line_counts(assembly);
+        let mut listing = String::new();
+        for (index, text) in source.lines().enumerate() {
+            match lines.get(&(index + 1)) {
+                Some(&count) => listing.push_str(&format!(
+                    "{:>8} {:>6.2}% | {}\n",
+                    count,
+                    self.percent(count),
+                    text
+                )),
+                None => listing.push_str(&format!("{:>16} | {}\n", "", text)),
+            }
+        }
+        let line_of = |address: usize| assembly.line_of(address).unwrap_or(0);
+        for v in self.loops() {
+            let name = assembly
+                .symbol_at(v.head)
+                .map(|v| format!(" ({})", v))
+                .unwrap_or_default();
+            listing.push_str(&format!(
+                "loop lines {}-{}{}: {} iterations, {} instructions ({:.2}%)\n",
+                line_of(v.head),
+                line_of(v.tail),
+                name,
+                v.iterations,
+                v.instructions,
+                self.percent(v.instructions)
+            ));
+        }
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::{Headless, Runtime};
+
+    use super::{Loop, Profiler};
+
+    const SOURCE: &str = "loop: LDA n
+    SUB one
+    STA n
+    BRZ end
+    BRA loop
+end: HLT
+n: DAT 3
+one: DAT 1
+";
+
+    #[test]
+    fn test_profile() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(SOURCE).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let mut memory = assembly.memory.clone();
+        let mut profiler = Profiler::default();
+        Headless::load_assembled(&mut memory, &instruction_set)
+            .with_hook(&mut profiler)
+            .run()
+            .unwrap();
+        assert_eq!(profiler.total(), 15);
+        assert_eq!(profiler.line_counts(&assembly)[&5], 2);
+        assert_eq!(
+            profiler.loops(),
+            [Loop {
+                head: 0,
+                tail: 4,
+                iterations: 2,
+                instructions: 14
+            }]
+        );
+        let listing = profiler.annotate(SOURCE, &assembly);
+        assert!(listing.starts_with("       3  20.00% | loop: LDA n\n"));
+        assert!(listing.contains("       0   0.00% | n: DAT 3\n"));
+        assert!(
+            listing.ends_with("loop lines 1-5 (loop): 2 iterations, 14 instructions (93.33%)\n")
+        );
+    }
+}
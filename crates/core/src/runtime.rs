@@ -1,107 +1,799 @@
+use std::collections::VecDeque;
 use std::io::{BufRead, Write};
 
-use crate::assembler;
+use crate::assembler::Assembly;
+use crate::instruction_set::{InstructionSet, OperandKind, Operation};
+use crate::stats::Stats;
 
-pub trait Runtime<'a> {
-    /// Load from a assembled program
-    fn load_assembled(memory: &'a mut [usize; 100]) -> Self;
-    /// Run whole program until completion.
-    fn run(&mut self) {
-        while !self.step() {}
-    }
-    /// Step next instruction in program,
-    /// returning whether the program is complete.
-    fn step(&mut self) -> bool;
+/// Maximum depth of nested subroutine calls
+pub const RETURN_STACK_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    InvalidInstruction {
+        address: usize,
+        word: usize,
+    },
+    AddressOutOfRange {
+        address: usize,
+        memory_size: usize,
+    },
+    /// Too many nested calls, limited by [`RETURN_STACK_SIZE`]
+    StackOverflow {
+        address: usize,
+    },
+    /// Returned without a matching call
+    StackUnderflow {
+        address: usize,
+    },
+    /// Input was requested when none was left
+    InputExhausted {
+        address: usize,
+    },
+    /// Input that could not be read as a number or character
+    InvalidInput {
+        address: usize,
+        input: String,
+    },
+    StepLimitExceeded {
+        limit: usize,
+    },
 }
 
-pub struct CommandLine<'a> {
-    memory: &'a mut [usize; 100],
-    program_counter: usize,
-    accumulator: usize,
-    stdin: std::io::Stdin,
-    stdout: std::io::Stdout,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputError {
+    Exhausted,
+    Invalid(String),
 }
 
-impl CommandLine<'_> {
-    fn write_stdout(&mut self, content: &str) {
-        let mut handle = self.stdout.lock();
-        handle.write_all(content.as_bytes()).unwrap();
-        self.stdout.flush().unwrap();
-    }
+/// Where the machine reads input from and writes output to
+pub trait Io {
+    /// Read a number for `INP`
+    fn input(&mut self) -> Result<usize, InputError>;
+    /// Read a character code for `INA`
+    fn input_character(&mut self) -> Result<usize, InputError>;
+    fn output(&mut self, value: usize);
+    fn output_character(&mut self, value: char);
+}
 
-    fn read_stdin(&mut self, buf: &mut String) {
-        buf.clear();
-        let mut handle = self.stdin.lock();
-        handle.read_line(buf).unwrap();
-        *buf = buf.strip_suffix("\n").unwrap().trim().to_string();
-    }
+/// Registers and memory of a running program
+pub struct Machine<'a> {
+    memory: &'a mut [usize],
+    instruction_set: &'a InstructionSet,
+    program_counter: usize,
+    accumulator: usize,
+    /// Set when a subtraction went below zero
+    negative_flag: bool,
+    /// Return addresses of subroutine calls
+    return_stack: Vec<usize>,
+    stats: Stats,
 }
 
-impl<'a> Runtime<'a> for CommandLine<'a> {
-    fn load_assembled(memory: &'a mut [usize; 100]) -> Self {
+impl<'a> Machine<'a> {
+    pub fn new(memory: &'a mut [usize], instruction_set: &'a InstructionSet) -> Self {
         Self {
             memory,
+            instruction_set,
             program_counter: 0,
             accumulator: 0,
-            stdin: std::io::stdin(),
-            stdout: std::io::stdout(),
+            negative_flag: false,
+            return_stack: Vec::with_capacity(RETURN_STACK_SIZE),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn memory(&self) -> &[usize] {
+        self.memory
+    }
+
+    pub fn instruction_set(&self) -> &InstructionSet {
+        self.instruction_set
+    }
+
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    pub fn accumulator(&self) -> usize {
+        self.accumulator
+    }
+
+    pub fn negative_flag(&self) -> bool {
+        self.negative_flag
+    }
+
+    /// Set the flag as if an earlier subtraction had gone below zero, or not
+    pub fn set_negative_flag(&mut self, negative: bool) {
+        self.negative_flag = negative;
+    }
+
+    pub fn return_stack(&self) -> &[usize] {
+        &self.return_stack
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Describe the registers and the next instruction on a single line,
+    /// naming operands from `assembly` when given
+    pub fn trace(&self, assembly: Option<&Assembly>) -> String {
+        let word = self.memory.get(self.program_counter).copied().unwrap_or(0);
+        let instruction = match assembly {
+            Some(assembly) => assembly.disassemble(word, self.instruction_set),
+            None => self.instruction_set.disassemble(word),
+        };
+        format!(
+            "pc: {:02} acc: {:03} neg: {} stack: {:?} | {}",
+            self.program_counter,
+            self.accumulator,
+            self.negative_flag as u8,
+            self.return_stack,
+            instruction.unwrap_or_else(|| format!("DAT {}", word)),
+        )
+    }
+
+    fn set_accumulator(&mut self, value: usize) {
+        self.accumulator = value;
+        self.negative_flag = false;
+    }
+
+    fn input_error(&self, err: InputError) -> RuntimeError {
+        let address = self.program_counter;
+        match err {
+            InputError::Exhausted => RuntimeError::InputExhausted { address },
+            InputError::Invalid(input) => RuntimeError::InvalidInput { address, input },
         }
     }
-    fn step(&mut self) -> bool {
-        let opcode = assembler::extract_opcode_from_assembled(self.memory[self.program_counter]);
-        let value = assembler::extract_value_from_assembled(self.memory[self.program_counter]);
-        match (opcode, value) {
-            (assembler::OPCODE_ADD, _) => {
-                let result = (self.memory[value] + self.accumulator).clamp(0, 999);
-                self.accumulator = result;
+
+    fn check_address(&self, address: usize) -> Result<usize, RuntimeError> {
+        match address < self.memory.len() {
+            true => Ok(address),
+            false => Err(RuntimeError::AddressOutOfRange {
+                address,
+                memory_size: self.memory.len(),
+            }),
+        }
+    }
+
+    /// Read a word as an operand, memory loaded from elsewhere can hold words
+    /// too large for the machine
+    fn read(&self, address: usize) -> usize {
+        self.memory[address].min(self.instruction_set.config().max_value())
+    }
+
+    /// Execute the next instruction, returning whether the program is complete.
+    pub fn step(&mut self, io: &mut dyn Io) -> Result<bool, RuntimeError> {
+        let word = self.memory[self.check_address(self.program_counter)?];
+        let (definition, value) =
+            self.instruction_set
+                .decode(word)
+                .ok_or(RuntimeError::InvalidInstruction {
+                    address: self.program_counter,
+                    word,
+                })?;
+        let value = match definition.operand {
+            OperandKind::Address => self.check_address(value)?,
+            OperandKind::Indirect => {
+                let pointer = self.check_address(value)?;
+                self.stats.record_read(pointer);
+                self.check_address(self.memory[pointer])?
             }
-            (assembler::OPCODE_SUB, _) => {
-                let result = (self.memory[value] - self.accumulator).clamp(0, 999);
-                self.accumulator = result;
+            _ => value,
+        };
+        match definition.operation {
+            Operation::Add | Operation::Subtract | Operation::Multiply | Operation::Load => {
+                self.stats.record_read(value)
             }
-            (assembler::OPCODE_STA, _) => self.memory[value] = self.accumulator,
-            (assembler::OPCODE_LDA, _) => self.accumulator = self.memory[value],
-            (assembler::OPCODE_BRA, _) => {
+            Operation::Store => self.stats.record_write(value),
+            Operation::BranchAlways => self.stats.record_branch(true),
+            Operation::BranchIfZero => self.stats.record_branch(self.accumulator == 0),
+            Operation::BranchIfPositive => self.stats.record_branch(!self.negative_flag),
+            _ => {}
+        }
+        let address = self.program_counter;
+        let complete = self.execute(definition.operation, value, io)?;
+        self.stats.record_instruction(address, definition.mnemonic);
+        Ok(complete)
+    }
+
+    fn execute(
+        &mut self,
+        operation: Operation,
+        value: usize,
+        io: &mut dyn Io,
+    ) -> Result<bool, RuntimeError> {
+        let max_value = self.instruction_set.config().max_value();
+        match operation {
+            Operation::Add => {
+                let result = (self.accumulator + self.read(value)).min(max_value);
+                self.set_accumulator(result);
+            }
+            Operation::Subtract => {
+                let operand = self.read(value);
+                match self.accumulator.checked_sub(operand) {
+                    Some(result) => self.set_accumulator(result),
+                    None => {
+                        // wrap around like a decimal odometer, BRP checks the flag instead
+                        self.accumulator = self.accumulator + max_value + 1 - operand;
+                        self.negative_flag = true;
+                    }
+                }
+            }
+            Operation::Multiply => {
+                let result = self
+                    .accumulator
+                    .saturating_mul(self.read(value))
+                    .min(max_value);
+                self.set_accumulator(result);
+            }
+            Operation::Store => self.memory[value] = self.accumulator,
+            Operation::Load => self.set_accumulator(self.read(value)),
+            Operation::BranchAlways => {
                 self.program_counter = value;
-                return false;
+                return Ok(false);
             }
-            (assembler::OPCODE_BRZ, _) => {
+            Operation::BranchIfZero => {
                 if self.accumulator == 0 {
                     self.program_counter = value;
-                    return false;
+                    return Ok(false);
                 }
             }
-            (assembler::OPCODE_BRP, _) => {
-                if self.accumulator > 0 {
+            Operation::BranchIfPositive => {
+                if !self.negative_flag {
                     self.program_counter = value;
-                    return false;
+                    return Ok(false);
                 }
             }
-            (assembler::OPCODE_INP, 1) => {
-                let mut ok = false;
-                while !ok {
-                    self.write_stdout(&format!("<<< "));
-                    let mut buf = String::new();
-                    self.read_stdin(&mut buf);
-                    if let Ok(value) = buf.parse::<usize>() {
-                        self.accumulator = value.min(999);
-                        ok = true;
-                    } else {
-                        self.write_stdout("not a valid number\n");
-                        ok = false;
-                    }
+            Operation::Call => {
+                if self.return_stack.len() == RETURN_STACK_SIZE {
+                    return Err(RuntimeError::StackOverflow {
+                        address: self.program_counter,
+                    });
                 }
+                self.return_stack.push(self.program_counter + 1);
+                self.program_counter = value;
+                return Ok(false);
             }
-            (assembler::OPCODE_OUT, 2) => {
-                self.write_stdout(&format!(">>> {}\n", self.accumulator.to_string()));
+            Operation::Return => {
+                self.program_counter =
+                    self.return_stack
+                        .pop()
+                        .ok_or(RuntimeError::StackUnderflow {
+                            address: self.program_counter,
+                        })?;
+                return Ok(false);
+            }
+            Operation::Input => {
+                let value = io.input().map_err(|v| self.input_error(v))?;
+                self.set_accumulator(value.min(max_value));
+            }
+            Operation::Output => io.output(self.accumulator),
+            Operation::InputCharacter => {
+                let value = io.input_character().map_err(|v| self.input_error(v))?;
+                self.set_accumulator(value.min(max_value));
+            }
+            Operation::OutputCharacter => {
+                let value = u8::try_from(self.accumulator)
+                    .ok()
+                    .filter(u8::is_ascii)
+                    .unwrap_or(b'?');
+                io.output_character(char::from(value));
+            }
+            Operation::Halt => return Ok(true),
+            Operation::Data | Operation::LoadIndexed | Operation::StoreIndexed => {
+                unreachable!("data and pseudo-instructions are never decoded")
             }
-            (assembler::OPCODE_HLT, _) => return true,
-            _ => unreachable!(),
-        }
-        if self.program_counter == self.memory.len() {
-            return true;
         }
         self.program_counter += 1;
-        false
+        Ok(self.program_counter == self.memory.len())
+    }
+}
+
+/// Observes every instruction a runtime executes, for profiling and coverage
+pub trait Hook {
+    /// Called after the instruction at `address` executed successfully
+    fn on_step(&mut self, address: usize, machine: &Machine);
+}
+
+pub trait Runtime<'a> {
+    /// Load from a assembled program
+    fn load_assembled(memory: &'a mut [usize], instruction_set: &'a InstructionSet) -> Self;
+    /// Run whole program until completion.
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        while !self.step()? {}
+        Ok(())
+    }
+    /// Step next instruction in program,
+    /// returning whether the program is complete.
+    fn step(&mut self) -> Result<bool, RuntimeError>;
+    /// Current state of the machine
+    fn machine(&self) -> &Machine<'a>;
+}
+
+/// I/O using stdin and stdout
+struct Terminal {
+    stdin: std::io::Stdin,
+    stdout: std::io::Stdout,
+    /// Given up front instead of reading stdin
+    inputs: Option<VecDeque<usize>>,
+    /// Prompt for input, and ask again when it is invalid
+    interactive: bool,
+    /// Print only the raw output values
+    quiet: bool,
+}
+
+impl Terminal {
+    fn write_stdout(&mut self, content: &str) {
+        let mut handle = self.stdout.lock();
+        handle.write_all(content.as_bytes()).unwrap();
+        self.stdout.flush().unwrap();
+    }
+
+    /// Read a line of input, `None` once stdin is closed
+    fn read_stdin(&mut self) -> Option<String> {
+        if self.interactive && !self.quiet {
+            self.write_stdout("<<< ");
+        }
+        let mut buf = String::new();
+        let mut handle = self.stdin.lock();
+        match handle.read_line(&mut buf).unwrap() {
+            0 => None,
+            _ => Some(buf.trim().to_string()),
+        }
+    }
+
+    fn read_input<T>(
+        &mut self,
+        parse: impl Fn(&str) -> Option<T>,
+        message: &str,
+    ) -> Result<T, InputError> {
+        loop {
+            let buf = self.read_stdin().ok_or(InputError::Exhausted)?;
+            match parse(&buf) {
+                Some(value) => return Ok(value),
+                None if self.interactive => self.write_stdout(message),
+                None => return Err(InputError::Invalid(buf)),
+            }
+        }
+    }
+}
+
+impl Io for Terminal {
+    fn input(&mut self) -> Result<usize, InputError> {
+        if let Some(inputs) = self.inputs.as_mut() {
+            return inputs.pop_front().ok_or(InputError::Exhausted);
+        }
+        self.read_input(|v| v.parse().ok(), "not a valid number\n")
+    }
+
+    fn input_character(&mut self) -> Result<usize, InputError> {
+        if let Some(inputs) = self.inputs.as_mut() {
+            return inputs.pop_front().ok_or(InputError::Exhausted);
+        }
+        let parse = |buf: &str| {
+            let mut chars = buf.chars();
+            match (chars.next(), chars.next()) {
+                (Some(value), None) if value.is_ascii() => Some(value as usize),
+                _ => None,
+            }
+        };
+        self.read_input(parse, "not a valid character\n")
+    }
+
+    fn output(&mut self, value: usize) {
+        match self.quiet {
+            true => self.write_stdout(&format!("{}\n", value)),
+            false => self.write_stdout(&format!(">>> {}\n", value)),
+        }
+    }
+
+    fn output_character(&mut self, value: char) {
+        self.write_stdout(&value.to_string());
+    }
+}
+
+pub struct CommandLine<'a> {
+    machine: Machine<'a>,
+    terminal: Terminal,
+    /// Print machine state to stderr before each step
+    trace: bool,
+    /// Names used in the trace
+    assembly: Option<&'a Assembly>,
+    hooks: Vec<&'a mut dyn Hook>,
+}
+
+impl<'a> CommandLine<'a> {
+    pub fn with_hook(mut self, hook: &'a mut dyn Hook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn with_trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Show label names in the trace
+    pub fn with_assembly(mut self, assembly: &'a Assembly) -> Self {
+        self.assembly = Some(assembly);
+        self
+    }
+
+    /// Take input from these values rather than stdin,
+    /// running out of them is a runtime error
+    pub fn with_inputs(mut self, inputs: impl IntoIterator<Item = usize>) -> Self {
+        self.terminal.inputs = Some(inputs.into_iter().collect());
+        self
+    }
+
+    /// When disabled, input is read without prompts
+    /// and invalid input is a runtime error rather than asked for again
+    pub fn with_interactive(mut self, interactive: bool) -> Self {
+        self.terminal.interactive = interactive;
+        self
+    }
+
+    /// Print outputs as bare numbers, without prompts or markers
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.terminal.quiet = quiet;
+        self
+    }
+}
+
+impl<'a> Runtime<'a> for CommandLine<'a> {
+    fn load_assembled(memory: &'a mut [usize], instruction_set: &'a InstructionSet) -> Self {
+        Self {
+            machine: Machine::new(memory, instruction_set),
+            terminal: Terminal {
+                stdin: std::io::stdin(),
+                stdout: std::io::stdout(),
+                inputs: None,
+                interactive: true,
+                quiet: false,
+            },
+            trace: false,
+            assembly: None,
+            hooks: vec![],
+        }
+    }
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        if self.trace {
+            eprintln!("{}", self.machine.trace(self.assembly));
+        }
+        let address = self.machine.program_counter();
+        let complete = self.machine.step(&mut self.terminal)?;
+        for hook in self.hooks.iter_mut() {
+            hook.on_step(address, &self.machine);
+        }
+        Ok(complete)
+    }
+    fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+/// A value written by the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Number(usize),
+    Character(char),
+}
+
+impl Output {
+    /// The accumulator value that was written, characters as their ASCII code
+    pub fn value(&self) -> usize {
+        match self {
+            Output::Number(value) => *value,
+            Output::Character(value) => *value as usize,
+        }
+    }
+}
+
+/// I/O from a fixed list of inputs, recording every output
+#[derive(Debug, Default)]
+struct Scripted {
+    inputs: VecDeque<usize>,
+    outputs: Vec<Output>,
+}
+
+impl Io for Scripted {
+    fn input(&mut self) -> Result<usize, InputError> {
+        self.inputs.pop_front().ok_or(InputError::Exhausted)
+    }
+
+    fn input_character(&mut self) -> Result<usize, InputError> {
+        self.inputs.pop_front().ok_or(InputError::Exhausted)
+    }
+
+    fn output(&mut self, value: usize) {
+        self.outputs.push(Output::Number(value));
+    }
+
+    fn output_character(&mut self, value: char) {
+        self.outputs.push(Output::Character(value));
+    }
+}
+
+/// Runs without a terminal, for tests and tooling
+pub struct Headless<'a> {
+    machine: Machine<'a>,
+    io: Scripted,
+    steps: usize,
+    step_limit: Option<usize>,
+    hooks: Vec<&'a mut dyn Hook>,
+}
+
+impl<'a> Headless<'a> {
+    pub fn with_hook(mut self, hook: &'a mut dyn Hook) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Values given to `INP` and `INA`, in order
+    pub fn with_inputs(mut self, inputs: impl IntoIterator<Item = usize>) -> Self {
+        self.io.inputs = inputs.into_iter().collect();
+        self
+    }
+
+    /// Queue more values after any not yet read
+    pub fn push_inputs(&mut self, inputs: impl IntoIterator<Item = usize>) {
+        self.io.inputs.extend(inputs);
+    }
+
+    /// Stop with an error after this many instructions
+    pub fn with_step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = Some(step_limit);
+        self
+    }
+
+    /// Inputs not yet read
+    pub fn inputs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.io.inputs.iter().copied()
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.io.outputs
+    }
+
+    /// Number of instructions executed so far
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+}
+
+impl<'a> Runtime<'a> for Headless<'a> {
+    fn load_assembled(memory: &'a mut [usize], instruction_set: &'a InstructionSet) -> Self {
+        Self {
+            machine: Machine::new(memory, instruction_set),
+            io: Scripted::default(),
+            steps: 0,
+            step_limit: None,
+            hooks: vec![],
+        }
+    }
+    fn step(&mut self) -> Result<bool, RuntimeError> {
+        if let Some(limit) = self.step_limit.filter(|v| self.steps >= *v) {
+            return Err(RuntimeError::StepLimitExceeded { limit });
+        }
+        let address = self.machine.program_counter();
+        let complete = self.machine.step(&mut self.io)?;
+        self.steps += 1;
+        for hook in self.hooks.iter_mut() {
+            hook.on_step(address, &self.machine);
+        }
+        Ok(complete)
+    }
+    fn machine(&self) -> &Machine<'a> {
+        &self.machine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+
+    use super::{Headless, Output, Runtime, RuntimeError};
+
+    fn run(
+        source: &str,
+        instruction_set: &InstructionSet,
+    ) -> (Vec<usize>, Result<(), RuntimeError>) {
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, instruction_set);
+        let mut memory = assemble_from_ast(&ast, instruction_set).unwrap().memory;
+        let result = Headless::load_assembled(&mut memory, instruction_set).run();
+        (memory, result)
+    }
+
+    #[test]
+    fn test_subtract_below_zero() {
+        let (memory, result) = run(
+            r#"
+    LDA a
+    SUB b
+    BRP end
+    STA result
+end:
+    HLT
+a: DAT 3
+b: DAT 5
+result: DAT
+"#,
+            &InstructionSet::standard(),
+        );
+        result.unwrap();
+        assert_eq!(memory[7], 998);
+    }
+
+    #[test]
+    fn test_subtract_and_branch_if_positive() {
+        // SUB takes memory from the accumulator, the baseline took the accumulator from memory
+        // and BRP now branches on zero, the baseline only branched above it
+        let (memory, result) = run(
+            r#"
+    LDA a
+    SUB b
+    STA diff
+    SUB diff
+    BRP zero
+    HLT
+zero:
+    STA taken
+    HLT
+a: DAT 5
+b: DAT 3
+diff: DAT
+taken: DAT 1
+"#,
+            &InstructionSet::standard(),
+        );
+        result.unwrap();
+        assert_eq!(memory[10], 2);
+        assert_eq!(memory[11], 0);
+    }
+
+    #[test]
+    fn test_indirect() {
+        let (memory, result) = run(
+            r#"
+    LDA @src
+    STA @dst
+    HLT
+src: DAT 5
+dst: DAT 6
+    DAT 42
+    DAT
+"#,
+            &InstructionSet::standard().with_indirect(),
+        );
+        result.unwrap();
+        assert_eq!(memory[6], 42);
+    }
+
+    #[test]
+    fn test_invalid_instruction() {
+        let (_, result) = run("DAT 400", &InstructionSet::standard());
+        assert_eq!(
+            result,
+            Err(RuntimeError::InvalidInstruction {
+                address: 0,
+                word: 400
+            })
+        );
+    }
+
+    #[test]
+    fn test_indirect_out_of_range() {
+        let (_, result) = run(
+            "LDA @ptr\nptr: DAT 500\n",
+            &InstructionSet::standard().with_indirect(),
+        );
+        assert_eq!(
+            result,
+            Err(RuntimeError::AddressOutOfRange {
+                address: 500,
+                memory_size: 100
+            })
+        );
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let (memory, result) = run(
+            r#"
+    CAL double
+    CAL double
+    STA result
+    HLT
+double:
+    LDA value
+    ADD value
+    STA value
+    RET
+value: DAT 3
+result: DAT
+"#,
+            &InstructionSet::standard().with_subroutines(),
+        );
+        result.unwrap();
+        assert_eq!(memory[9], 12);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let (_, result) = run(
+            "loop: CAL loop",
+            &InstructionSet::standard().with_subroutines(),
+        );
+        assert_eq!(result, Err(RuntimeError::StackOverflow { address: 0 }));
+        let (_, result) = run("RET", &InstructionSet::standard().with_subroutines());
+        assert_eq!(result, Err(RuntimeError::StackUnderflow { address: 0 }));
+    }
+
+    #[test]
+    fn test_scripted_io() {
+        let instruction_set = InstructionSet::standard().with_extended_io();
+        let mut parsed = pass_program("INP\nOUT\nINP\nOTC\nINP\n").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let mut memory = assemble_from_ast(&ast, &instruction_set).unwrap().memory;
+        let mut runtime =
+            Headless::load_assembled(&mut memory, &instruction_set).with_inputs([1200, 72]);
+        assert_eq!(
+            runtime.run(),
+            Err(RuntimeError::InputExhausted { address: 4 })
+        );
+        assert_eq!(
+            runtime.outputs(),
+            [Output::Number(999), Output::Character('H')]
+        );
+        assert_eq!(runtime.steps(), 4);
+        assert_eq!(runtime.machine().stats().per_mnemonic["INP"], 2);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program("loop: BRA loop").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let mut memory = assemble_from_ast(&ast, &instruction_set).unwrap().memory;
+        let mut runtime =
+            Headless::load_assembled(&mut memory, &instruction_set).with_step_limit(50);
+        assert_eq!(
+            runtime.run(),
+            Err(RuntimeError::StepLimitExceeded { limit: 50 })
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let instruction_set = InstructionSet::standard();
+        let source =
+            "loop: LDA n\nSUB one\nSTA n\nBRZ end\nBRA loop\nend: HLT\nn: DAT 3\none: DAT 1\n";
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let mut memory = assemble_from_ast(&ast, &instruction_set).unwrap().memory;
+        let mut runtime = Headless::load_assembled(&mut memory, &instruction_set);
+        runtime.run().unwrap();
+        let stats = runtime.machine().stats();
+        assert_eq!(stats.instructions, 15);
+        assert_eq!(stats.per_mnemonic["BRZ"], 3);
+        assert_eq!((stats.memory_reads, stats.memory_writes), (6, 3));
+        assert_eq!((stats.branches_taken, stats.branches_not_taken), (3, 2));
+        assert_eq!((stats.code_mailboxes(), stats.data_mailboxes()), (6, 2));
+    }
+
+    #[test]
+    fn test_oversized_words() {
+        // words loaded from elsewhere can be too wide, and read as the largest value
+        let instruction_set = InstructionSet::standard();
+        let mut memory = vec![505, 105, 902, 0, 0, usize::MAX];
+        memory.resize(100, 0);
+        let mut runtime = Headless::load_assembled(&mut memory, &instruction_set);
+        runtime.run().unwrap();
+        assert_eq!(runtime.outputs()[0].value(), 999);
     }
 }
@@ -0,0 +1,346 @@
+//! A small solver for linear constraints over bounded integers.
+//!
+//! Bounds are narrowed from each constraint in turn, then the widest variable
+//! is split in half and both halves searched, lower first. Domains are finite,
+//! so given enough steps every problem is decided. Arithmetic is checked, and
+//! anything which doesn't fit in an `i64` is left undecided.
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// `constant + Σ coefficient * variable`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Linear {
+    pub constant: i64,
+    /// Coefficients by variable, never zero
+    pub terms: BTreeMap<usize, i64>,
+}
+
+impl Linear {
+    pub fn constant(value: i64) -> Self {
+        Self {
+            constant: value,
+            terms: BTreeMap::new(),
+        }
+    }
+
+    pub fn variable(variable: usize) -> Self {
+        Self {
+            constant: 0,
+            terms: BTreeMap::from([(variable, 1)]),
+        }
+    }
+
+    /// The value when no variables are involved
+    pub fn as_constant(&self) -> Option<i64> {
+        self.terms.is_empty().then_some(self.constant)
+    }
+
+    /// The sum, or `None` when a coefficient overflows, like the other operations
+    pub fn add(&self, other: &Linear) -> Option<Self> {
+        let mut sum = self.clone();
+        sum.constant = sum.constant.checked_add(other.constant)?;
+        for (variable, coefficient) in &other.terms {
+            let entry = sum.terms.entry(*variable).or_default();
+            *entry = entry.checked_add(*coefficient)?;
+            if *entry == 0 {
+                sum.terms.remove(variable);
+            }
+        }
+        Some(sum)
+    }
+
+    pub fn scale(&self, factor: i64) -> Option<Self> {
+        if factor == 0 {
+            return Some(Self::default());
+        }
+        Some(Self {
+            constant: self.constant.checked_mul(factor)?,
+            terms: self
+                .terms
+                .iter()
+                .map(|(v, c)| Some((*v, c.checked_mul(factor)?)))
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    pub fn sub(&self, other: &Linear) -> Option<Self> {
+        self.add(&other.scale(-1)?)
+    }
+
+    pub fn offset(&self, value: i64) -> Option<Self> {
+        self.add(&Self::constant(value))
+    }
+
+    /// Value given every variable's value
+    pub fn evaluate(&self, values: &[i64]) -> Option<i64> {
+        self.terms.iter().try_fold(self.constant, |sum, (v, c)| {
+            sum.checked_add(c.checked_mul(values[*v])?)
+        })
+    }
+
+    /// Smallest and largest values over the domains
+    fn range(&self, domains: &[(i64, i64)]) -> Option<(i64, i64)> {
+        self.terms
+            .iter()
+            .try_fold((self.constant, self.constant), |(lo, hi), (v, c)| {
+                let (a, b) = (c.checked_mul(domains[*v].0)?, c.checked_mul(domains[*v].1)?);
+                Some((lo.checked_add(a.min(b))?, hi.checked_add(a.max(b))?))
+            })
+    }
+}
+
+impl Display for Linear {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (variable, coefficient) in &self.terms {
+            let sign = match (first, *coefficient < 0) {
+                (true, true) => "-",
+                (true, false) => "",
+                (false, true) => " - ",
+                (false, false) => " + ",
+            };
+            match coefficient.abs() {
+                1 => write!(f, "{}x{}", sign, variable)?,
+                c => write!(f, "{}{}*x{}", sign, c, variable)?,
+            }
+            first = false;
+        }
+        match (first, self.constant) {
+            (true, c) => write!(f, "{}", c),
+            (false, 0) => Ok(()),
+            (false, c) if c < 0 => write!(f, " - {}", -c),
+            (false, c) => write!(f, " + {}", c),
+        }
+    }
+}
+
+/// A relation between an expression and zero
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Constraint {
+    /// `expression >= 0`
+    NonNegative(Linear),
+    /// `expression == 0`
+    Zero(Linear),
+    /// `expression != 0`
+    NonZero(Linear),
+}
+
+impl Constraint {
+    /// Whether `values` satisfy the constraint, `None` when the expression overflows
+    pub fn holds(&self, values: &[i64]) -> Option<bool> {
+        Some(match self {
+            Constraint::NonNegative(v) => v.evaluate(values)? >= 0,
+            Constraint::Zero(v) => v.evaluate(values)? == 0,
+            Constraint::NonZero(v) => v.evaluate(values)? != 0,
+        })
+    }
+
+    /// The opposite relation, `None` when the expression overflows
+    pub fn negate(&self) -> Option<Self> {
+        Some(match self {
+            Constraint::NonNegative(v) => Constraint::NonNegative(v.scale(-1)?.offset(-1)?),
+            Constraint::Zero(v) => Constraint::NonZero(v.clone()),
+            Constraint::NonZero(v) => Constraint::Zero(v.clone()),
+        })
+    }
+}
+
+/// Outcome of a search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Solution {
+    /// A value for every variable satisfying every constraint
+    Satisfiable(Vec<i64>),
+    Unsatisfiable,
+    /// Gave up after the step limit, or on arithmetic too large to check
+    Unknown,
+}
+
+/// Rounds of narrowing before splitting, narrowing can creep one value at a time
+const PROPAGATION_ROUNDS: usize = 64;
+
+/// Narrow `domains` to the values each constraint allows, returning false when none are left
+/// and `None` on overflow
+fn propagate(constraints: &[Constraint], domains: &mut [(i64, i64)]) -> Option<bool> {
+    for _ in 0..PROPAGATION_ROUNDS {
+        let mut changed = false;
+        for constraint in constraints {
+            let bounds = match constraint {
+                Constraint::NonNegative(v) => vec![v.clone()],
+                Constraint::Zero(v) => vec![v.clone(), v.scale(-1)?],
+                Constraint::NonZero(v) => {
+                    let (lo, hi) = v.range(domains)?;
+                    if lo == 0 && hi == 0 {
+                        return Some(false);
+                    }
+                    // only a single free variable at the edge of its domain can be narrowed
+                    let free: Vec<_> = v
+                        .terms
+                        .iter()
+                        .filter(|(x, _)| domains[**x].0 < domains[**x].1)
+                        .collect();
+                    if let [(x, c)] = free[..] {
+                        let rest = v
+                            .terms
+                            .iter()
+                            .filter(|(y, _)| *y != x)
+                            .try_fold(v.constant, |sum, (y, c)| {
+                                sum.checked_add(c.checked_mul(domains[*y].0)?)
+                            })?;
+                        if rest.checked_rem(*c)? == 0 {
+                            let zero = rest.checked_div(*c)?.checked_neg()?;
+                            let domain = &mut domains[*x];
+                            if zero == domain.0 {
+                                domain.0 += 1;
+                                changed = true;
+                            } else if zero == domain.1 {
+                                domain.1 -= 1;
+                                changed = true;
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+            for bound in &bounds {
+                let (_, hi) = bound.range(domains)?;
+                if hi < 0 {
+                    return Some(false);
+                }
+                for (x, c) in &bound.terms {
+                    // the largest the other terms can be, this term has to make up the rest
+                    let (a, b) = (c.checked_mul(domains[*x].0)?, c.checked_mul(domains[*x].1)?);
+                    let rest = hi.checked_sub(a.max(b))?;
+                    let domain = &mut domains[*x];
+                    if *c > 0 {
+                        let lo = rest.checked_div_euclid(*c)?.checked_neg()?;
+                        if lo > domain.0 {
+                            domain.0 = lo;
+                            changed = true;
+                        }
+                    } else {
+                        let hi = rest.checked_div_euclid(c.checked_neg()?)?;
+                        if hi < domain.1 {
+                            domain.1 = hi;
+                            changed = true;
+                        }
+                    }
+                    if domain.0 > domain.1 {
+                        return Some(false);
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    Some(true)
+}
+
+fn search(constraints: &[Constraint], mut domains: Vec<(i64, i64)>, steps: &mut usize) -> Solution {
+    if *steps == 0 {
+        return Solution::Unknown;
+    }
+    *steps -= 1;
+    match propagate(constraints, &mut domains) {
+        Some(true) => {}
+        Some(false) => return Solution::Unsatisfiable,
+        None => return Solution::Unknown,
+    }
+    let widest = (0..domains.len())
+        .filter(|v| domains[*v].0 < domains[*v].1)
+        .max_by_key(|v| domains[*v].1 - domains[*v].0);
+    let Some(variable) = widest else {
+        let values: Vec<i64> = domains.iter().map(|v| v.0).collect();
+        let holds: Option<Vec<bool>> = constraints.iter().map(|v| v.holds(&values)).collect();
+        return match holds {
+            Some(holds) if holds.iter().all(|v| *v) => Solution::Satisfiable(values),
+            Some(_) => Solution::Unsatisfiable,
+            None => Solution::Unknown,
+        };
+    };
+    let (lo, hi) = domains[variable];
+    let middle = lo + (hi - lo) / 2;
+    let mut unknown = false;
+    for half in [(lo, middle), (middle + 1, hi)] {
+        let mut domains = domains.clone();
+        domains[variable] = half;
+        match search(constraints, domains, steps) {
+            Solution::Satisfiable(v) => return Solution::Satisfiable(v),
+            Solution::Unknown => unknown = true,
+            Solution::Unsatisfiable => {}
+        }
+    }
+    match unknown {
+        true => Solution::Unknown,
+        false => Solution::Unsatisfiable,
+    }
+}
+
+/// Find values for `variables` variables, each within `0..=max_value`,
+/// satisfying every constraint, preferring small values
+pub fn solve(
+    constraints: &[Constraint],
+    variables: usize,
+    max_value: i64,
+    step_limit: usize,
+) -> Solution {
+    let mut steps = step_limit;
+    search(constraints, vec![(0, max_value); variables], &mut steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{solve, Constraint, Linear, Solution};
+
+    fn x(variable: usize) -> Linear {
+        Linear::variable(variable)
+    }
+
+    #[test]
+    fn test_solve() {
+        // x0 + x1 == 1500, x0 - x1 >= 200, x1 != 650
+        let constraints = [
+            Constraint::Zero(x(0).add(&x(1)).unwrap().offset(-1500).unwrap()),
+            Constraint::NonNegative(x(0).sub(&x(1)).unwrap().offset(-200).unwrap()),
+            Constraint::NonZero(x(1).offset(-650).unwrap()),
+        ];
+        let Solution::Satisfiable(values) = solve(&constraints, 2, 999, 10_000) else {
+            panic!("expected a solution");
+        };
+        assert!(constraints.iter().all(|v| v.holds(&values) == Some(true)));
+        assert_eq!(values, vec![999, 501]);
+        // 2 * x0 == 7 has no integer solution
+        let constraints = [Constraint::Zero(x(0).scale(2).unwrap().offset(-7).unwrap())];
+        assert_eq!(solve(&constraints, 1, 999, 10_000), Solution::Unsatisfiable);
+        // x0 > x1 > x0
+        let constraints = [
+            Constraint::NonNegative(x(0).sub(&x(1)).unwrap().offset(-1).unwrap()),
+            Constraint::NonNegative(x(1).sub(&x(0)).unwrap().offset(-1).unwrap()),
+        ];
+        assert_eq!(solve(&constraints, 2, 999, 10_000), Solution::Unsatisfiable);
+        assert_eq!(solve(&constraints, 2, 999, 3), Solution::Unknown);
+        // coefficients too large to check are undecided rather than wrapping
+        let constraints = [Constraint::NonNegative(x(0).scale(i64::MAX / 2).unwrap())];
+        assert_eq!(solve(&constraints, 1, 999, 10_000), Solution::Unknown);
+    }
+
+    #[test]
+    fn test_linear() {
+        let v = x(0)
+            .scale(3)
+            .and_then(|v| v.sub(&x(1)))
+            .and_then(|v| v.offset(-4))
+            .unwrap();
+        assert_eq!(v.to_string(), "3*x0 - x1 - 4");
+        assert_eq!(v.evaluate(&[2, 1]), Some(1));
+        assert_eq!(v.sub(&v).unwrap().as_constant(), Some(0));
+        assert_eq!(v.scale(i64::MAX), None);
+        assert_eq!(v.evaluate(&[i64::MAX, 0]), None);
+        let constraint = Constraint::NonNegative(v);
+        let negated = constraint.negate().unwrap();
+        assert_eq!(constraint.holds(&[2, 2]), Some(true));
+        assert_eq!(negated.holds(&[2, 2]), Some(false));
+        assert_eq!(negated.holds(&[1, 0]), Some(true));
+    }
+}
@@ -0,0 +1,81 @@
+//! Counters collected while a program runs.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// Total instructions executed
+    pub instructions: usize,
+    /// Instructions executed, by mnemonic
+    pub per_mnemonic: BTreeMap<&'static str, usize>,
+    pub memory_reads: usize,
+    pub memory_writes: usize,
+    pub branches_taken: usize,
+    pub branches_not_taken: usize,
+    /// Addresses executed as instructions
+    code: BTreeSet<usize>,
+    /// Addresses read or written as data
+    data: BTreeSet<usize>,
+}
+
+impl Stats {
+    pub(crate) fn record_instruction(&mut self, address: usize, mnemonic: &'static str) {
+        self.instructions += 1;
+        *self.per_mnemonic.entry(mnemonic).or_default() += 1;
+        self.code.insert(address);
+    }
+
+    pub(crate) fn record_read(&mut self, address: usize) {
+        self.memory_reads += 1;
+        self.data.insert(address);
+    }
+
+    pub(crate) fn record_write(&mut self, address: usize) {
+        self.memory_writes += 1;
+        self.data.insert(address);
+    }
+
+    pub(crate) fn record_branch(&mut self, taken: bool) {
+        match taken {
+            true => self.branches_taken += 1,
+            false => self.branches_not_taken += 1,
+        }
+    }
+
+    /// Number of distinct mailboxes executed as instructions
+    pub fn code_mailboxes(&self) -> usize {
+        self.code.len()
+    }
+
+    /// Number of distinct mailboxes read or written as data
+    pub fn data_mailboxes(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "instructions executed: {}", self.instructions)?;
+        for (mnemonic, count) in &self.per_mnemonic {
+            writeln!(f, "  {}: {}", mnemonic, count)?;
+        }
+        writeln!(
+            f,
+            "memory reads: {}, writes: {}",
+            self.memory_reads, self.memory_writes
+        )?;
+        writeln!(
+            f,
+            "branches taken: {}, not taken: {}",
+            self.branches_taken, self.branches_not_taken
+        )?;
+        write!(
+            f,
+            "mailboxes used: {} code, {} data",
+            self.code_mailboxes(),
+            self.data_mailboxes()
+        )
+    }
+}
@@ -0,0 +1,599 @@
+//! Exhaustive search for the shortest straight-line code equivalent to a fragment.
+//!
+//! Candidates are built from loads, stores and arithmetic on the fragment's cells,
+//! shortest first, and run on a [`Machine`] against the original. One passing a
+//! set of test values is then checked against every value the live-in cells can hold.
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use crate::ast::{Instruction, InstructionType, MemoryLocation, Statement};
+use crate::instruction_set::{InstructionSet, Operation};
+use crate::runtime::{InputError, Io, Machine};
+
+/// Live-in values are checked exhaustively, so there can only be a few of them
+pub const MAX_LIVE_IN: usize = 2;
+/// Longest fragment searched for a shorter equivalent
+pub const MAX_LENGTH: usize = 6;
+/// Most combinations of live-in values and flag checked exhaustively,
+/// two live-in cells or the accumulator and one cell on the standard machine
+pub const MAX_DOMAIN: usize = 2_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SuperoptError {
+    /// Only loads, stores and arithmetic on labeled cells are straight-line code
+    Unsupported {
+        line: usize,
+    },
+    /// A live cell the fragment never mentions
+    UnknownCell {
+        name: String,
+    },
+    /// The fragment reads a value that wasn't declared live-in
+    UndefinedRead {
+        name: String,
+        line: usize,
+    },
+    TooManyLiveIn {
+        count: usize,
+    },
+    TooLong {
+        length: usize,
+    },
+    /// Too many combinations of live-in values to check every one, `None` past `usize`
+    DomainTooLarge {
+        size: Option<usize>,
+    },
+}
+
+impl Display for SuperoptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuperoptError::Unsupported { line } => write!(
+                f,
+                "only LDA, STA, ADD, SUB and MUL of labels can be superoptimised, line {}",
+                line
+            ),
+            SuperoptError::UnknownCell { name } => {
+                write!(f, "'{}' is not used by the fragment", name)
+            }
+            SuperoptError::UndefinedRead { name, line } => {
+                write!(f, "{} is read on line {} but isn't live-in", name, line)
+            }
+            SuperoptError::TooManyLiveIn { count } => write!(
+                f,
+                "{} live-in values can't be checked exhaustively, at most {} can",
+                count, MAX_LIVE_IN
+            ),
+            SuperoptError::TooLong { length } => write!(
+                f,
+                "fragment is {} instructions long, at most {} can be searched",
+                length, MAX_LENGTH
+            ),
+            SuperoptError::DomainTooLarge { size } => write!(
+                f,
+                "{} combinations of live-in values can't be checked exhaustively, at most {} can",
+                size.map_or("too many".to_string(), |v| v.to_string()),
+                MAX_DOMAIN
+            ),
+        }
+    }
+}
+
+/// Which values the code around a fragment gives it and reads back
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Liveness {
+    pub live_in: Vec<String>,
+    pub live_out: Vec<String>,
+    /// Whether the accumulator and negative flag hold values on entry
+    pub accumulator_in: bool,
+    /// Whether the accumulator and negative flag are read afterwards
+    pub accumulator_out: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superoptimised<'a> {
+    /// The shortest equivalent code, or the original when nothing shorter is
+    pub code: Vec<Statement<'a>>,
+    pub original: usize,
+    /// Number of candidates run against the original
+    pub candidates: usize,
+}
+
+/// Operation on a cell, indexing [`Search::cells`]
+type Step = (Operation, usize);
+
+/// Straight-line code never does any I/O
+struct NoIo;
+
+impl Io for NoIo {
+    fn input(&mut self) -> Result<usize, InputError> {
+        Err(InputError::Exhausted)
+    }
+
+    fn input_character(&mut self) -> Result<usize, InputError> {
+        Err(InputError::Exhausted)
+    }
+
+    fn output(&mut self, _: usize) {}
+
+    fn output_character(&mut self, _: char) {}
+}
+
+struct Search<'i> {
+    instruction_set: &'i InstructionSet,
+    operations: Vec<Operation>,
+    cells: Vec<String>,
+    /// Values of the cells defined by `DAT` in the fragment
+    constants: BTreeMap<usize, usize>,
+    /// Indexes of the live-in and live-out cells
+    live_in: Vec<usize>,
+    live_out: Vec<usize>,
+    liveness: Liveness,
+    /// Instruction slots in the memory image, one more than the longest code
+    slots: usize,
+}
+
+impl Search<'_> {
+    /// Address of the cell holding the accumulator's value on entry
+    fn accumulator_cell(&self) -> usize {
+        1 + self.slots + self.cells.len()
+    }
+
+    /// Run `code` with `inputs` in the live-in cells, accumulator and flag,
+    /// returning the live-out values followed by the accumulator and flag
+    fn run(&self, code: &[Step], inputs: &[usize]) -> Vec<usize> {
+        let encode = |operation, address| {
+            let definition = self
+                .instruction_set
+                .by_operation(operation, false)
+                .expect("only operations of the instruction set are searched");
+            self.instruction_set.encode(definition, address)
+        };
+        let halt = encode(Operation::Halt, 0);
+        let mut memory = vec![halt; self.accumulator_cell() + 1];
+        memory[0] = encode(Operation::Load, self.accumulator_cell());
+        for (slot, (operation, cell)) in code.iter().enumerate() {
+            memory[1 + slot] = encode(*operation, 1 + self.slots + cell);
+        }
+        for (index, cell) in memory[1 + self.slots..].iter_mut().enumerate() {
+            *cell = self.constants.get(&index).copied().unwrap_or(0);
+        }
+        let mut inputs = inputs.iter();
+        for cell in &self.live_in {
+            memory[1 + self.slots + cell] = *inputs.next().unwrap();
+        }
+        let mut negative = false;
+        if self.liveness.accumulator_in {
+            memory[self.accumulator_cell()] = *inputs.next().unwrap();
+            negative = *inputs.next().unwrap() != 0;
+        }
+        let mut machine = Machine::new(&mut memory, self.instruction_set);
+        // loading the accumulator clears the flag, so it's set afterwards
+        machine
+            .step(&mut NoIo)
+            .expect("the accumulator cell exists");
+        machine.set_negative_flag(negative);
+        while !machine
+            .step(&mut NoIo)
+            .expect("straight-line code always runs")
+        {}
+        let (accumulator, negative) = (machine.accumulator(), machine.negative_flag());
+        let mut outputs: Vec<usize> = self
+            .live_out
+            .iter()
+            .map(|v| memory[1 + self.slots + v])
+            .collect();
+        if self.liveness.accumulator_out {
+            outputs.extend([accumulator, negative as usize]);
+        }
+        outputs
+    }
+
+    /// Number of values [`Self::run`] returns
+    fn width(&self) -> usize {
+        self.live_out.len() + 2 * self.liveness.accumulator_out as usize
+    }
+
+    /// Number of input combinations [`Self::all_inputs`] yields, `None` past `usize`
+    fn domain(&self) -> Option<usize> {
+        let count = self.live_in.len() + self.liveness.accumulator_in as usize;
+        let values = self.instruction_set.config().max_value() + 1;
+        values
+            .checked_pow(count as u32)?
+            .checked_mul(self.flags().len().max(1))
+    }
+
+    /// Both states of the flag on entry, when it's live
+    fn flags(&self) -> &'static [usize] {
+        match self.liveness.accumulator_in {
+            true => &[0, 1],
+            false => &[],
+        }
+    }
+
+    /// Every value of the live-in cells and accumulator, then the flag, in order
+    fn all_inputs(&self) -> impl Iterator<Item = Vec<usize>> + '_ {
+        let count = self.live_in.len() + self.liveness.accumulator_in as usize;
+        let values = self.instruction_set.config().max_value() + 1;
+        (0..values.pow(count as u32)).flat_map(move |mut v| {
+            let inputs: Vec<usize> = (0..count)
+                .map(|_| {
+                    let value = v % values;
+                    v /= values;
+                    value
+                })
+                .collect();
+            self.with_flags(inputs)
+        })
+    }
+
+    /// `inputs` with each state of the flag appended, or alone when it isn't live
+    fn with_flags(&self, inputs: Vec<usize>) -> Vec<Vec<usize>> {
+        match self.flags() {
+            [] => vec![inputs],
+            flags => flags
+                .iter()
+                .map(|flag| inputs.iter().copied().chain([*flag]).collect())
+                .collect(),
+        }
+    }
+
+    /// Values around the edges of the domain, then some spread across it
+    fn test_inputs(&self) -> Vec<Vec<usize>> {
+        let count = self.live_in.len() + self.liveness.accumulator_in as usize;
+        let max = self.instruction_set.config().max_value();
+        let edges = [0, 1, 2, max / 2, max - 1, max];
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % (max as u64 + 1)) as usize
+        };
+        let mut tests: Vec<Vec<usize>> = edges.iter().map(|v| vec![*v; count]).collect();
+        for a in edges {
+            for b in edges {
+                tests.push((0..count).map(|i| if i % 2 == 0 { a } else { b }).collect());
+            }
+        }
+        tests.extend((0..32).map(|_| (0..count).map(|_| random()).collect()));
+        tests.into_iter().flat_map(|v| self.with_flags(v)).collect()
+    }
+
+    /// Try every sequence of `length` steps extending `prefix`, returning the first equivalent
+    fn extend(
+        &self,
+        prefix: &mut Vec<Step>,
+        length: usize,
+        readable: &mut Vec<bool>,
+        accumulator: bool,
+        check: &mut dyn FnMut(&[Step]) -> bool,
+    ) -> bool {
+        if prefix.len() == length {
+            return check(prefix);
+        }
+        for operation in &self.operations {
+            for cell in 0..self.cells.len() {
+                let allowed = match operation {
+                    // loading twice in a row wastes the first
+                    Operation::Load => {
+                        readable[cell] && !matches!(prefix.last(), Some((Operation::Load, _)))
+                    }
+                    Operation::Store => accumulator && !self.constants.contains_key(&cell),
+                    _ => accumulator && readable[cell],
+                };
+                if !allowed {
+                    continue;
+                }
+                let was_readable = readable[cell];
+                if *operation == Operation::Store {
+                    readable[cell] = true;
+                }
+                prefix.push((*operation, cell));
+                let found = self.extend(prefix, length, readable, true, check);
+                if found {
+                    return true;
+                }
+                prefix.pop();
+                readable[cell] = was_readable;
+            }
+        }
+        false
+    }
+}
+
+/// Index of the cell called `name`, adding it when new
+fn index_of(cells: &mut Vec<String>, name: &str) -> usize {
+    match cells.iter().position(|v| v == name) {
+        Some(index) => index,
+        None => {
+            cells.push(name.to_string());
+            cells.len() - 1
+        }
+    }
+}
+
+/// Find the shortest code equivalent to the straight-line `fragment`,
+/// for the values in `liveness`
+pub fn superoptimise<'a>(
+    fragment: &[Statement<'a>],
+    liveness: &Liveness,
+    instruction_set: &InstructionSet,
+) -> Result<Superoptimised<'a>, SuperoptError> {
+    let mut cells: Vec<String> = liveness.live_in.clone();
+    let mut constants = BTreeMap::new();
+    let mut original = vec![];
+    for stmt in fragment {
+        let instruction: &Instruction = stmt.into();
+        let line = instruction.line;
+        match (&instruction.instruction, stmt) {
+            (InstructionType::Data(value), Statement::Labeled { label, .. }) => {
+                constants.insert(index_of(&mut cells, &label.label), *value);
+            }
+            (
+                InstructionType::Load(MemoryLocation::Label(name))
+                | InstructionType::Store(MemoryLocation::Label(name))
+                | InstructionType::Add(MemoryLocation::Label(name))
+                | InstructionType::Subtract(MemoryLocation::Label(name))
+                | InstructionType::Multiply(MemoryLocation::Label(name)),
+                _,
+            ) => original.push((
+                instruction.instruction.operation(),
+                index_of(&mut cells, name),
+                line,
+            )),
+            _ => return Err(SuperoptError::Unsupported { line }),
+        }
+    }
+    for name in liveness.live_in.iter().chain(&liveness.live_out) {
+        if !fragment.iter().any(|v| {
+            let instruction: &Instruction = v.into();
+            matches!(instruction.instruction.memory_location(), Some(MemoryLocation::Label(v)) if v == name)
+        }) {
+            return Err(SuperoptError::UnknownCell { name: name.clone() });
+        }
+    }
+    let count = liveness.live_in.len() + liveness.accumulator_in as usize;
+    if count > MAX_LIVE_IN {
+        return Err(SuperoptError::TooManyLiveIn { count });
+    }
+    if original.len() > MAX_LENGTH {
+        return Err(SuperoptError::TooLong {
+            length: original.len(),
+        });
+    }
+    // the original has to be as well defined as the candidates
+    let mut readable: Vec<bool> = (0..cells.len())
+        .map(|v| v < liveness.live_in.len() || constants.contains_key(&v))
+        .collect();
+    let mut accumulator = liveness.accumulator_in;
+    for (operation, cell, line) in &original {
+        let undefined = match operation {
+            Operation::Load => (!readable[*cell]).then(|| cells[*cell].clone()),
+            Operation::Store => (!accumulator).then(|| "the accumulator".to_string()),
+            _ if !accumulator => Some("the accumulator".to_string()),
+            _ => (!readable[*cell]).then(|| cells[*cell].clone()),
+        };
+        if let Some(name) = undefined {
+            return Err(SuperoptError::UndefinedRead { name, line: *line });
+        }
+        match operation {
+            Operation::Store => readable[*cell] = true,
+            _ => accumulator = true,
+        }
+    }
+
+    let search = Search {
+        instruction_set,
+        operations: [
+            Operation::Load,
+            Operation::Store,
+            Operation::Add,
+            Operation::Subtract,
+            Operation::Multiply,
+        ]
+        .into_iter()
+        .filter(|v| instruction_set.by_operation(*v, false).is_some())
+        .collect(),
+        live_in: (0..liveness.live_in.len()).collect(),
+        live_out: liveness
+            .live_out
+            .iter()
+            .map(|v| cells.iter().position(|c| c == v).unwrap())
+            .collect(),
+        cells,
+        constants,
+        liveness: liveness.clone(),
+        slots: original.len() + 1,
+    };
+    match search.domain() {
+        Some(size) if size <= MAX_DOMAIN => {}
+        size => return Err(SuperoptError::DomainTooLarge { size }),
+    }
+    let original_steps: Vec<Step> = original.iter().map(|(op, cell, _)| (*op, *cell)).collect();
+    let tests: Vec<(Vec<usize>, Vec<usize>)> = search
+        .test_inputs()
+        .into_iter()
+        .map(|v| {
+            let expected = search.run(&original_steps, &v);
+            (v, expected)
+        })
+        .collect();
+    // the original's outputs on every input, end to end, only run once a candidate needs them
+    let exhaustive = OnceCell::new();
+    let width = search.width();
+    let mut candidates = 0;
+    let mut check = |code: &[Step]| {
+        candidates += 1;
+        if !tests
+            .iter()
+            .all(|(inputs, expected)| search.run(code, inputs) == *expected)
+        {
+            return false;
+        }
+        if width == 0 {
+            return true;
+        }
+        let expected: &Vec<usize> = exhaustive.get_or_init(|| {
+            search
+                .all_inputs()
+                .flat_map(|v| search.run(&original_steps, &v))
+                .collect()
+        });
+        search
+            .all_inputs()
+            .zip(expected.chunks(width))
+            .all(|(v, expected)| search.run(code, &v) == expected)
+    };
+    let mut best = None;
+    for length in 0..original.len() {
+        let mut prefix = vec![];
+        let mut readable: Vec<bool> = (0..search.cells.len())
+            .map(|v| v < search.live_in.len() || search.constants.contains_key(&v))
+            .collect();
+        if search.extend(
+            &mut prefix,
+            length,
+            &mut readable,
+            liveness.accumulator_in,
+            &mut check,
+        ) {
+            best = Some(prefix);
+            break;
+        }
+    }
+
+    let code = match best {
+        Some(steps) => steps
+            .into_iter()
+            .map(|(operation, cell)| Statement::UnLabeled {
+                instruction: Instruction {
+                    instruction: InstructionType::from_operation(
+                        operation,
+                        MemoryLocation::Label(Cow::Owned(search.cells[cell].clone())),
+                        0,
+                    )
+                    .expect("candidates only use single operand instructions"),
+                    comments: Box::new([]),
+                    end_comment: None,
+                    line: 0,
+                },
+            })
+            .collect(),
+        None => fragment
+            .iter()
+            .filter(|v| {
+                let instruction: &Instruction = (*v).into();
+                instruction.instruction.operation() != Operation::Data
+            })
+            .cloned()
+            .collect(),
+    };
+    Ok(Superoptimised {
+        code,
+        original: original.len(),
+        candidates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{ast_to_source, parsed_to_ast};
+    use crate::grammar::pass_program;
+    use crate::instruction_set::{InstructionSet, MachineConfig};
+
+    use super::{superoptimise, Liveness, SuperoptError};
+
+    fn search(source: &str, liveness: &Liveness) -> Result<String, SuperoptError> {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        superoptimise(&ast, liveness, &instruction_set)
+            .map(|v| ast_to_source(&v.code, &instruction_set))
+    }
+
+    fn cells(names: &[&str]) -> Vec<String> {
+        names.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_finds_shorter() {
+        let liveness = Liveness {
+            live_in: cells(&["a"]),
+            live_out: cells(&["b"]),
+            ..Default::default()
+        };
+        // the temporary isn't live-out, so needn't be written
+        let source = "LDA a\nSTA t\nLDA t\nADD t\nSTA b\n";
+        assert_eq!(
+            search(source, &liveness),
+            Ok("    LDA a\n    ADD a\n    STA b\n".into())
+        );
+        // adding then subtracting one saturates at 999, so has to stay
+        let source = "LDA a\nADD one\nSUB one\nSTA b\none: DAT 1\n";
+        assert_eq!(
+            search(source, &liveness),
+            Ok("    LDA a\n    ADD one\n    SUB one\n    STA b\n".into())
+        );
+        let liveness = Liveness {
+            accumulator_in: true,
+            accumulator_out: true,
+            ..Default::default()
+        };
+        // the flag may be set on entry and the add clears it, so one add remains
+        let source = "STA t\nLDA zero\nADD t\nzero: DAT 0\n";
+        assert_eq!(search(source, &liveness), Ok("    ADD zero\n".into()));
+        // reloading the accumulator clears the flag too, so can't be dropped
+        let source = "STA t\nLDA t\n";
+        assert_eq!(
+            search(source, &liveness),
+            Ok("    STA t\n    LDA t\n".into())
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let liveness = Liveness {
+            live_out: cells(&["b"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            search("LDA a\nSTA b\n", &liveness),
+            Err(SuperoptError::UndefinedRead {
+                name: "a".into(),
+                line: 1
+            })
+        );
+        assert_eq!(
+            search("INP\nSTA b\n", &liveness),
+            Err(SuperoptError::Unsupported { line: 1 })
+        );
+        let liveness = Liveness {
+            live_in: cells(&["a", "c"]),
+            accumulator_in: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            search("LDA a\nADD c\n", &liveness),
+            Err(SuperoptError::TooManyLiveIn { count: 3 })
+        );
+        let liveness = Liveness {
+            live_in: cells(&["a", "c"]),
+            live_out: cells(&["b"]),
+            ..Default::default()
+        };
+        let source = "LDA a\nADD c\nSTA b\n";
+        for (word_digits, size) in [(4, Some(100_000_000)), (11, None)] {
+            let config = MachineConfig::new(100, word_digits).unwrap();
+            let instruction_set = InstructionSet::standard().with_config(config);
+            let mut parsed = pass_program(source).unwrap();
+            let ast = parsed_to_ast(&mut parsed, &instruction_set);
+            assert_eq!(
+                superoptimise(&ast, &liveness, &instruction_set),
+                Err(SuperoptError::DomainTooLarge { size })
+            );
+        }
+    }
+}
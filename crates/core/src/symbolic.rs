@@ -0,0 +1,795 @@
+//! Symbolic execution of assembled programs, finding inputs which lead to each outcome.
+//!
+//! Every `INP` reads a fresh variable, mailboxes and the accumulator hold linear
+//! expressions of them and execution forks wherever the path depends on their
+//! values: at `BRZ` and `BRP`, and where `ADD` saturates or `SUB` goes below zero.
+//! Each path keeps the constraints on its inputs, solved by [`crate::solver`]
+//! whenever it forks to drop the impossible side.
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+
+use crate::assembler::Assembly;
+use crate::instruction_set::{InstructionSet, OperandKind, Operation};
+use crate::runtime::{RuntimeError, RETURN_STACK_SIZE};
+use crate::solver::{solve, Constraint, Linear, Solution};
+
+/// Default number of paths explored before stopping
+pub const DEFAULT_MAX_PATHS: usize = 256;
+/// Default number of inputs a path may read before it's abandoned
+pub const DEFAULT_MAX_INPUTS: usize = 8;
+/// Steps the solver may take on each question
+const SOLVER_STEPS: usize = 10_000;
+
+/// Something checked once a program halts,
+/// e.g. `out0 == in0 + in1` or `total <= 500`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assertion {
+    pub text: String,
+    left: Vec<(i64, Term)>,
+    comparison: Comparison,
+    right: Vec<(i64, Term)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Term {
+    Number(i64),
+    /// The nth input read
+    Input(usize),
+    /// The nth output written
+    Output(usize),
+    Accumulator,
+    Mailbox(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssertionError {
+    /// Not a number, `inN`, `outN`, `acc` or a label of the program
+    UnknownTerm(String),
+    /// Needs exactly one of `==`, `!=`, `<`, `<=`, `>` or `>=`
+    MissingComparison,
+    /// A sum with a missing term, e.g. `in0 +`
+    MissingTerm,
+}
+
+impl Display for AssertionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownTerm(term) => write!(f, "unknown term '{}'", term),
+            Self::MissingComparison => write!(f, "expected one comparison"),
+            Self::MissingTerm => write!(f, "expected a term"),
+        }
+    }
+}
+
+impl Assertion {
+    /// Parse sums of numbers, inputs `inN`, outputs `outN`, the accumulator `acc`
+    /// and labels, compared with `==`, `!=`, `<`, `<=`, `>` or `>=`
+    pub fn parse(text: &str, assembly: &Assembly) -> Result<Self, AssertionError> {
+        let mut tokens = vec![];
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => {}
+                '+' | '-' => tokens.push(c.to_string()),
+                '=' | '!' | '<' | '>' => {
+                    let mut token = c.to_string();
+                    if chars.next_if_eq(&'=').is_some() {
+                        token.push('=');
+                    }
+                    tokens.push(token);
+                }
+                c => {
+                    let mut token = c.to_string();
+                    while let Some(c) = chars.next_if(|v| v.is_ascii_alphanumeric() || *v == '_') {
+                        token.push(c);
+                    }
+                    tokens.push(token);
+                }
+            }
+        }
+        let comparisons = [
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            ("<=", Comparison::LessOrEqual),
+            (">", Comparison::Greater),
+            (">=", Comparison::GreaterOrEqual),
+        ];
+        let mut found = tokens.iter().enumerate().filter_map(|(index, token)| {
+            comparisons
+                .iter()
+                .find(|v| v.0 == token)
+                .map(|v| (index, v.1))
+        });
+        let (Some((index, comparison)), None) = (found.next(), found.next()) else {
+            return Err(AssertionError::MissingComparison);
+        };
+        Ok(Self {
+            text: text.to_string(),
+            left: parse_sum(&tokens[..index], assembly)?,
+            comparison,
+            right: parse_sum(&tokens[index + 1..], assembly)?,
+        })
+    }
+}
+
+fn parse_term(token: &str, assembly: &Assembly) -> Result<Term, AssertionError> {
+    let index = |prefix| {
+        token
+            .strip_prefix(prefix)
+            .filter(|v| !v.is_empty())
+            .and_then(|v| v.parse().ok())
+    };
+    if let Ok(value) = token.parse() {
+        Ok(Term::Number(value))
+    } else if let Some(index) = index("in") {
+        Ok(Term::Input(index))
+    } else if let Some(index) = index("out") {
+        Ok(Term::Output(index))
+    } else if token == "acc" {
+        Ok(Term::Accumulator)
+    } else {
+        assembly
+            .address_of(token)
+            .map(Term::Mailbox)
+            .ok_or_else(|| AssertionError::UnknownTerm(token.to_string()))
+    }
+}
+
+fn parse_sum(tokens: &[String], assembly: &Assembly) -> Result<Vec<(i64, Term)>, AssertionError> {
+    let mut terms = vec![];
+    let mut sign = 1;
+    let mut expect_term = true;
+    for token in tokens {
+        match (token.as_str(), expect_term) {
+            ("+", false) => expect_term = true,
+            ("-", false) => {
+                sign = -1;
+                expect_term = true;
+            }
+            ("-", true) => sign = -sign,
+            (token, true) => {
+                terms.push((sign, parse_term(token, assembly)?));
+                sign = 1;
+                expect_term = false;
+            }
+            (token, false) => return Err(AssertionError::UnknownTerm(token.to_string())),
+        }
+    }
+    match expect_term {
+        true => Err(AssertionError::MissingTerm),
+        false => Ok(terms),
+    }
+}
+
+/// How a path ended, or something it did on the way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Halted,
+    Error(RuntimeError),
+    /// The program halted with the assertion false
+    AssertionFailed(String),
+    /// `OUT` wrote a value which had gone below zero
+    NegativeOutput {
+        address: usize,
+    },
+    /// Still running after the step limit, likely looping forever
+    StepLimitExceeded,
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Halted => write!(f, "halted"),
+            Outcome::Error(err) => write!(f, "runtime error: {:?}", err),
+            Outcome::AssertionFailed(text) => write!(f, "assertion failed: {}", text),
+            Outcome::NegativeOutput { address } => {
+                write!(f, "negative value output at address {}", address)
+            }
+            Outcome::StepLimitExceeded => write!(f, "step limit exceeded"),
+        }
+    }
+}
+
+/// Concrete inputs leading to an outcome
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub outcome: Outcome,
+    pub inputs: Vec<usize>,
+    /// Outputs written before the outcome
+    pub outputs: Vec<usize>,
+    pub steps: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    /// Paths followed to an end
+    pub paths: usize,
+    /// Paths given up on, for reading too many inputs, executing or addressing
+    /// through a value which depends on them, multiplying two of them,
+    /// coefficients too large for an `i64`, or constraints the solver couldn't
+    /// decide in time
+    pub abandoned: usize,
+    /// Whether paths were left unexplored after reaching the limit
+    pub incomplete: bool,
+}
+
+/// State along one path through the program
+#[derive(Debug, Clone)]
+struct Path {
+    memory: Vec<Linear>,
+    program_counter: usize,
+    accumulator: Linear,
+    negative_flag: bool,
+    return_stack: Vec<usize>,
+    /// Number of inputs read, each the variable of its index
+    inputs: usize,
+    outputs: Vec<Linear>,
+    constraints: Vec<Constraint>,
+    steps: usize,
+}
+
+/// What a single step did to a path
+enum Step {
+    Continue(Vec<Path>),
+    End(Path, Outcome),
+    Abandon,
+}
+
+/// Explores the paths through a program
+pub struct Explorer<'a> {
+    memory: &'a [usize],
+    instruction_set: &'a InstructionSet,
+    assertions: Vec<Assertion>,
+    step_limit: usize,
+    max_paths: usize,
+    max_inputs: usize,
+}
+
+impl<'a> Explorer<'a> {
+    pub fn new(memory: &'a [usize], instruction_set: &'a InstructionSet) -> Self {
+        Self {
+            memory,
+            instruction_set,
+            assertions: vec![],
+            step_limit: crate::testing::DEFAULT_STEP_LIMIT,
+            max_paths: DEFAULT_MAX_PATHS,
+            max_inputs: DEFAULT_MAX_INPUTS,
+        }
+    }
+
+    /// Check `assertion` on every path which halts
+    pub fn with_assertion(mut self, assertion: Assertion) -> Self {
+        self.assertions.push(assertion);
+        self
+    }
+
+    /// Stop each path after this many instructions
+    pub fn with_step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = step_limit;
+        self
+    }
+
+    pub fn with_max_paths(mut self, max_paths: usize) -> Self {
+        self.max_paths = max_paths;
+        self
+    }
+
+    pub fn with_max_inputs(mut self, max_inputs: usize) -> Self {
+        self.max_inputs = max_inputs;
+        self
+    }
+
+    fn max_value(&self) -> i64 {
+        self.instruction_set.config().max_value() as i64
+    }
+
+    /// Values of the inputs meeting every constraint, if the solver finds some
+    fn solve(&self, constraints: &[Constraint], inputs: usize) -> Option<Vec<i64>> {
+        match solve(constraints, inputs, self.max_value(), SOLVER_STEPS) {
+            Solution::Satisfiable(values) => Some(values),
+            Solution::Unsatisfiable | Solution::Unknown => None,
+        }
+    }
+
+    /// The path with `constraint` added, if it's still possible
+    fn fork(&self, path: &Path, constraint: Constraint) -> Option<Path> {
+        let mut path = path.clone();
+        path.constraints.push(constraint);
+        self.solve(&path.constraints, path.inputs)?;
+        Some(path)
+    }
+
+    /// Concrete inputs and outputs of a path, given values satisfying it
+    fn finding(&self, path: &Path, values: &[i64], outcome: Outcome) -> Option<Finding> {
+        Some(Finding {
+            outcome,
+            inputs: values[..path.inputs].iter().map(|v| *v as usize).collect(),
+            outputs: path
+                .outputs
+                .iter()
+                .map(|v| Some(v.evaluate(values)? as usize))
+                .collect::<Option<_>>()?,
+            steps: path.steps,
+        })
+    }
+
+    fn term(&self, path: &Path, term: &Term) -> Option<Linear> {
+        match term {
+            Term::Number(v) => Some(Linear::constant(*v)),
+            Term::Input(v) => Some(Linear::variable(*v)),
+            Term::Output(v) => path.outputs.get(*v).cloned(),
+            Term::Accumulator => Some(path.accumulator.clone()),
+            Term::Mailbox(v) => Some(path.memory[*v].clone()),
+        }
+    }
+
+    fn sum(&self, path: &Path, terms: &[(i64, Term)]) -> Option<Linear> {
+        terms
+            .iter()
+            .try_fold(Linear::default(), |sum, (sign, term)| {
+                sum.add(&self.term(path, term)?.scale(*sign)?)
+            })
+    }
+
+    /// Inputs making `assertion` false on a halted path
+    fn violation(&self, path: &Path, assertion: &Assertion) -> Option<Vec<i64>> {
+        // inputs the program never read can take any value
+        let inputs = [&assertion.left, &assertion.right]
+            .into_iter()
+            .flatten()
+            .filter_map(|(_, v)| match v {
+                Term::Input(v) => Some(v + 1),
+                _ => None,
+            })
+            .fold(path.inputs, usize::max);
+        let (Some(left), Some(right)) = (
+            self.sum(path, &assertion.left),
+            self.sum(path, &assertion.right),
+        ) else {
+            // an output that was never written
+            return self.solve(&path.constraints, inputs);
+        };
+        let difference = left.sub(&right)?;
+        let holds = match assertion.comparison {
+            Comparison::Equal => Constraint::Zero(difference),
+            Comparison::NotEqual => Constraint::NonZero(difference),
+            Comparison::Less => Constraint::NonNegative(difference.scale(-1)?.offset(-1)?),
+            Comparison::LessOrEqual => Constraint::NonNegative(difference.scale(-1)?),
+            Comparison::Greater => Constraint::NonNegative(difference.offset(-1)?),
+            Comparison::GreaterOrEqual => Constraint::NonNegative(difference),
+        };
+        let mut constraints = path.constraints.clone();
+        constraints.push(holds.negate()?);
+        self.solve(&constraints, inputs)
+    }
+
+    /// Set the accumulator, clearing the negative flag like the runtime
+    fn set_accumulator(mut path: Path, value: Linear) -> Path {
+        path.accumulator = value;
+        path.negative_flag = false;
+        path.program_counter += 1;
+        path
+    }
+
+    /// Set the accumulator to `value` capped at the largest value, forking when it might be over
+    fn saturate(&self, path: Path, value: Linear) -> Step {
+        let max = self.max_value();
+        match value.as_constant() {
+            Some(v) => Step::Continue(vec![Self::set_accumulator(
+                path,
+                Linear::constant(v.min(max)),
+            )]),
+            None => {
+                let Some(within) = Linear::constant(max).sub(&value) else {
+                    return Step::Abandon;
+                };
+                let within = Constraint::NonNegative(within);
+                let Some(over) = within.negate() else {
+                    return Step::Abandon;
+                };
+                let mut paths = vec![];
+                if let Some(path) = self.fork(&path, within) {
+                    paths.push(Self::set_accumulator(path, value));
+                }
+                if let Some(path) = self.fork(&path, over) {
+                    paths.push(Self::set_accumulator(path, Linear::constant(max)));
+                }
+                Step::Continue(paths)
+            }
+        }
+    }
+
+    /// Follow `branch` when `condition` holds and carry on otherwise, when each is possible
+    fn branch(&self, path: Path, condition: Constraint, target: usize) -> Step {
+        let Some(otherwise) = condition.negate() else {
+            return Step::Abandon;
+        };
+        let mut paths = vec![];
+        if let Some(mut taken) = self.fork(&path, condition.clone()) {
+            taken.program_counter = target;
+            paths.push(taken);
+        }
+        if let Some(mut next) = self.fork(&path, otherwise) {
+            next.program_counter += 1;
+            paths.push(next);
+        }
+        Step::Continue(paths)
+    }
+
+    fn step(&self, mut path: Path) -> Step {
+        let address = path.program_counter;
+        let memory_size = path.memory.len();
+        let out_of_range = |address| {
+            Outcome::Error(RuntimeError::AddressOutOfRange {
+                address,
+                memory_size,
+            })
+        };
+        if address >= memory_size {
+            return Step::End(path, out_of_range(address));
+        }
+        let Some(word) = path.memory[address].as_constant() else {
+            return Step::Abandon;
+        };
+        let word = word as usize;
+        let Some((definition, value)) = self.instruction_set.decode(word) else {
+            return Step::End(
+                path,
+                Outcome::Error(RuntimeError::InvalidInstruction { address, word }),
+            );
+        };
+        let value = match definition.operand {
+            OperandKind::Address if value >= memory_size => {
+                return Step::End(path, out_of_range(value))
+            }
+            OperandKind::Indirect if value >= memory_size => {
+                return Step::End(path, out_of_range(value))
+            }
+            OperandKind::Indirect => match path.memory[value].as_constant() {
+                Some(v) if (v as usize) < memory_size => v as usize,
+                Some(v) => return Step::End(path, out_of_range(v as usize)),
+                None => return Step::Abandon,
+            },
+            _ => value,
+        };
+        path.steps += 1;
+        let cell = || path.memory[value].clone();
+        match definition.operation {
+            Operation::Add => {
+                let Some(sum) = path.accumulator.add(&cell()) else {
+                    return Step::Abandon;
+                };
+                self.saturate(path, sum)
+            }
+            Operation::Subtract => {
+                let wrapped = path
+                    .accumulator
+                    .sub(&cell())
+                    .and_then(|v| Some((v.offset(self.max_value() + 1)?, v)));
+                let Some((wrapped, difference)) = wrapped else {
+                    return Step::Abandon;
+                };
+                match difference.as_constant() {
+                    Some(v) if v >= 0 => {
+                        Step::Continue(vec![Self::set_accumulator(path, difference)])
+                    }
+                    Some(_) => {
+                        path.accumulator = wrapped;
+                        path.negative_flag = true;
+                        path.program_counter += 1;
+                        Step::Continue(vec![path])
+                    }
+                    None => {
+                        let mut paths = vec![];
+                        let positive = Constraint::NonNegative(difference.clone());
+                        let Some(negative) = positive.negate() else {
+                            return Step::Abandon;
+                        };
+                        if let Some(path) = self.fork(&path, positive) {
+                            paths.push(Self::set_accumulator(path, difference));
+                        }
+                        if let Some(mut path) = self.fork(&path, negative) {
+                            path.accumulator = wrapped;
+                            path.negative_flag = true;
+                            path.program_counter += 1;
+                            paths.push(path);
+                        }
+                        Step::Continue(paths)
+                    }
+                }
+            }
+            Operation::Multiply => {
+                let (a, b) = (path.accumulator.clone(), cell());
+                let product = match (a.as_constant(), b.as_constant()) {
+                    (Some(a), _) => b.scale(a),
+                    (_, Some(b)) => a.scale(b),
+                    (None, None) => None,
+                };
+                let Some(product) = product else {
+                    return Step::Abandon;
+                };
+                self.saturate(path, product)
+            }
+            Operation::Store => {
+                path.memory[value] = path.accumulator.clone();
+                path.program_counter += 1;
+                Step::Continue(vec![path])
+            }
+            Operation::Load => {
+                let value = cell();
+                Step::Continue(vec![Self::set_accumulator(path, value)])
+            }
+            Operation::BranchAlways => {
+                path.program_counter = value;
+                Step::Continue(vec![path])
+            }
+            Operation::BranchIfZero => {
+                let condition = Constraint::Zero(path.accumulator.clone());
+                match path.accumulator.as_constant() {
+                    Some(v) => {
+                        path.program_counter = if v == 0 { value } else { address + 1 };
+                        Step::Continue(vec![path])
+                    }
+                    None => self.branch(path, condition, value),
+                }
+            }
+            Operation::BranchIfPositive => {
+                path.program_counter = match path.negative_flag {
+                    false => value,
+                    true => address + 1,
+                };
+                Step::Continue(vec![path])
+            }
+            Operation::Call => {
+                if path.return_stack.len() == RETURN_STACK_SIZE {
+                    return Step::End(
+                        path,
+                        Outcome::Error(RuntimeError::StackOverflow { address }),
+                    );
+                }
+                path.return_stack.push(address + 1);
+                path.program_counter = value;
+                Step::Continue(vec![path])
+            }
+            Operation::Return => match path.return_stack.pop() {
+                Some(v) => {
+                    path.program_counter = v;
+                    Step::Continue(vec![path])
+                }
+                None => Step::End(
+                    path,
+                    Outcome::Error(RuntimeError::StackUnderflow { address }),
+                ),
+            },
+            Operation::Input | Operation::InputCharacter => {
+                if path.inputs == self.max_inputs {
+                    return Step::Abandon;
+                }
+                let input = Linear::variable(path.inputs);
+                path.inputs += 1;
+                Step::Continue(vec![Self::set_accumulator(path, input)])
+            }
+            Operation::Output | Operation::OutputCharacter => {
+                path.outputs.push(path.accumulator.clone());
+                path.program_counter += 1;
+                Step::Continue(vec![path])
+            }
+            Operation::Halt => Step::End(path, Outcome::Halted),
+            Operation::Data | Operation::LoadIndexed | Operation::StoreIndexed => {
+                unreachable!("data and pseudo-instructions are never decoded")
+            }
+        }
+    }
+
+    /// Follow paths from the start of the program, shortest first
+    pub fn explore(&self) -> Report {
+        let mut report = Report::default();
+        let mut queue = VecDeque::from([Path {
+            memory: self
+                .memory
+                .iter()
+                .map(|v| Linear::constant(*v as i64))
+                .collect(),
+            program_counter: 0,
+            accumulator: Linear::default(),
+            negative_flag: false,
+            return_stack: vec![],
+            inputs: 0,
+            outputs: vec![],
+            constraints: vec![],
+            steps: 0,
+        }]);
+        while let Some(path) = queue.pop_front() {
+            if path.steps == self.step_limit {
+                self.end(&mut report, path, Outcome::StepLimitExceeded);
+                continue;
+            }
+            // an output while the flag is set wrote a value which went below zero
+            let negative_output = path.negative_flag
+                && path
+                    .memory
+                    .get(path.program_counter)
+                    .and_then(Linear::as_constant)
+                    .and_then(|v| self.instruction_set.decode(v as usize))
+                    .is_some_and(|(v, _)| v.operation == Operation::Output);
+            if negative_output {
+                if let Some(values) = self.solve(&path.constraints, path.inputs) {
+                    let outcome = Outcome::NegativeOutput {
+                        address: path.program_counter,
+                    };
+                    report
+                        .findings
+                        .extend(self.finding(&path, &values, outcome));
+                }
+            }
+            match self.step(path) {
+                Step::Continue(paths) => queue.extend(paths),
+                Step::End(path, outcome) => self.end(&mut report, path, outcome),
+                Step::Abandon => report.abandoned += 1,
+            }
+            // every path queued could fork again, stop before the queue outgrows the limit
+            if report.paths >= self.max_paths || queue.len() > self.max_paths {
+                report.incomplete = !queue.is_empty();
+                break;
+            }
+        }
+        report
+    }
+
+    /// Record how a path ended, checking the assertions if it halted
+    fn end(&self, report: &mut Report, path: Path, outcome: Outcome) {
+        let finding = self
+            .solve(&path.constraints, path.inputs)
+            .and_then(|values| self.finding(&path, &values, outcome.clone()));
+        let Some(finding) = finding else {
+            report.abandoned += 1;
+            return;
+        };
+        report.paths += 1;
+        if outcome == Outcome::Halted {
+            for assertion in &self.assertions {
+                if let Some(values) = self.violation(&path, assertion) {
+                    let outcome = Outcome::AssertionFailed(assertion.text.clone());
+                    report
+                        .findings
+                        .extend(self.finding(&path, &values, outcome));
+                }
+            }
+        }
+        report.findings.push(finding);
+    }
+}
+
+/// Explore `assembly` with the default limits, checking `assertions` whenever it halts
+pub fn explore(
+    assembly: &Assembly,
+    instruction_set: &InstructionSet,
+    assertions: Vec<Assertion>,
+) -> Report {
+    assertions
+        .into_iter()
+        .fold(Explorer::new(&assembly.memory, instruction_set), |v, a| {
+            v.with_assertion(a)
+        })
+        .explore()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble_from_ast, Assembly};
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::{Headless, Runtime};
+
+    use super::{explore, Assertion, AssertionError, Explorer, Outcome};
+
+    fn assemble(source: &str) -> Assembly {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(source).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        assemble_from_ast(&ast, &instruction_set).unwrap()
+    }
+
+    /// Run the program concretely on a finding's inputs
+    fn outputs(assembly: &Assembly, inputs: &[usize]) -> Vec<usize> {
+        let instruction_set = InstructionSet::standard();
+        let mut memory = assembly.memory.clone();
+        let mut runtime =
+            Headless::load_assembled(&mut memory, &instruction_set).with_inputs(inputs.to_vec());
+        runtime.run().unwrap();
+        runtime.outputs().iter().map(|v| v.value()).collect()
+    }
+
+    #[test]
+    fn test_paths() {
+        // outputs the larger of two inputs, but gets equal inputs wrong
+        let assembly = assemble(
+            "INP\nSTA a\nINP\nSTA b\nSUB a\nBRZ same\nBRP second\nLDA a\nOUT\nHLT\n\
+             second: LDA b\nOUT\nHLT\nsame: HLT\na: DAT\nb: DAT\n",
+        );
+        let instruction_set = InstructionSet::standard();
+        let assertion = Assertion::parse("out0 >= in0", &assembly).unwrap();
+        let report = explore(&assembly, &instruction_set, vec![assertion]);
+        assert_eq!(report.paths, 3);
+        assert!(!report.incomplete);
+        let halted: Vec<_> = report
+            .findings
+            .iter()
+            .filter(|v| v.outcome == Outcome::Halted)
+            .collect();
+        assert_eq!(halted.len(), 3);
+        for finding in &halted {
+            assert_eq!(outputs(&assembly, &finding.inputs), finding.outputs);
+        }
+        let failed: Vec<_> = report
+            .findings
+            .iter()
+            .filter(|v| matches!(v.outcome, Outcome::AssertionFailed(_)))
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].inputs[0], failed[0].inputs[1]);
+    }
+
+    #[test]
+    fn test_bugs() {
+        let instruction_set = InstructionSet::standard();
+        // subtracting without checking, then looping until the input is exactly ten
+        let assembly =
+            assemble("INP\nSUB ten\nOUT\nloop: BRZ done\nBRA loop\ndone: HLT\nten: DAT 10\n");
+        let report = Explorer::new(&assembly.memory, &instruction_set)
+            .with_step_limit(50)
+            .explore();
+        let outcomes: Vec<_> = report
+            .findings
+            .iter()
+            .map(|v| (v.outcome.clone(), v.inputs.clone()))
+            .collect();
+        assert!(outcomes.contains(&(Outcome::NegativeOutput { address: 2 }, vec![0])));
+        assert!(outcomes.contains(&(Outcome::Halted, vec![10])));
+        assert!(outcomes.contains(&(Outcome::StepLimitExceeded, vec![11])));
+        // paths reading more inputs than allowed are given up
+        let assembly = assemble("loop: INP\nBRZ loop\nOUT\nHLT\n");
+        let report = Explorer::new(&assembly.memory, &instruction_set)
+            .with_max_inputs(3)
+            .explore();
+        assert_eq!((report.paths, report.abandoned), (3, 1));
+        // doubling the input forever, the coefficient outgrows an i64 while x0 == 0 stays possible
+        let assembly = assemble("INP\nloop: STA x\nADD x\nBRA loop\nx: DAT\n");
+        let report = Explorer::new(&assembly.memory, &instruction_set)
+            .with_step_limit(500)
+            .explore();
+        assert!(report.abandoned >= 1);
+    }
+
+    #[test]
+    fn test_parse_assertion() {
+        let assembly = assemble("HLT\ntotal: DAT\n");
+        assert!(Assertion::parse("out0 == in0 + in1 - -2", &assembly).is_ok());
+        assert!(Assertion::parse("total<=500", &assembly).is_ok());
+        assert_eq!(
+            Assertion::parse("out0 + 1", &assembly),
+            Err(AssertionError::MissingComparison)
+        );
+        assert_eq!(
+            Assertion::parse("out0 == in0 +", &assembly),
+            Err(AssertionError::MissingTerm)
+        );
+        assert_eq!(
+            Assertion::parse("count > 0", &assembly),
+            Err(AssertionError::UnknownTerm("count".into()))
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! Declarative test cases run against assembled programs.
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use pest::iterators::Pairs;
+use serde::Deserialize;
+
+use crate::assembler::Assembly;
+use crate::grammar::Rule;
+use crate::instruction_set::InstructionSet;
+use crate::runtime::{Headless, Hook, Runtime, RuntimeError};
+use crate::stats::Stats;
+
+/// Comment prefix marking an inline test case
+pub const INLINE_TEST_PREFIX: &str = "@test";
+
+/// Default number of instructions a test case may execute
+pub const DEFAULT_STEP_LIMIT: usize = 10_000;
+
+fn default_step_limit() -> usize {
+    DEFAULT_STEP_LIMIT
+}
+
+/// A set of test cases, usually loaded from a TOML file:
+///
+/// ```toml
+/// step_limit = 1000
+///
+/// [[test]]
+/// name = "adds two numbers"
+/// inputs = [3, 4]
+/// expected_outputs = [7]
+/// expected_memory = { userInput = 3 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestSpec {
+    /// Step limit for cases which don't set their own
+    #[serde(default = "default_step_limit")]
+    pub step_limit: usize,
+    #[serde(default, rename = "test")]
+    pub tests: Vec<TestCase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: String,
+    #[serde(default)]
+    pub inputs: Vec<usize>,
+    #[serde(default)]
+    pub expected_outputs: Vec<usize>,
+    /// Final values of memory cells, keyed by label or address
+    #[serde(default)]
+    pub expected_memory: BTreeMap<String, usize>,
+    pub step_limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    Outputs {
+        expected: Vec<usize>,
+        actual: Vec<usize>,
+    },
+    Memory {
+        location: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// An `expected_memory` key that is neither a label nor an address
+    UnknownLocation {
+        location: String,
+    },
+    Runtime(RuntimeError),
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Outputs { expected, actual } => {
+                write!(f, "expected outputs {:?} but got {:?}", expected, actual)
+            }
+            Self::Memory {
+                location,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "expected {} in {} but got {}",
+                expected, location, actual
+            ),
+            Self::UnknownLocation { location } => write!(f, "unknown location {}", location),
+            Self::Runtime(err) => write!(f, "runtime error: {:?}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InlineTestError {
+    /// A section other than `in:`, `out:`, `mem:` or `steps:`
+    UnknownSection {
+        section: String,
+        line: usize,
+    },
+    InvalidValue {
+        value: String,
+        line: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    pub outputs: Vec<usize>,
+    pub steps: usize,
+    pub stats: Stats,
+    pub failures: Vec<Failure>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl TestSpec {
+    pub fn from_toml(content: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    /// Run every case on a fresh copy of the assembled memory
+    pub fn run(&self, assembly: &Assembly, instruction_set: &InstructionSet) -> Vec<TestResult> {
+        self.tests
+            .iter()
+            .map(|case| {
+                let step_limit = case.step_limit.unwrap_or(self.step_limit);
+                case.run(assembly, instruction_set, step_limit, None)
+            })
+            .collect()
+    }
+
+    /// Run every case with `hook` observing each of them, such as [`crate::coverage::Coverage`]
+    pub fn run_with_hook(
+        &self,
+        assembly: &Assembly,
+        instruction_set: &InstructionSet,
+        hook: &mut dyn Hook,
+    ) -> Vec<TestResult> {
+        self.tests
+            .iter()
+            .map(|case| {
+                let step_limit = case.step_limit.unwrap_or(self.step_limit);
+                case.run(assembly, instruction_set, step_limit, Some(hook))
+            })
+            .collect()
+    }
+}
+
+impl TestCase {
+    pub fn run(
+        &self,
+        assembly: &Assembly,
+        instruction_set: &InstructionSet,
+        step_limit: usize,
+        hook: Option<&mut dyn Hook>,
+    ) -> TestResult {
+        let mut memory = assembly.memory.clone();
+        let mut runtime = Headless::load_assembled(&mut memory, instruction_set)
+            .with_inputs(self.inputs.iter().copied())
+            .with_step_limit(step_limit);
+        if let Some(hook) = hook {
+            runtime = runtime.with_hook(hook);
+        }
+        let mut failures = vec![];
+        if let Err(err) = runtime.run() {
+            failures.push(Failure::Runtime(err));
+        }
+        let outputs: Vec<usize> = runtime.outputs().iter().map(|v| v.value()).collect();
+        let steps = runtime.steps();
+        let stats = runtime.machine().stats().clone();
+        if outputs != self.expected_outputs {
+            failures.push(Failure::Outputs {
+                expected: self.expected_outputs.clone(),
+                actual: outputs.clone(),
+            });
+        }
+        for (location, expected) in &self.expected_memory {
+            let address = assembly
+                .address_of(location)
+                .or_else(|| location.parse().ok())
+                .filter(|v| *v < memory.len());
+            match address {
+                Some(address) if memory[address] != *expected => failures.push(Failure::Memory {
+                    location: location.clone(),
+                    expected: *expected,
+                    actual: memory[address],
+                }),
+                Some(_) => {}
+                None => failures.push(Failure::UnknownLocation {
+                    location: location.clone(),
+                }),
+            }
+        }
+        TestResult {
+            name: self.name.clone(),
+            outputs,
+            steps,
+            stats,
+            failures,
+        }
+    }
+}
+
+/// Parse the body of a `; @test` comment,
+/// e.g. `in: 3 4 out: 7 mem: total=7 steps: 100`
+fn parse_inline_test(annotation: &str, line: usize) -> Result<TestCase, InlineTestError> {
+    let mut case = TestCase {
+        name: format!("line {}: {}", line, annotation),
+        inputs: vec![],
+        expected_outputs: vec![],
+        expected_memory: BTreeMap::new(),
+        step_limit: None,
+    };
+    let invalid = |value: &str| InlineTestError::InvalidValue {
+        value: value.to_string(),
+        line,
+    };
+    let mut section = None;
+    for token in annotation.split_whitespace() {
+        if let Some(name) = token.strip_suffix(':') {
+            section = Some(name);
+            continue;
+        }
+        match section {
+            Some("in") => case.inputs.push(token.parse().map_err(|_| invalid(token))?),
+            Some("out") => case
+                .expected_outputs
+                .push(token.parse().map_err(|_| invalid(token))?),
+            Some("mem") => {
+                let (location, value) = token.split_once('=').ok_or_else(|| invalid(token))?;
+                let value = value.parse().map_err(|_| invalid(token))?;
+                case.expected_memory.insert(location.to_string(), value);
+            }
+            Some("steps") if case.step_limit.is_none() => {
+                case.step_limit = Some(token.parse().map_err(|_| invalid(token))?)
+            }
+            Some("steps") | None => return Err(invalid(token)),
+            Some(section) => {
+                return Err(InlineTestError::UnknownSection {
+                    section: section.to_string(),
+                    line,
+                })
+            }
+        }
+    }
+    Ok(case)
+}
+
+/// Collect the test cases written as `; @test ...` comments in a parsed program,
+/// each named by the line of its comment.
+/// Comments after the last statement aren't part of the AST, so these come from the parse.
+pub fn inline_tests(parsed: Pairs<'_, Rule>) -> Result<Vec<TestCase>, InlineTestError> {
+    let mut tests = vec![];
+    for comment in parsed.flatten().filter(|v| v.as_rule() == Rule::comment) {
+        let line = comment.as_span().start_pos().line_col().0;
+        let annotation = comment
+            .as_str()
+            .trim_start_matches(';')
+            .trim()
+            .strip_prefix(INLINE_TEST_PREFIX)
+            // only `@test` on its own, not words like `@tests`
+            .filter(|v| v.is_empty() || v.starts_with(char::is_whitespace));
+        if let Some(annotation) = annotation {
+            tests.push(parse_inline_test(annotation.trim(), line)?);
+        }
+    }
+    Ok(tests)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_from_ast;
+    use crate::ast::parsed_to_ast;
+    use crate::grammar::pass_program;
+    use crate::instruction_set::InstructionSet;
+    use crate::runtime::RuntimeError;
+
+    use super::{inline_tests, Failure, InlineTestError, TestSpec};
+
+    const SPEC: &str = r#"
+step_limit = 100
+
+[[test]]
+name = "adds"
+inputs = [3, 4]
+expected_outputs = [7]
+expected_memory = { first = 3, "5" = 0 }
+
+[[test]]
+name = "wrong"
+inputs = [1, 1]
+expected_outputs = [3]
+expected_memory = { missing = 1 }
+
+[[test]]
+name = "starved"
+inputs = [1]
+expected_outputs = []
+"#;
+
+    #[test]
+    fn test_spec() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed =
+            pass_program("INP\nSTA first\nINP\nADD first\nOUT\nHLT\nfirst: DAT\n").unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let spec = TestSpec::from_toml(SPEC).unwrap();
+        let results = spec.run(&assembly, &instruction_set);
+        assert!(results[0].passed());
+        assert_eq!(results[0].steps, 6);
+        assert_eq!(
+            results[1].failures,
+            [
+                Failure::Outputs {
+                    expected: vec![3],
+                    actual: vec![2]
+                },
+                Failure::UnknownLocation {
+                    location: "missing".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            results[2].failures,
+            [Failure::Runtime(RuntimeError::InputExhausted {
+                address: 2
+            })]
+        );
+        assert!(TestSpec::from_toml("[[test]]\nname = 'a'\ninput = [1]").is_err());
+    }
+
+    #[test]
+    fn test_inline_tests() {
+        let instruction_set = InstructionSet::standard();
+        let source = "; @test in: 3 4 out: 7 mem: first=3 steps: 10\nINP\n; @test out: 1\nHLT\n";
+        let tests = inline_tests(pass_program(source).unwrap()).unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].inputs, [3, 4]);
+        assert_eq!(tests[0].expected_outputs, [7]);
+        assert_eq!(tests[0].expected_memory["first"], 3);
+        assert_eq!(tests[0].step_limit, Some(10));
+        assert_eq!(tests[1].name, "line 3: out: 1");
+
+        assert_eq!(
+            inline_tests(pass_program("HLT\n; @test input: 3\n").unwrap()),
+            Err(InlineTestError::UnknownSection {
+                section: "input".to_string(),
+                line: 2
+            })
+        );
+
+        let tests = inline_tests(pass_program("; @tests are below\n; @test\nHLT\n").unwrap());
+        let tests = tests.unwrap();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].expected_outputs, []);
+
+        // after the last instruction, and after an instruction on the same line
+        let source = "INP\nADD one ; @test in: 5 out: 6\nOUT\none: DAT 1\n; @test in: 1 out: 2";
+        let mut parsed = pass_program(source).unwrap();
+        let tests = inline_tests(parsed.clone()).unwrap();
+        let names: Vec<_> = tests.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["line 2: in: 5 out: 6", "line 5: in: 1 out: 2"]);
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let spec = TestSpec {
+            step_limit: super::DEFAULT_STEP_LIMIT,
+            tests,
+        };
+        assert!(spec
+            .run(&assembly, &instruction_set)
+            .iter()
+            .all(|v| v.passed()));
+    }
+
+    #[test]
+    fn test_examples() {
+        let examples = [
+            (
+                include_str!("../../../examples/add_two.lmc"),
+                InstructionSet::standard(),
+            ),
+            (
+                include_str!("../../../examples/hello.lmc"),
+                InstructionSet::standard().with_extended_io(),
+            ),
+            (
+                include_str!("../../../examples/subroutine.lmc"),
+                InstructionSet::standard().with_subroutines(),
+            ),
+        ];
+        for (source, instruction_set) in examples {
+            let mut parsed = pass_program(source).unwrap();
+            let tests = inline_tests(parsed.clone()).unwrap();
+            let ast = parsed_to_ast(&mut parsed, &instruction_set);
+            let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+            let spec = TestSpec {
+                step_limit: super::DEFAULT_STEP_LIMIT,
+                tests,
+            };
+            assert!(!spec.tests.is_empty());
+            for result in spec.run(&assembly, &instruction_set) {
+                assert!(result.passed(), "{:?}", result);
+            }
+        }
+    }
+}
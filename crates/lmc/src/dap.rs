@@ -0,0 +1,680 @@
+//! A Debug Adapter Protocol server, so editors can debug LMC code.
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use lmc_core::assembler::{assemble_from_ast, Assembly, SymbolKind};
+use lmc_core::ast::{is_generated_label, try_parsed_to_ast};
+use lmc_core::expand::expand_pseudo_instructions;
+use lmc_core::grammar::pass_program;
+use lmc_core::instruction_set::{InstructionSet, Operation};
+use lmc_core::runtime::{Headless, Output, Runtime, RuntimeError};
+use serde_json::{json, Value};
+
+use crate::parse_typed_inputs;
+
+/// The only thread, LMC has a single program counter
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const MEMORY_REFERENCE: u64 = 2;
+/// Instructions run between checks for a pause request
+const POLL_INTERVAL: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    StepIn,
+    StepOver,
+    StepOut,
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn load(path: &str, instruction_set: &InstructionSet) -> Result<Assembly, String> {
+    let source = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut parsed = pass_program(&source).map_err(|err| format!("parse error: {}", err))?;
+    let ast = try_parsed_to_ast(&mut parsed, instruction_set)
+        .map_err(|err| format!("parse error: {}", err))?;
+    let expanded = expand_pseudo_instructions(ast);
+    let mut assembly = assemble_from_ast(&expanded, instruction_set)
+        .map_err(|err| format!("assembler error: {:?}", err))?;
+    // labels the toolchain made up mean nothing in the source being debugged
+    assembly.symbols.retain(|k, _| !is_generated_label(k));
+    Ok(assembly)
+}
+
+/// The first address of each source line, the only ones stepping and breakpoints stop at,
+/// so lines expanding to several mailboxes and the library routines they call run as one
+fn line_starts(assembly: &Assembly) -> BTreeSet<usize> {
+    let mut lines = BTreeSet::new();
+    (0..assembly.program_size())
+        .filter(|v| lines.insert(assembly.source_map[*v]))
+        .collect()
+}
+
+/// A launched program
+struct Session<'a> {
+    runtime: Headless<'a>,
+    assembly: &'a Assembly,
+    line_starts: BTreeSet<usize>,
+    path: String,
+    stop_on_entry: bool,
+    started: bool,
+    finished: bool,
+    /// How to carry on once the input being waited for is given
+    waiting: Option<Resume>,
+    outputs_sent: usize,
+}
+
+struct Adapter<'a, W: Write> {
+    writer: W,
+    seq: u64,
+    instruction_set: &'a InstructionSet,
+    messages: Receiver<Value>,
+    /// Requests received while the program was running
+    pending: VecDeque<Value>,
+    /// Source lines with a breakpoint
+    breakpoints: BTreeSet<usize>,
+    configured: bool,
+}
+
+/// Serve the debug adapter protocol, reading requests from `reader`
+pub fn run(
+    reader: impl BufRead + Send + 'static,
+    writer: impl Write,
+    instruction_set: &InstructionSet,
+) {
+    let (sender, messages) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = reader;
+        while let Some(message) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let mut adapter = Adapter {
+        writer,
+        seq: 0,
+        instruction_set,
+        messages,
+        pending: VecDeque::new(),
+        breakpoints: BTreeSet::new(),
+        configured: false,
+    };
+    adapter.run();
+}
+
+impl<W: Write> Adapter<'_, W> {
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        // a client that went away can't be told about it
+        let _ = write!(
+            self.writer,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let _ = self.writer.flush();
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn respond_error(&mut self, request: &Value, message: String) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn output(&mut self, category: &str, output: String) {
+        self.event("output", json!({ "category": category, "output": output }));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<&str>) {
+        self.event(
+            "stopped",
+            json!({
+                "reason": reason,
+                "description": description,
+                "threadId": THREAD_ID,
+                "allThreadsStopped": true,
+            }),
+        );
+    }
+
+    fn next_message(&mut self) -> Option<Value> {
+        self.pending
+            .pop_front()
+            .or_else(|| self.messages.recv().ok())
+    }
+
+    fn run(&mut self) {
+        while let Some(request) = self.next_message() {
+            match request["command"].as_str().unwrap_or_default() {
+                "initialize" => {
+                    self.respond(
+                        &request,
+                        json!({ "supportsConfigurationDoneRequest": true }),
+                    );
+                    self.event("initialized", json!({}));
+                }
+                "setBreakpoints" => self.set_breakpoints(&request),
+                "configurationDone" => {
+                    self.configured = true;
+                    self.respond(&request, json!({}));
+                }
+                "threads" => self.respond(&request, json!({ "threads": [] })),
+                "launch" => {
+                    let path = request["arguments"]["program"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string();
+                    match load(&path, self.instruction_set) {
+                        Ok(assembly) => return self.debug(&request, path, &assembly),
+                        Err(message) => self.respond_error(&request, message),
+                    }
+                }
+                "disconnect" => return self.respond(&request, json!({})),
+                command => {
+                    self.respond_error(&request, format!("unsupported request: {}", command))
+                }
+            }
+        }
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) {
+        let arguments = &request["arguments"];
+        // verified against the program on disk, which may not be launched yet
+        let assembly = arguments["source"]["path"]
+            .as_str()
+            .and_then(|v| load(v, self.instruction_set).ok());
+        let lines: Vec<usize> = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v["line"].as_u64())
+            .map(|v| v as usize)
+            .collect();
+        self.breakpoints = lines.iter().copied().collect();
+        let breakpoints: Vec<Value> = lines
+            .into_iter()
+            .map(|line| {
+                let verified = assembly
+                    .as_ref()
+                    .is_some_and(|v| v.source_map.contains(&line));
+                json!({ "verified": verified, "line": line })
+            })
+            .collect();
+        self.respond(request, json!({ "breakpoints": breakpoints }));
+    }
+
+    fn debug(&mut self, launch: &Value, path: String, assembly: &Assembly) {
+        let mut memory = assembly.memory.clone();
+        let mut session = Session {
+            runtime: Headless::load_assembled(&mut memory, self.instruction_set),
+            assembly,
+            line_starts: line_starts(assembly),
+            path,
+            stop_on_entry: launch["arguments"]["stopOnEntry"]
+                .as_bool()
+                .unwrap_or_default(),
+            started: false,
+            finished: false,
+            waiting: None,
+            outputs_sent: 0,
+        };
+        self.respond(launch, json!({}));
+        if self.configured {
+            self.start(&mut session);
+        }
+        while let Some(request) = self.next_message() {
+            match request["command"].as_str().unwrap_or_default() {
+                "configurationDone" => {
+                    self.configured = true;
+                    self.respond(&request, json!({}));
+                    self.start(&mut session);
+                }
+                "setBreakpoints" => self.set_breakpoints(&request),
+                "threads" => self.respond(
+                    &request,
+                    json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+                ),
+                "stackTrace" => {
+                    let frames = self.stack_trace(&session);
+                    self.respond(
+                        &request,
+                        json!({ "stackFrames": frames, "totalFrames": frames.len() }),
+                    );
+                }
+                "scopes" => self.respond(
+                    &request,
+                    json!({ "scopes": [
+                        { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                        { "name": "Memory", "variablesReference": MEMORY_REFERENCE, "expensive": false },
+                    ]}),
+                ),
+                "variables" => {
+                    let variables = self.variables(&session, &request["arguments"]);
+                    self.respond(&request, json!({ "variables": variables }));
+                }
+                "continue" => {
+                    self.respond(&request, json!({ "allThreadsContinued": true }));
+                    self.resume(&mut session, Resume::Continue);
+                }
+                "next" => {
+                    self.respond(&request, json!({}));
+                    self.resume(&mut session, Resume::StepOver);
+                }
+                "stepIn" => {
+                    self.respond(&request, json!({}));
+                    self.resume(&mut session, Resume::StepIn);
+                }
+                "stepOut" => {
+                    self.respond(&request, json!({}));
+                    self.resume(&mut session, Resume::StepOut);
+                }
+                // only received while stopped, running programs check for it themselves
+                "pause" => self.respond(&request, json!({})),
+                "evaluate" => {
+                    let expression = request["arguments"]["expression"]
+                        .as_str()
+                        .unwrap_or_default();
+                    let inputs = parse_typed_inputs(expression);
+                    if inputs.is_empty() {
+                        self.respond_error(&request, "type input values to give the program".to_string());
+                        continue;
+                    }
+                    self.respond(
+                        &request,
+                        json!({
+                            "result": format!("queued input {:?}", inputs),
+                            "variablesReference": 0,
+                        }),
+                    );
+                    session.runtime.push_inputs(inputs);
+                    if let Some(resume) = session.waiting.take() {
+                        self.resume(&mut session, resume);
+                    }
+                }
+                "disconnect" => return self.respond(&request, json!({})),
+                command => self.respond_error(&request, format!("unsupported request: {}", command)),
+            }
+        }
+    }
+
+    fn start(&mut self, session: &mut Session) {
+        if session.started {
+            return;
+        }
+        session.started = true;
+        let line = session.assembly.line_of(0);
+        if session.stop_on_entry {
+            self.stopped("entry", None);
+        } else if line.is_some_and(|v| self.breakpoints.contains(&v)) {
+            self.stopped("breakpoint", None);
+        } else {
+            self.resume(session, Resume::Continue);
+        }
+    }
+
+    fn send_outputs(&mut self, session: &mut Session) {
+        let outputs = session.runtime.outputs()[session.outputs_sent..].to_vec();
+        session.outputs_sent += outputs.len();
+        for output in outputs {
+            let text = match output {
+                Output::Number(value) => format!("{}\n", value),
+                Output::Character(value) => value.to_string(),
+            };
+            self.output("stdout", text);
+        }
+    }
+
+    fn finish(&mut self, session: &mut Session, exit_code: i32) {
+        session.finished = true;
+        self.event("exited", json!({ "exitCode": exit_code }));
+        self.event("terminated", json!({}));
+    }
+
+    fn resume(&mut self, session: &mut Session, resume: Resume) {
+        if session.finished {
+            return self.event("terminated", json!({}));
+        }
+        let depth = session.runtime.machine().return_stack().len();
+        let mut steps = 0;
+        loop {
+            let result = session.runtime.step();
+            self.send_outputs(session);
+            match result {
+                Ok(true) => return self.finish(session, 0),
+                Ok(false) => {}
+                Err(RuntimeError::InputExhausted { .. }) => {
+                    session.waiting = Some(resume);
+                    self.output(
+                        "console",
+                        "waiting for input, type it in the debug console\n".to_string(),
+                    );
+                    return self.stopped("pause", Some("Waiting for input"));
+                }
+                Err(err) => {
+                    self.output("stderr", format!("runtime error: {:?}\n", err));
+                    return self.finish(session, 1);
+                }
+            }
+            steps += 1;
+            let machine = session.runtime.machine();
+            let stack = machine.return_stack().len();
+            let line_start = session.line_starts.contains(&machine.program_counter());
+            let done = match resume {
+                Resume::Continue => false,
+                Resume::StepIn => line_start,
+                Resume::StepOver => line_start && stack <= depth,
+                Resume::StepOut => stack < depth,
+            };
+            if done {
+                return self.stopped("step", None);
+            }
+            let line = session.assembly.line_of(machine.program_counter());
+            if line_start && line.is_some_and(|v| self.breakpoints.contains(&v)) {
+                return self.stopped("breakpoint", None);
+            }
+            if steps % POLL_INTERVAL == 0 {
+                while let Ok(message) = self.messages.try_recv() {
+                    if message["command"] == "pause" {
+                        self.respond(&message, json!({}));
+                        return self.stopped("pause", None);
+                    }
+                    self.pending.push_back(message);
+                }
+            }
+        }
+    }
+
+    /// Name of the subroutine called by the `CAL` before `return_address`
+    fn callee(&self, session: &Session, return_address: usize) -> String {
+        let machine = session.runtime.machine();
+        let call = machine.memory()[return_address - 1];
+        match self.instruction_set.decode(call) {
+            Some((definition, operand)) if definition.operation == Operation::Call => session
+                .assembly
+                .symbol_at(operand)
+                .map_or_else(|| operand.to_string(), |v| v.to_string()),
+            _ => "?".to_string(),
+        }
+    }
+
+    fn stack_trace(&self, session: &Session) -> Vec<Value> {
+        let machine = session.runtime.machine();
+        let stack = machine.return_stack();
+        let name = Path::new(&session.path)
+            .file_name()
+            .map(|v| v.to_string_lossy().to_string());
+        let frame = |id: usize, address: usize, function: String| {
+            json!({
+                "id": id,
+                "name": function,
+                "source": { "name": name, "path": session.path },
+                "line": session.assembly.line_of(address).unwrap_or(0),
+                "column": 1,
+                "instructionPointerReference": address.to_string(),
+            })
+        };
+        // innermost first, each caller stopped at its CAL
+        let mut addresses = vec![machine.program_counter()];
+        addresses.extend(stack.iter().rev().map(|v| v - 1));
+        let mut functions: Vec<String> = stack
+            .iter()
+            .rev()
+            .map(|v| self.callee(session, *v))
+            .collect();
+        functions.push("main".to_string());
+        addresses
+            .into_iter()
+            .zip(functions)
+            .enumerate()
+            .map(|(id, (address, function))| frame(id, address, function))
+            .collect()
+    }
+
+    fn variables(&self, session: &Session, arguments: &Value) -> Vec<Value> {
+        let machine = session.runtime.machine();
+        let config = self.instruction_set.config();
+        let addr_width = (config.memory_size() - 1).to_string().len();
+        let word_width = config.word_digits() as usize;
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        match arguments["variablesReference"].as_u64() {
+            Some(REGISTERS_REFERENCE) => {
+                let mut variables = vec![
+                    variable(
+                        "PC".to_string(),
+                        format!("{:0addr_width$}", machine.program_counter()),
+                    ),
+                    variable(
+                        "Accumulator".to_string(),
+                        format!("{:0word_width$}", machine.accumulator()),
+                    ),
+                    variable(
+                        "Negative flag".to_string(),
+                        machine.negative_flag().to_string(),
+                    ),
+                ];
+                if !machine.return_stack().is_empty() {
+                    variables.push(variable(
+                        "Return stack".to_string(),
+                        format!("{:?}", machine.return_stack()),
+                    ));
+                }
+                variables
+            }
+            Some(MEMORY_REFERENCE) => machine
+                .memory()
+                .iter()
+                .enumerate()
+                .map(|(address, word)| {
+                    let symbol = session
+                        .assembly
+                        .symbols
+                        .iter()
+                        .find(|(_, v)| v.address == address);
+                    let name = match symbol {
+                        Some((label, _)) => format!("{:0addr_width$} {}", address, label),
+                        None => format!("{:0addr_width$}", address),
+                    };
+                    let code = address < session.assembly.program_size()
+                        && !symbol.is_some_and(|(_, v)| v.kind == SymbolKind::Data);
+                    let value = match session.assembly.disassemble(*word, self.instruction_set) {
+                        Some(instruction) if code => {
+                            format!("{:0word_width$}  {}", word, instruction)
+                        }
+                        _ => format!("{:0word_width$}", word),
+                    };
+                    variable(name, value)
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use lmc_core::instruction_set::InstructionSet;
+    use serde_json::{json, Value};
+
+    use super::{read_message, run};
+
+    fn frame(message: Value) -> String {
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    /// Serve `requests`, returning every message sent back
+    fn session(requests: Vec<Value>) -> Vec<Value> {
+        let input: String = requests
+            .into_iter()
+            .enumerate()
+            .map(|(seq, mut v)| {
+                v["seq"] = json!(seq + 1);
+                v["type"] = json!("request");
+                frame(v)
+            })
+            .collect();
+        let mut output = vec![];
+        run(Cursor::new(input), &mut output, &InstructionSet::standard());
+
+        let mut reader = Cursor::new(output);
+        let mut messages = vec![];
+        while let Some(message) = read_message(&mut reader) {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_session() {
+        let program = concat!(env!("CARGO_MANIFEST_DIR"), "/../../examples/add_two.lmc");
+        let messages = session(vec![
+            json!({ "command": "initialize", "arguments": { "adapterID": "lmc" } }),
+            json!({ "command": "launch", "arguments": { "program": program } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": program },
+                "breakpoints": [{ "line": 8 }, { "line": 9 }],
+            }}),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "evaluate", "arguments": { "expression": "3, 4", "context": "repl" } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "next" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        let summary: Vec<String> = messages
+            .iter()
+            .map(|v| match v["type"].as_str().unwrap() {
+                "response" => format!("{} {}", v["command"], v["success"]),
+                _ => format!(
+                    "{} {}",
+                    v["event"],
+                    v["body"]["reason"].as_str().unwrap_or_default()
+                ),
+            })
+            .map(|v| v.replace('"', "").trim().to_string())
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "initialize true",
+                "initialized",
+                "launch true",
+                "setBreakpoints true",
+                "configurationDone true",
+                "output",
+                "stopped pause",
+                "evaluate true",
+                "stopped breakpoint",
+                "variables true",
+                "next true",
+                "stopped step",
+                "stackTrace true",
+                "continue true",
+                "output",
+                "exited",
+                "terminated",
+                "disconnect true",
+            ]
+        );
+        assert_eq!(
+            messages[3]["body"]["breakpoints"],
+            json!([{ "verified": false, "line": 8 }, { "verified": true, "line": 9 }])
+        );
+        assert_eq!(
+            messages[9]["body"]["variables"][1],
+            json!({ "name": "Accumulator", "value": "007", "variablesReference": 0 })
+        );
+        assert_eq!(messages[12]["body"]["stackFrames"][0]["line"], 10);
+        assert_eq!(messages[14]["body"]["output"], "7\n");
+    }
+
+    #[test]
+    fn test_multiple_mailbox_lines() {
+        // each LDI expands to several mailboxes, all on its line
+        let program = std::env::temp_dir().join(format!("lmc-dap-{}.lmc", std::process::id()));
+        std::fs::write(
+            &program,
+            "LDI table, i\nLDI table, i\nOUT\nHLT\ntable: DAT 7\ni: DAT 0\n",
+        )
+        .unwrap();
+        let program = program.to_str().unwrap();
+        let line = |v: &Value| v["body"]["stackFrames"][0]["line"].clone();
+        let messages = session(vec![
+            json!({ "command": "initialize", "arguments": { "adapterID": "lmc" } }),
+            json!({ "command": "launch", "arguments": { "program": program } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": program },
+                "breakpoints": [{ "line": 1 }, { "line": 3 }],
+            }}),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "next" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        std::fs::remove_file(program).unwrap();
+        let responses: Vec<&Value> = messages
+            .iter()
+            .filter(|v| v["type"] == "response")
+            .collect();
+        // stepping finishes the line, and its breakpoint isn't hit again on the way
+        assert_eq!(line(responses[5]), 2);
+        assert_eq!(line(responses[7]), 3);
+        let names: Vec<&str> = responses[8]["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|v| v["name"].as_str())
+            .collect();
+        assert!(names.contains(&"10 table"));
+        assert!(!names.iter().any(|v| v.contains("__")));
+    }
+}
@@ -1,10 +1,35 @@
+use std::io::IsTerminal;
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
-use lmc_core::assembler::assemble_from_ast;
-use lmc_core::ast::parsed_to_ast;
-use lmc_core::grammar::pass_program;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use lmc_core::assembler::{assemble_from_ast, Assembly};
+use lmc_core::ast::{ast_to_source, try_parsed_to_ast, Statement};
+use lmc_core::compiler;
+use lmc_core::coverage::Coverage;
+use lmc_core::equivalence::{self, Behaviour, Checker, DEFAULT_RANDOM_TESTS};
+use lmc_core::expand::expand_pseudo_instructions;
+use lmc_core::fuzz::fuzz_inputs;
+use lmc_core::grading::{grade_submission, GradeRow, CSV_HEADER};
+use lmc_core::grammar::{pass_program, Rule};
+use lmc_core::instruction_set::{InstructionSet, MachineConfig};
+use lmc_core::listing::listing;
+use lmc_core::optimise::optimise;
+use lmc_core::profile::Profiler;
 use lmc_core::runtime::{CommandLine, Runtime};
+use lmc_core::superopt::{superoptimise, Liveness};
+use lmc_core::symbolic::{Assertion, Explorer, Outcome, DEFAULT_MAX_INPUTS, DEFAULT_MAX_PATHS};
+use lmc_core::testing::{inline_tests, Failure, TestSpec, DEFAULT_STEP_LIMIT};
+use pest::iterators::Pairs;
+use rayon::prelude::*;
+
+mod dap;
+mod tui;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ReportFormat {
+    Csv,
+    Json,
+}
 
 #[derive(Subcommand, Debug)]
 enum Command {
@@ -19,15 +44,168 @@ enum Command {
         /// Show the Abstract Syntax Tree of source code
         #[arg(long = "ast")]
         show_ast: bool,
+        /// Show source code after expanding pseudo-instructions
+        #[arg(long = "expanded")]
+        show_expanded: bool,
         /// Show final assembled output of source code
         #[arg(long = "assembled")]
         show_assembled: bool,
+        /// Show the assembled output converted back into mnemonics
+        #[arg(long = "disassembled")]
+        show_disassembled: bool,
         /// Enable all outputs
         #[arg(long = "all")]
         show_all: bool,
     },
+    /// Assemble the LMC code, printing one machine word per line
+    Assemble {
+        /// Print a listing with labels, operands and a symbol table instead
+        #[arg(long = "listing")]
+        listing: bool,
+    },
     /// Run the LMC code, using a CLI environment
-    Run,
+    Run {
+        /// Print the machine state to stderr before each instruction
+        #[arg(long = "trace")]
+        trace: bool,
+        /// Comma separated inputs to use instead of stdin, e.g. `3,4`
+        #[arg(long = "input", value_delimiter = ',', conflicts_with = "input_file")]
+        input: Option<Vec<usize>>,
+        /// File of inputs separated by whitespace or commas
+        #[arg(long = "input-file")]
+        input_file: Option<PathBuf>,
+        /// Read stdin without prompts, failing on invalid or missing input;
+        /// the default when stdin is not a terminal
+        #[arg(long = "non-interactive")]
+        non_interactive: bool,
+        /// Print only the raw output values
+        #[arg(short = 'q', long = "quiet")]
+        quiet: bool,
+        /// Print execution statistics to stderr once the program stops
+        #[arg(long = "stats")]
+        stats: bool,
+        /// Print the source annotated with execution counts to stderr once the program stops
+        #[arg(long = "profile")]
+        profile: bool,
+    },
+    /// Run a program against the test cases in a spec file
+    Test {
+        /// LMC code file to test
+        program: PathBuf,
+        /// TOML file listing more test cases,
+        /// on top of the `; @test` comments in the program
+        spec: Option<PathBuf>,
+        /// Print the source annotated with coverage across all test cases
+        #[arg(long = "coverage")]
+        coverage: bool,
+        /// Write coverage across all test cases to an lcov tracefile
+        #[arg(long = "lcov")]
+        lcov: Option<PathBuf>,
+    },
+    /// Test every `.lmc` file in a directory, writing a report of the results
+    Grade {
+        /// Directory of submissions
+        dir: PathBuf,
+        /// TOML file listing the test cases
+        #[arg(long = "spec")]
+        spec: PathBuf,
+        #[arg(long = "format", value_enum, default_value_t = ReportFormat::Csv)]
+        format: ReportFormat,
+        /// Write the report to a file instead of stdout
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Compile a program in the structured language, printing the LMC code
+    Compile {
+        /// Structured language file to compile
+        program: PathBuf,
+        /// Print a listing with labels, operands and a symbol table instead
+        #[arg(long = "listing")]
+        listing: bool,
+        /// Run the peephole optimiser over the generated code
+        #[arg(short = 'O', long = "optimise")]
+        optimise: bool,
+    },
+    /// Search for the shortest code equivalent to a straight-line fragment of LMC code
+    Superopt {
+        /// LMC file of loads, stores and arithmetic, with `DAT` constants
+        fragment: PathBuf,
+        /// Comma separated labels holding values on entry
+        #[arg(long = "live-in", value_delimiter = ',')]
+        live_in: Vec<String>,
+        /// Comma separated labels read after the fragment
+        #[arg(long = "live-out", value_delimiter = ',')]
+        live_out: Vec<String>,
+        /// The accumulator holds a value on entry
+        #[arg(long = "acc-in")]
+        accumulator_in: bool,
+        /// The accumulator and negative flag are read after the fragment
+        #[arg(long = "acc-out")]
+        accumulator_out: bool,
+    },
+    /// Search for inputs on which a program's outputs differ from a reference solution's
+    Equiv {
+        /// LMC code file to check
+        program: PathBuf,
+        /// LMC code file of the reference solution
+        reference: PathBuf,
+        /// Longest sequence of inputs to try
+        #[arg(long = "max-inputs", default_value_t = equivalence::DEFAULT_MAX_INPUTS)]
+        max_inputs: usize,
+        /// Number of random input sequences to try after the systematic ones
+        #[arg(long = "random-tests", default_value_t = DEFAULT_RANDOM_TESTS)]
+        random_tests: usize,
+        /// Seed for the random input sequences
+        #[arg(long = "seed", default_value_t = 0)]
+        seed: u64,
+        /// Number of instructions each run may execute
+        #[arg(long = "step-limit", default_value_t = DEFAULT_STEP_LIMIT)]
+        step_limit: usize,
+    },
+    /// Execute the LMC code symbolically, printing inputs which lead to each way it can end,
+    /// failing when any path errors, loops, outputs a negative value or fails an assertion
+    Symex {
+        /// Check whenever the program halts, e.g. `out0 == in0 + in1` or `total <= 500`
+        #[arg(long = "assert")]
+        assertions: Vec<String>,
+        /// Number of paths to explore before stopping
+        #[arg(long = "max-paths", default_value_t = DEFAULT_MAX_PATHS)]
+        max_paths: usize,
+        /// Number of inputs a path may read before it's given up on
+        #[arg(long = "max-inputs", default_value_t = DEFAULT_MAX_INPUTS)]
+        max_inputs: usize,
+        /// Number of instructions a path may execute before it's reported as looping
+        #[arg(long = "step-limit", default_value_t = DEFAULT_STEP_LIMIT)]
+        step_limit: usize,
+    },
+    /// Run the LMC code with random inputs, printing the first inputs found for each runtime error
+    Fuzz {
+        /// Number of input sequences to try
+        #[arg(long = "iterations", default_value_t = 10_000)]
+        iterations: usize,
+        /// Longest sequence of inputs to try
+        #[arg(long = "max-inputs", default_value_t = DEFAULT_MAX_INPUTS)]
+        max_inputs: usize,
+        /// Seed for the input sequences
+        #[arg(long = "seed", default_value_t = 0)]
+        seed: u64,
+        /// Number of instructions each run may execute
+        #[arg(long = "step-limit", default_value_t = DEFAULT_STEP_LIMIT)]
+        step_limit: usize,
+    },
+    /// Print the LMC code after peephole optimisation, with a report of what changed to stderr
+    Opt,
+    /// Step through the LMC code in a terminal view of the mailboxes and registers
+    Tui {
+        /// Comma separated inputs to queue, more can be typed while running
+        #[arg(long = "input", value_delimiter = ',', conflicts_with = "input_file")]
+        input: Option<Vec<usize>>,
+        /// File of inputs separated by whitespace or commas
+        #[arg(long = "input-file")]
+        input_file: Option<PathBuf>,
+    },
+    /// Debug programs from an editor, speaking the Debug Adapter Protocol over stdio
+    Dap,
 }
 
 #[derive(Parser, Debug)]
@@ -35,32 +213,407 @@ enum Command {
 struct Args {
     /// LMC code file to process
     #[arg(short = 'f', long = "file")]
-    pub file_path: PathBuf,
+    pub file_path: Option<PathBuf>,
+    /// Enable the character I/O instructions (OTC, INA)
+    #[arg(long = "extended-io")]
+    pub extended_io: bool,
+    /// Enable indirect loads and stores (LDA @ptr, STA @ptr)
+    #[arg(long = "indirect", conflicts_with = "subroutines")]
+    pub indirect: bool,
+    /// Enable subroutine calls with a return stack (CAL label, RET)
+    #[arg(long = "subroutines")]
+    pub subroutines: bool,
+    /// Number of mailboxes in memory
+    #[arg(long = "memory-size", default_value_t = 100)]
+    pub memory_size: usize,
+    /// Number of decimal digits in each mailbox
+    #[arg(long = "word-digits", default_value_t = 3)]
+    pub word_digits: u32,
     #[command(subcommand)]
     pub command: Command,
 }
 
-fn main() {
-    let args = Args::parse();
+fn read_file(path: &PathBuf) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("failed to read {}: {}", path.display(), err);
+        std::process::exit(1);
+    })
+}
+
+fn read_inputs(path: &PathBuf) -> Vec<usize> {
+    read_file(path)
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse().unwrap_or_else(|_| {
+                eprintln!("invalid input in {}: '{}'", path.display(), v);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
+/// Values typed while debugging, numbers or else the codes of each character
+fn parse_typed_inputs(text: &str) -> Vec<usize> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|v| !v.is_empty())
+        .flat_map(|v| match v.parse() {
+            Ok(value) => vec![value],
+            Err(_) => v.chars().map(|v| v as usize).collect(),
+        })
+        .collect()
+}
+
+fn parse(source: &str) -> Pairs<'_, Rule> {
+    pass_program(source).unwrap_or_else(|err| {
+        eprintln!("parse error: {}", err);
+        std::process::exit(1);
+    })
+}
+
+fn to_ast<'a>(
+    parsed: &mut Pairs<'a, Rule>,
+    instruction_set: &InstructionSet,
+) -> Vec<Statement<'a>> {
+    try_parsed_to_ast(parsed, instruction_set).unwrap_or_else(|err| {
+        eprintln!("parse error: {}", err);
+        std::process::exit(1);
+    })
+}
+
+fn assemble(expanded: &[Statement], instruction_set: &InstructionSet) -> Assembly {
+    assemble_from_ast(expanded, instruction_set).unwrap_or_else(|err| {
+        eprintln!("assembler error: {:?}", err);
+        std::process::exit(1);
+    })
+}
 
-    let file_content = std::fs::read_to_string(args.file_path).unwrap();
+/// An LMC code file parsed, expanded and assembled
+struct Program<'a> {
+    ast: Vec<Statement<'a>>,
+    expanded: Vec<Statement<'a>>,
+    assembly: Assembly,
+}
 
-    let mut parsed = pass_program(&file_content).unwrap();
-    let tokens = parsed.clone();
-    let ast = parsed_to_ast(&mut parsed);
-    let mut assembled = [0; 100];
-    assemble_from_ast(&ast, &mut assembled).unwrap();
+/// Parse, expand and assemble LMC code, exiting on any error
+fn build<'a>(source: &'a str, instruction_set: &InstructionSet) -> Program<'a> {
+    let ast = to_ast(&mut parse(source), instruction_set);
+    let expanded = expand_pseudo_instructions(ast.clone());
+    let assembly = assemble(&expanded, instruction_set);
+    Program {
+        ast,
+        expanded,
+        assembly,
+    }
+}
 
+/// Read the LMC code file given with `--file`, which commands on a single program need
+fn read_file_arg(file_path: Option<PathBuf>) -> String {
+    let Some(file_path) = file_path else {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--file is required for this command",
+            )
+            .exit();
+    };
+    read_file(&file_path)
+}
+
+/// Read, parse, expand and assemble an LMC code file, exiting on any error
+fn load(path: &PathBuf, instruction_set: &InstructionSet) -> Assembly {
+    build(&read_file(path), instruction_set).assembly
+}
+
+fn print_failure(failure: &Failure) {
+    match failure {
+        Failure::Outputs { expected, actual } => {
+            println!("    outputs differ");
+            println!("      expected: {:?}", expected);
+            println!("      actual:   {:?}", actual);
+            if let Some(index) = expected.iter().zip(actual).position(|(a, b)| a != b) {
+                println!("      first difference at output {}", index);
+            }
+        }
+        Failure::Memory {
+            location,
+            expected,
+            actual,
+        } => println!(
+            "    memory {} expected {} but was {}",
+            location, expected, actual
+        ),
+        Failure::UnknownLocation { location } => {
+            println!("    memory {} is not a label or address", location)
+        }
+        Failure::Runtime(err) => println!("    runtime error: {:?}", err),
+    }
+}
+
+fn run_tests(
+    program: &PathBuf,
+    spec: Option<&PathBuf>,
+    show_coverage: bool,
+    lcov: Option<&PathBuf>,
+    instruction_set: &InstructionSet,
+) -> bool {
+    let mut spec = match spec {
+        Some(spec) => TestSpec::from_toml(&read_file(spec)).unwrap_or_else(|err| {
+            eprintln!("invalid test spec: {}", err);
+            std::process::exit(1);
+        }),
+        None => TestSpec {
+            step_limit: DEFAULT_STEP_LIMIT,
+            tests: vec![],
+        },
+    };
+    let source = read_file(program);
+    let parsed = parse(&source);
+    let ast = to_ast(&mut parsed.clone(), instruction_set);
+    match inline_tests(parsed) {
+        Ok(tests) => spec.tests.extend(tests),
+        Err(err) => {
+            eprintln!("invalid inline test: {:?}", err);
+            std::process::exit(1);
+        }
+    }
+    if spec.tests.is_empty() {
+        eprintln!("no test cases found");
+        return false;
+    }
+    let expanded = expand_pseudo_instructions(ast);
+    let assembly = assemble(&expanded, instruction_set);
+    let mut coverage = Coverage::default();
+    let results = spec.run_with_hook(&assembly, instruction_set, &mut coverage);
+    for result in &results {
+        match result.passed() {
+            true => println!("PASS {} ({} steps)", result.name, result.steps),
+            false => println!("FAIL {} ({} steps)", result.name, result.steps),
+        }
+        result.failures.iter().for_each(print_failure);
+    }
+    let passed = results.iter().filter(|v| v.passed()).count();
+    println!("{} passed, {} failed", passed, results.len() - passed);
+    if show_coverage {
+        print!(
+            "--- Coverage ---\n{}--- END ---\n",
+            coverage.annotate(&source, &expanded)
+        );
+    }
+    if let Some(path) = lcov {
+        let tracefile = coverage.lcov(&program.to_string_lossy(), &expanded);
+        if let Err(err) = std::fs::write(path, tracefile) {
+            eprintln!("failed to write {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+    passed == results.len()
+}
+
+fn grade(
+    dir: &PathBuf,
+    spec: &PathBuf,
+    format: ReportFormat,
+    output: Option<&PathBuf>,
+    instruction_set: &InstructionSet,
+) {
+    let spec = TestSpec::from_toml(&read_file(spec)).unwrap_or_else(|err| {
+        eprintln!("invalid test spec: {}", err);
+        std::process::exit(1);
+    });
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap_or_else(|err| {
+            eprintln!("failed to read {}: {}", dir.display(), err);
+            std::process::exit(1);
+        })
+        .filter_map(|v| v.ok().map(|v| v.path()))
+        .filter(|v| v.extension().is_some_and(|v| v == "lmc"))
+        .collect();
+    files.sort();
+    // panics are reported per submission, don't clutter stderr with them
+    std::panic::set_hook(Box::new(|_| {}));
+    let rows: Vec<GradeRow> = files
+        .par_iter()
+        .flat_map_iter(|path| {
+            let file = path.file_name().unwrap().to_string_lossy();
+            match std::fs::read_to_string(path) {
+                Ok(source) => grade_submission(&file, &source, &spec, instruction_set),
+                Err(err) => vec![GradeRow::error(&file, format!("failed to read: {}", err))],
+            }
+        })
+        .collect();
+    let _ = std::panic::take_hook();
+    let report = match format {
+        ReportFormat::Csv => std::iter::once(CSV_HEADER.to_string())
+            .chain(rows.iter().map(GradeRow::to_csv))
+            .map(|v| v + "\n")
+            .collect(),
+        ReportFormat::Json => serde_json::to_string_pretty(&rows).unwrap() + "\n",
+    };
+    match output {
+        Some(path) => std::fs::write(path, report).unwrap_or_else(|err| {
+            eprintln!("failed to write {}: {}", path.display(), err);
+            std::process::exit(1);
+        }),
+        None => print!("{}", report),
+    }
+}
+
+fn compile(
+    program: &PathBuf,
+    show_listing: bool,
+    optimised: bool,
+    instruction_set: &InstructionSet,
+) {
+    let source = read_file(program);
+    let mut ast = compiler::compile(&source, instruction_set).unwrap_or_else(|err| {
+        eprintln!("{}: {}", program.display(), err);
+        std::process::exit(1);
+    });
+    if optimised {
+        ast = optimise(ast).0;
+    }
+    let assembly = assemble(&ast, instruction_set);
+    match show_listing {
+        true => print!("{}", listing(&ast, &assembly, instruction_set)),
+        false => print!("{}", ast_to_source(&ast, instruction_set)),
+    }
+}
+
+fn superopt(fragment: &PathBuf, liveness: &Liveness, instruction_set: &InstructionSet) {
+    let source = read_file(fragment);
+    let ast = to_ast(&mut parse(&source), instruction_set);
+    let found = superoptimise(&ast, liveness, instruction_set).unwrap_or_else(|err| {
+        eprintln!("{}: {}", fragment.display(), err);
+        std::process::exit(1);
+    });
+    print!("{}", ast_to_source(&found.code, instruction_set));
+    eprintln!(
+        "{} -> {} instructions, {} candidates tried",
+        found.original,
+        found.code.len(),
+        found.candidates
+    );
+}
+
+fn describe(behaviour: &Behaviour) -> String {
+    match &behaviour.error {
+        Some(err) => format!("outputs {:?}, then {:?}", behaviour.outputs, err),
+        None => format!("outputs {:?}", behaviour.outputs),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let config = MachineConfig::new(args.memory_size, args.word_digits).unwrap_or_else(|err| {
+        Args::command()
+            .error(ErrorKind::ValueValidation, err)
+            .exit()
+    });
+    let mut instruction_set = InstructionSet::standard().with_config(config);
+    if args.extended_io {
+        instruction_set = instruction_set.with_extended_io();
+    }
+    if args.indirect {
+        instruction_set = instruction_set.with_indirect();
+    }
+    if args.subroutines {
+        instruction_set = instruction_set.with_subroutines();
+    }
     match args.command {
+        Command::Test {
+            program,
+            spec,
+            coverage,
+            lcov,
+        } => {
+            let passed = run_tests(
+                &program,
+                spec.as_ref(),
+                coverage,
+                lcov.as_ref(),
+                &instruction_set,
+            );
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Command::Grade {
+            dir,
+            spec,
+            format,
+            output,
+        } => grade(&dir, &spec, format, output.as_ref(), &instruction_set),
+        Command::Compile {
+            program,
+            listing,
+            optimise,
+        } => compile(&program, listing, optimise, &instruction_set),
+        Command::Equiv {
+            program,
+            reference,
+            max_inputs,
+            random_tests,
+            seed,
+            step_limit,
+        } => {
+            let (candidate, reference) = (
+                load(&program, &instruction_set),
+                load(&reference, &instruction_set),
+            );
+            let verdict = Checker::new(&reference, &candidate, &instruction_set)
+                .with_max_inputs(max_inputs)
+                .with_random_tests(random_tests)
+                .with_seed(seed)
+                .with_step_limit(step_limit)
+                .check();
+            match verdict.counterexample {
+                Some(counterexample) => {
+                    println!("programs differ on inputs {:?}", counterexample.inputs);
+                    println!("  reference: {}", describe(&counterexample.reference));
+                    println!("  program:   {}", describe(&counterexample.candidate));
+                    std::process::exit(1);
+                }
+                None => println!("no difference found in {} tests", verdict.tests),
+            }
+        }
+        Command::Superopt {
+            fragment,
+            live_in,
+            live_out,
+            accumulator_in,
+            accumulator_out,
+        } => {
+            let liveness = Liveness {
+                live_in,
+                live_out,
+                accumulator_in,
+                accumulator_out,
+            };
+            superopt(&fragment, &liveness, &instruction_set);
+        }
+        Command::Dap => dap::run(
+            std::io::BufReader::new(std::io::stdin()),
+            std::io::stdout(),
+            &instruction_set,
+        ),
         Command::Show {
             show_source,
             show_tokenized,
             show_ast,
+            show_expanded,
             show_assembled,
+            show_disassembled,
             show_all,
         } => {
+            let source = read_file_arg(args.file_path);
+            let tokens = parse(&source);
+            let Program {
+                ast,
+                expanded,
+                assembly,
+            } = build(&source, &instruction_set);
             if show_source || show_all {
-                println!("--- Source ---\n{}\n--- END ---", file_content);
+                println!("--- Source ---\n{}\n--- END ---", source);
             }
             if show_tokenized || show_all {
                 println!("--- Tokenized ---\n{:?}\n--- END ---", tokens);
@@ -68,10 +621,175 @@ fn main() {
             if show_ast || show_all {
                 println!("--- Abstract Syntax Tree (AST) ---\n{:?}\n--- END ---", ast);
             }
+            if show_expanded || show_all {
+                println!(
+                    "--- Expanded ---\n{}--- END ---",
+                    ast_to_source(&expanded, &instruction_set)
+                );
+            }
             if show_assembled || show_all {
-                println!("--- Assembled ---\n{:?}\n--- END ---", assembled);
+                println!("--- Assembled ---\n{:?}\n--- END ---", assembly.memory);
+            }
+            if show_disassembled || show_all {
+                println!("--- Disassembled ---");
+                for (addr, word) in assembly.memory.iter().enumerate() {
+                    let instruction = assembly
+                        .disassemble(*word, &instruction_set)
+                        .unwrap_or_else(|| format!("DAT {}", word));
+                    println!(
+                        "{:0addr_width$} {:0word_width$} {}",
+                        addr,
+                        word,
+                        instruction,
+                        addr_width = (config.memory_size() - 1).to_string().len(),
+                        word_width = config.word_digits() as usize,
+                    );
+                }
+                println!("--- END ---");
+            }
+        }
+        Command::Assemble { listing: true } => {
+            let source = read_file_arg(args.file_path);
+            let program = build(&source, &instruction_set);
+            print!(
+                "{}",
+                listing(&program.expanded, &program.assembly, &instruction_set)
+            );
+        }
+        Command::Assemble { listing: false } => {
+            let source = read_file_arg(args.file_path);
+            let assembly = build(&source, &instruction_set).assembly;
+            for word in &assembly.memory[..assembly.program_size()] {
+                println!("{:0width$}", word, width = config.word_digits() as usize);
+            }
+        }
+        Command::Run {
+            trace,
+            input,
+            input_file,
+            non_interactive,
+            quiet,
+            stats,
+            profile,
+        } => {
+            let source = read_file_arg(args.file_path);
+            let assembly = build(&source, &instruction_set).assembly;
+            let inputs = input.or_else(|| input_file.map(|v| read_inputs(&v)));
+            let interactive = !non_interactive && std::io::stdin().is_terminal();
+            let mut profiler = Profiler::default();
+            let mut memory = assembly.memory.clone();
+            let mut runtime = CommandLine::load_assembled(&mut memory, &instruction_set)
+                .with_trace(trace)
+                .with_assembly(&assembly)
+                .with_interactive(interactive)
+                .with_quiet(quiet);
+            if let Some(inputs) = inputs {
+                runtime = runtime.with_inputs(inputs);
+            }
+            if profile {
+                runtime = runtime.with_hook(&mut profiler);
+            }
+            let result = runtime.run();
+            if stats {
+                eprintln!("--- Stats ---\n{}\n--- END ---", runtime.machine().stats());
+            }
+            drop(runtime);
+            if profile {
+                eprint!(
+                    "--- Profile ---\n{}--- END ---\n",
+                    profiler.annotate(&source, &assembly)
+                );
+            }
+            if let Err(err) = result {
+                eprintln!("runtime error: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+        Command::Tui { input, input_file } => {
+            let source = read_file_arg(args.file_path);
+            let assembly = build(&source, &instruction_set).assembly;
+            let inputs = input
+                .or_else(|| input_file.map(|v| read_inputs(&v)))
+                .unwrap_or_default();
+            if let Err(err) = tui::run(&source, &assembly, &instruction_set, inputs) {
+                eprintln!("terminal error: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Command::Opt => {
+            let source = read_file_arg(args.file_path);
+            let (optimised, report) = optimise(build(&source, &instruction_set).ast);
+            // the optimised program has to still assemble
+            assemble(
+                &expand_pseudo_instructions(optimised.clone()),
+                &instruction_set,
+            );
+            print!("{}", ast_to_source(&optimised, &instruction_set));
+            eprintln!("--- Optimised ---\n{}\n--- END ---", report);
+        }
+        Command::Symex {
+            assertions,
+            max_paths,
+            max_inputs,
+            step_limit,
+        } => {
+            let source = read_file_arg(args.file_path);
+            let assembly = build(&source, &instruction_set).assembly;
+            let mut explorer = Explorer::new(&assembly.memory, &instruction_set)
+                .with_max_paths(max_paths)
+                .with_max_inputs(max_inputs)
+                .with_step_limit(step_limit);
+            for text in &assertions {
+                let assertion = Assertion::parse(text, &assembly).unwrap_or_else(|err| {
+                    eprintln!("invalid assertion '{}': {}", text, err);
+                    std::process::exit(1);
+                });
+                explorer = explorer.with_assertion(assertion);
+            }
+            let report = explorer.explore();
+            for finding in &report.findings {
+                println!(
+                    "{}: inputs {:?} outputs {:?} ({} steps)",
+                    finding.outcome, finding.inputs, finding.outputs, finding.steps
+                );
+            }
+            println!(
+                "{} paths explored, {} abandoned{}",
+                report.paths,
+                report.abandoned,
+                if report.incomplete {
+                    ", stopped at the path limit"
+                } else {
+                    ""
+                }
+            );
+            if report.findings.iter().any(|v| v.outcome != Outcome::Halted) {
+                std::process::exit(1);
+            }
+        }
+        Command::Fuzz {
+            iterations,
+            max_inputs,
+            seed,
+            step_limit,
+        } => {
+            let source = read_file_arg(args.file_path);
+            let assembly = build(&source, &instruction_set).assembly;
+            let failures = fuzz_inputs(
+                &assembly,
+                &instruction_set,
+                iterations,
+                max_inputs,
+                step_limit,
+                seed,
+            );
+            for failure in &failures {
+                println!("{:?}: inputs {:?}", failure.error, failure.inputs);
+            }
+            println!("{} runs, {} errors found", iterations, failures.len());
+            if !failures.is_empty() {
+                std::process::exit(1);
             }
         }
-        Command::Run => CommandLine::load_assembled(&mut assembled).run(),
     }
 }
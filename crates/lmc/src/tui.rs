@@ -0,0 +1,419 @@
+//! A terminal view of the mailboxes, registers and source of a running program.
+use std::collections::BTreeSet;
+use std::io;
+use std::time::Duration;
+
+use lmc_core::assembler::Assembly;
+use lmc_core::instruction_set::InstructionSet;
+use lmc_core::runtime::{Headless, Output, Runtime, RuntimeError};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, List, ListItem, ListState, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::parse_typed_inputs;
+
+/// Instructions per second when running
+const SPEEDS: [u32; 9] = [1, 2, 5, 10, 25, 50, 100, 250, 1000];
+/// Shortest time between redraws while running
+const FRAME: Duration = Duration::from_millis(20);
+const GRID_COLUMNS: usize = 10;
+
+/// Settings kept across a reset
+struct Controls {
+    /// Source lines with a breakpoint
+    breakpoints: BTreeSet<usize>,
+    /// Index into [`SPEEDS`]
+    speed: usize,
+    /// Selected source line, from zero
+    cursor: usize,
+}
+
+enum Exit {
+    Quit,
+    Reset,
+}
+
+struct App<'a> {
+    runtime: Headless<'a>,
+    assembly: &'a Assembly,
+    instruction_set: &'a InstructionSet,
+    source: Vec<&'a str>,
+    controls: &'a mut Controls,
+    running: bool,
+    finished: bool,
+    status: String,
+    /// Input being typed after pressing `i`
+    typing: Option<String>,
+}
+
+/// Show the machine running `assembly` until the user quits
+pub fn run(
+    source: &str,
+    assembly: &Assembly,
+    instruction_set: &InstructionSet,
+    inputs: Vec<usize>,
+) -> io::Result<()> {
+    let mut controls = Controls {
+        breakpoints: BTreeSet::new(),
+        speed: 3,
+        cursor: 0,
+    };
+    let mut terminal = ratatui::init();
+    let result = loop {
+        let mut memory = assembly.memory.clone();
+        let mut app = App::new(
+            Headless::load_assembled(&mut memory, instruction_set).with_inputs(inputs.clone()),
+            source,
+            assembly,
+            instruction_set,
+            &mut controls,
+        );
+        match app.event_loop(&mut terminal) {
+            Ok(Exit::Reset) => continue,
+            Ok(Exit::Quit) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+impl<'a> App<'a> {
+    fn new(
+        runtime: Headless<'a>,
+        source: &'a str,
+        assembly: &'a Assembly,
+        instruction_set: &'a InstructionSet,
+        controls: &'a mut Controls,
+    ) -> Self {
+        Self {
+            runtime,
+            assembly,
+            instruction_set,
+            source: source.lines().collect(),
+            controls,
+            running: false,
+            finished: false,
+            status: "ready".to_string(),
+            typing: None,
+        }
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<Exit> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let speed = SPEEDS[self.controls.speed];
+            let tick = (Duration::from_secs(1) / speed).max(FRAME);
+            let timeout = match self.running {
+                true => tick,
+                false => Duration::from_secs(60),
+            };
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        if let Some(exit) = self.handle_key(key) {
+                            return Ok(exit);
+                        }
+                    }
+                }
+                continue;
+            }
+            let steps = (tick.as_secs_f64() * speed as f64).round().max(1.0) as usize;
+            for _ in 0..steps {
+                if !self.running {
+                    break;
+                }
+                self.step();
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        if self.finished {
+            return;
+        }
+        match self.runtime.step() {
+            Ok(true) => {
+                self.finished = true;
+                self.running = false;
+                self.status = "halted".to_string();
+            }
+            Ok(false) => {
+                let pc = self.runtime.machine().program_counter();
+                let line = self.assembly.line_of(pc);
+                if let Some(line) = line.filter(|v| self.controls.breakpoints.contains(v)) {
+                    if self.running {
+                        self.running = false;
+                        self.status = format!("breakpoint on line {}", line);
+                    }
+                }
+            }
+            Err(RuntimeError::InputExhausted { .. }) => {
+                self.running = false;
+                self.status = "waiting for input, press i".to_string();
+            }
+            Err(err) => {
+                self.finished = true;
+                self.running = false;
+                self.status = format!("runtime error: {:?}", err);
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Exit> {
+        if let Some(typing) = &mut self.typing {
+            match key.code {
+                KeyCode::Char(c) => typing.push(c),
+                KeyCode::Backspace => {
+                    typing.pop();
+                }
+                KeyCode::Enter => {
+                    let inputs = parse_typed_inputs(typing);
+                    self.runtime.push_inputs(inputs);
+                    self.typing = None;
+                }
+                KeyCode::Esc => self.typing = None,
+                _ => {}
+            }
+            return None;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Exit::Quit),
+            KeyCode::Char('x') => return Some(Exit::Reset),
+            KeyCode::Char('s') | KeyCode::Char(' ') => {
+                self.running = false;
+                self.step();
+            }
+            KeyCode::Char('r') => {
+                self.running = !self.running && !self.finished;
+                if self.running {
+                    self.status = "running".to_string();
+                }
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.controls.speed = (self.controls.speed + 1).min(SPEEDS.len() - 1)
+            }
+            KeyCode::Char('-') => self.controls.speed = self.controls.speed.saturating_sub(1),
+            KeyCode::Up => self.controls.cursor = self.controls.cursor.saturating_sub(1),
+            KeyCode::Down => {
+                self.controls.cursor =
+                    (self.controls.cursor + 1).min(self.source.len().saturating_sub(1))
+            }
+            KeyCode::Char('b') => {
+                let line = self.controls.cursor + 1;
+                if !self.controls.breakpoints.remove(&line) {
+                    self.controls.breakpoints.insert(line);
+                }
+            }
+            KeyCode::Char('i') => self.typing = Some(String::new()),
+            _ => {}
+        }
+        None
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let machine = self.runtime.machine();
+        let config = self.instruction_set.config();
+        let addr_width = (config.memory_size() - 1).to_string().len();
+        let word_width = config.word_digits() as usize;
+        let pc = machine.program_counter();
+        let rows = machine.memory().len().div_ceil(GRID_COLUMNS);
+
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let grid_width = addr_width + GRID_COLUMNS * (word_width + 1) + 2;
+        let [left, source] =
+            Layout::horizontal([Constraint::Length(grid_width as u16), Constraint::Min(20)])
+                .areas(main);
+        let [grid, registers, io] = Layout::vertical([
+            Constraint::Length(rows as u16 + 3),
+            Constraint::Length(8),
+            Constraint::Min(4),
+        ])
+        .areas(left);
+
+        let header = Row::new(
+            std::iter::once(String::new())
+                .chain((0..GRID_COLUMNS).map(|v| format!("{:>word_width$}", v))),
+        )
+        .dark_gray();
+        let mailboxes = machine
+            .memory()
+            .chunks(GRID_COLUMNS)
+            .enumerate()
+            .map(|(row, words)| {
+                let cells = words.iter().enumerate().map(|(column, word)| {
+                    let address = row * GRID_COLUMNS + column;
+                    let breakpoint = self
+                        .assembly
+                        .line_of(address)
+                        .is_some_and(|v| self.controls.breakpoints.contains(&v));
+                    let style = if address == pc {
+                        Style::new().black().on_yellow()
+                    } else if breakpoint {
+                        Style::new().red()
+                    } else if address >= self.assembly.program_size() && *word == 0 {
+                        Style::new().dark_gray()
+                    } else {
+                        Style::new()
+                    };
+                    Cell::from(format!("{:0word_width$}", word)).style(style)
+                });
+                let label = Cell::from(format!("{:0addr_width$}", row * GRID_COLUMNS)).dark_gray();
+                Row::new(std::iter::once(label).chain(cells))
+            });
+        let widths = std::iter::once(Constraint::Length(addr_width as u16))
+            .chain((0..GRID_COLUMNS).map(|_| Constraint::Length(word_width as u16)));
+        frame.render_widget(
+            Table::new(mailboxes, widths)
+                .header(header)
+                .block(Block::bordered().title(" Mailboxes ")),
+            grid,
+        );
+
+        let mut lines = vec![
+            Line::from(format!("PC    {:0addr_width$}", pc)),
+            Line::from(format!("ACC   {:0word_width$}", machine.accumulator())),
+            Line::from(format!(
+                "FLAG  {}",
+                match machine.negative_flag() {
+                    true => "negative",
+                    false => "-",
+                }
+            )),
+        ];
+        if !machine.return_stack().is_empty() {
+            lines.push(Line::from(format!("STACK {:?}", machine.return_stack())));
+        }
+        lines.push(Line::from(format!("steps {}", self.runtime.steps())));
+        lines.push(Line::from(format!(
+            "speed {}/s",
+            SPEEDS[self.controls.speed]
+        )));
+        lines.push(Line::from(self.status.clone()).bold());
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Machine ")),
+            registers,
+        );
+
+        let inputs: Vec<String> = self.runtime.inputs().map(|v| v.to_string()).collect();
+        let input = match &self.typing {
+            Some(typing) => Line::from(format!("in>  {}_", typing)).yellow(),
+            None => Line::from(format!("in   {}", inputs.join(" "))),
+        };
+        let outputs: String = self
+            .runtime
+            .outputs()
+            .iter()
+            .map(|v| match v {
+                Output::Number(value) => format!("{} ", value),
+                Output::Character(value) => value.to_string(),
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(vec![input, Line::from(format!("out  {}", outputs))])
+                .block(Block::bordered().title(" I/O ")),
+            io,
+        );
+
+        let current = self.assembly.line_of(pc);
+        let items = self.source.iter().enumerate().map(|(index, text)| {
+            let line = index + 1;
+            let marker = match self.controls.breakpoints.contains(&line) {
+                true => "●",
+                false => " ",
+            };
+            let item = ListItem::new(format!("{}{:>4} {}", marker, line, text));
+            match Some(line) == current {
+                true => item.black().on_yellow(),
+                false => item,
+            }
+        });
+        let mut state = ListState::default().with_selected(Some(self.controls.cursor));
+        frame.render_stateful_widget(
+            List::new(items)
+                .highlight_symbol("> ")
+                .highlight_style(Style::new().bold())
+                .block(Block::bordered().title(" Source ")),
+            source,
+            &mut state,
+        );
+
+        frame.render_widget(
+            Paragraph::new(
+                "s step  r run/pause  +/- speed  x reset  ↑↓ b breakpoint  i input  q quit",
+            )
+            .dark_gray(),
+            footer,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use lmc_core::assembler::assemble_from_ast;
+    use lmc_core::ast::parsed_to_ast;
+    use lmc_core::grammar::pass_program;
+    use lmc_core::instruction_set::InstructionSet;
+    use lmc_core::runtime::{Headless, Runtime};
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::{KeyCode, KeyEvent};
+    use ratatui::Terminal;
+
+    use super::{App, Controls};
+
+    const SOURCE: &str = "INP
+OUT
+BRA end
+end: HLT
+";
+
+    #[test]
+    fn test_app() {
+        let instruction_set = InstructionSet::standard();
+        let mut parsed = pass_program(SOURCE).unwrap();
+        let ast = parsed_to_ast(&mut parsed, &instruction_set);
+        let assembly = assemble_from_ast(&ast, &instruction_set).unwrap();
+        let mut memory = assembly.memory.clone();
+        let mut controls = Controls {
+            breakpoints: BTreeSet::from([3]),
+            speed: 0,
+            cursor: 0,
+        };
+        let mut app = App::new(
+            Headless::load_assembled(&mut memory, &instruction_set),
+            SOURCE,
+            &assembly,
+            &instruction_set,
+            &mut controls,
+        );
+        app.step();
+        assert_eq!(app.status, "waiting for input, press i");
+        for key in [KeyCode::Char('i'), KeyCode::Char('7'), KeyCode::Enter] {
+            app.handle_key(KeyEvent::from(key));
+        }
+        app.running = true;
+        app.step();
+        app.step();
+        assert!(!app.running);
+        assert_eq!(app.status, "breakpoint on line 3");
+
+        let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let screen: Vec<String> = buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|v| v.iter().map(|v| v.symbol()).collect())
+            .collect();
+        assert!(screen[2].contains("00 901 902 603 000 000"));
+        assert!(screen.iter().any(|v| v.contains("ACC   007")));
+        assert!(screen.iter().any(|v| v.contains("out  7")));
+        assert!(screen.iter().any(|v| v.contains("●   3 BRA end")));
+    }
+}
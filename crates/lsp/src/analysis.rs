@@ -0,0 +1,416 @@
+//! Everything the server knows about a single document.
+use lmc_core::assembler::{assemble_from_ast, Assembly, SymbolKind};
+use lmc_core::ast::{ast_to_source, try_parsed_to_ast};
+use lmc_core::expand::expand_pseudo_instructions;
+use lmc_core::grammar::{pass_program, Rule};
+use lmc_core::instruction_set::InstructionSet;
+use lsp_types::{
+    CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, Position, Range, TextEdit,
+};
+use pest::error::LineColLocation;
+use pest::iterators::Pair;
+use pest::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    LabelDefinition,
+    LabelReference,
+    Mnemonic,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub name: String,
+    pub range: Range,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    pub tokens: Vec<Token>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Only present when the document assembled without errors
+    pub assembly: Option<Assembly>,
+    /// The whole document reformatted, when it parsed
+    pub formatted: Option<String>,
+    /// In UTF-16 code units, as are all columns
+    line_lengths: Vec<u32>,
+}
+
+/// Convert pest's line and column in characters, both from 1, on `line_text`
+fn position(line_text: &str, (line, column): (usize, usize)) -> Position {
+    let character: usize = line_text
+        .chars()
+        .take(column - 1)
+        .map(char::len_utf16)
+        .sum();
+    Position::new(line as u32 - 1, character as u32)
+}
+
+fn span_range(span: Span) -> Range {
+    let (start, end) = (span.start_pos(), span.end_pos());
+    Range::new(
+        position(start.line_of(), start.line_col()),
+        position(end.line_of(), end.line_col()),
+    )
+}
+
+fn collect_tokens(pair: Pair<Rule>, tokens: &mut Vec<Token>) {
+    let text = pair.as_str();
+    let kind = match pair.as_rule() {
+        Rule::labelName => Some(TokenKind::LabelDefinition),
+        Rule::instructionName => Some(TokenKind::Mnemonic),
+        Rule::memoryLocation
+            if text
+                .trim_start_matches('@')
+                .starts_with(|c: char| c.is_alphabetic() || c == '_') =>
+        {
+            Some(TokenKind::LabelReference)
+        }
+        _ => None,
+    };
+    match kind {
+        Some(kind) => {
+            let mut range = span_range(pair.as_span());
+            if text.starts_with('@') {
+                range.start.character += 1;
+            }
+            tokens.push(Token {
+                kind,
+                name: text.trim_start_matches('@').to_string(),
+                range,
+            })
+        }
+        None => pair
+            .into_inner()
+            .for_each(|inner| collect_tokens(inner, tokens)),
+    }
+}
+
+fn contains(range: &Range, position: &Position) -> bool {
+    range.start.line == position.line
+        && range.start.character <= position.character
+        && position.character <= range.end.character
+}
+
+impl Analysis {
+    pub fn new(text: &str, instruction_set: &InstructionSet) -> Self {
+        let mut analysis = Analysis {
+            line_lengths: text
+                .lines()
+                .map(|v| v.encode_utf16().count() as u32)
+                .collect(),
+            ..Default::default()
+        };
+        let parsed = match pass_program(text) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                let (start, end) = match err.line_col {
+                    LineColLocation::Pos(pos) => (pos, (pos.0, pos.1 + 1)),
+                    LineColLocation::Span(start, end) => (start, end),
+                };
+                let line_text = |line: usize| text.lines().nth(line - 1).unwrap_or_default();
+                analysis.push_diagnostic(
+                    Range::new(
+                        position(line_text(start.0), start),
+                        position(line_text(end.0), end),
+                    ),
+                    err.variant.message().to_string(),
+                );
+                return analysis;
+            }
+        };
+        for pair in parsed.clone() {
+            collect_tokens(pair, &mut analysis.tokens);
+        }
+        let ast = match try_parsed_to_ast(&mut parsed.clone(), instruction_set) {
+            Ok(ast) => ast,
+            Err(err) => {
+                let line = match &err {
+                    lmc_core::ast::AstError::UnknownInstruction { line, .. }
+                    | lmc_core::ast::AstError::UnexpectedOperand { line, .. }
+                    | lmc_core::ast::AstError::MissingOperand { line, .. } => *line,
+                };
+                analysis.push_diagnostic(analysis.line_range(line), err.to_string());
+                return analysis;
+            }
+        };
+        // comments after the last statement aren't part of the AST
+        let trailing_comments: String = parsed
+            .filter(|v| v.as_rule() == Rule::comment)
+            .map(|v| format!("; {}\n", v.as_str().trim_start_matches(';').trim()))
+            .collect();
+        analysis.formatted = Some(ast_to_source(&ast, instruction_set) + &trailing_comments);
+        let expanded = expand_pseudo_instructions(ast);
+        match assemble_from_ast(&expanded, instruction_set) {
+            Ok(assembly) => analysis.assembly = Some(assembly),
+            Err(err) => {
+                let range = analysis.line_range(err.line().unwrap_or(1));
+                analysis.push_diagnostic(range, format!("{:?}", err));
+            }
+        }
+        analysis
+    }
+
+    fn line_range(&self, line: usize) -> Range {
+        let length = self.line_lengths.get(line - 1).copied().unwrap_or(0);
+        let line = line as u32 - 1;
+        Range::new(Position::new(line, 0), Position::new(line, length))
+    }
+
+    fn push_diagnostic(&mut self, range: Range, message: String) {
+        self.diagnostics.push(Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("lmc".to_string()),
+            message,
+            ..Default::default()
+        });
+    }
+
+    pub fn token_at(&self, position: &Position) -> Option<&Token> {
+        self.tokens.iter().find(|v| contains(&v.range, position))
+    }
+
+    /// The label under the cursor, from either its definition or a reference
+    pub fn label_at(&self, position: &Position) -> Option<&str> {
+        self.token_at(position)
+            .filter(|v| v.kind != TokenKind::Mnemonic)
+            .map(|v| v.name.as_str())
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Token> {
+        self.tokens
+            .iter()
+            .find(|v| v.kind == TokenKind::LabelDefinition && v.name == name)
+    }
+
+    pub fn references(&self, name: &str, include_declaration: bool) -> Vec<&Token> {
+        self.tokens
+            .iter()
+            .filter(|v| v.name == name)
+            .filter(|v| match v.kind {
+                TokenKind::LabelDefinition => include_declaration,
+                TokenKind::LabelReference => true,
+                TokenKind::Mnemonic => false,
+            })
+            .collect()
+    }
+
+    /// Markdown describing the resolved address and machine word under the cursor
+    pub fn hover(&self, position: &Position, instruction_set: &InstructionSet) -> Option<String> {
+        let assembly = self.assembly.as_ref()?;
+        let token = self.token_at(position)?;
+        let config = instruction_set.config();
+        let cell = |address: usize| {
+            let word = assembly.memory[address];
+            format!(
+                "{:0addr_width$}  {:0word_width$}  {}",
+                address,
+                word,
+                assembly
+                    .disassemble(word, instruction_set)
+                    .unwrap_or_else(|| format!("DAT {}", word)),
+                addr_width = (config.memory_size() - 1).to_string().len(),
+                word_width = config.word_digits() as usize,
+            )
+        };
+        let (title, addresses) = match token.kind {
+            TokenKind::Mnemonic => {
+                let line = token.range.start.line as usize + 1;
+                let addresses: Vec<usize> = (0..assembly.program_size())
+                    .filter(|v| assembly.line_of(*v) == Some(line))
+                    .collect();
+                (String::new(), addresses)
+            }
+            _ => {
+                let symbol = assembly.symbols.get(&token.name)?;
+                let kind = match symbol.kind {
+                    SymbolKind::Code => "code",
+                    SymbolKind::Data => "data",
+                };
+                (
+                    format!("**{}** {} label\n\n", token.name, kind),
+                    vec![symbol.address],
+                )
+            }
+        };
+        let cells: Vec<String> = addresses.into_iter().map(cell).collect();
+        Some(format!("{}```\n{}\n```", title, cells.join("\n")))
+    }
+
+    pub fn completions(&self, instruction_set: &InstructionSet) -> Vec<CompletionItem> {
+        let mut mnemonics: Vec<&str> = instruction_set
+            .definitions()
+            .iter()
+            .map(|v| v.mnemonic)
+            .collect();
+        mnemonics.sort();
+        mnemonics.dedup();
+        let mnemonics = mnemonics.into_iter().map(|v| CompletionItem {
+            label: v.to_string(),
+            kind: Some(CompletionItemKind::KEYWORD),
+            ..Default::default()
+        });
+        let labels = self
+            .tokens
+            .iter()
+            .filter(|v| v.kind == TokenKind::LabelDefinition)
+            .map(|v| CompletionItem {
+                label: v.name.clone(),
+                kind: Some(CompletionItemKind::VARIABLE),
+                ..Default::default()
+            });
+        mnemonics.chain(labels).collect()
+    }
+
+    /// Edits renaming the label under the cursor everywhere it is used
+    pub fn rename(&self, position: &Position, new_name: &str) -> Result<Vec<TextEdit>, String> {
+        let name = self
+            .label_at(position)
+            .ok_or_else(|| "no label at this position".to_string())?;
+        let valid = new_name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && new_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("'{}' is not a valid label name", new_name));
+        }
+        if self.definition(new_name).is_some() {
+            return Err(format!("label '{}' is already defined", new_name));
+        }
+        Ok(self
+            .references(name, true)
+            .into_iter()
+            .map(|v| TextEdit::new(v.range, new_name.to_string()))
+            .collect())
+    }
+
+    /// A single edit replacing the document with its formatted source
+    pub fn format(&self) -> Option<Vec<TextEdit>> {
+        let formatted = self.formatted.as_ref()?;
+        let end = Position::new(self.line_lengths.len() as u32 + 1, 0);
+        Some(vec![TextEdit::new(
+            Range::new(Position::new(0, 0), end),
+            formatted.clone(),
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lmc_core::instruction_set::InstructionSet;
+    use lsp_types::{Position, Range};
+
+    use super::{Analysis, TokenKind};
+
+    const SOURCE: &str = "start: INP
+    BRZ end
+    STA @ptr
+    BRA start
+end: HLT
+ptr: DAT 9
+";
+
+    #[test]
+    fn test_tokens() {
+        let instruction_set = InstructionSet::standard().with_indirect();
+        let analysis = Analysis::new(SOURCE, &instruction_set);
+        assert!(analysis.diagnostics.is_empty());
+        let token = analysis.token_at(&Position::new(2, 10)).unwrap();
+        assert_eq!(token.kind, TokenKind::LabelReference);
+        assert_eq!(token.name, "ptr");
+        assert_eq!(
+            token.range,
+            Range::new(Position::new(2, 9), Position::new(2, 12))
+        );
+        assert_eq!(
+            analysis.definition("ptr").unwrap().range,
+            Range::new(Position::new(5, 0), Position::new(5, 3))
+        );
+        assert_eq!(analysis.references("start", true).len(), 2);
+        assert_eq!(analysis.references("start", false).len(), 1);
+    }
+
+    #[test]
+    fn test_hover() {
+        let instruction_set = InstructionSet::standard().with_indirect();
+        let analysis = Analysis::new(SOURCE, &instruction_set);
+        assert_eq!(
+            analysis
+                .hover(&Position::new(1, 9), &instruction_set)
+                .unwrap(),
+            "**end** code label\n\n```\n04  000  HLT\n```"
+        );
+        assert_eq!(
+            analysis
+                .hover(&Position::new(2, 5), &instruction_set)
+                .unwrap(),
+            "```\n02  005  STA @ptr\n```"
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let instruction_set = InstructionSet::standard();
+        let analysis = Analysis::new("INP\nFOO 1\n", &instruction_set);
+        assert_eq!(
+            analysis.diagnostics[0].message,
+            "unknown instruction type found: 'FOO' on line 2"
+        );
+        assert_eq!(analysis.diagnostics[0].range.start, Position::new(1, 0));
+
+        let analysis = Analysis::new("INP\n  BRA nowhere\n", &instruction_set);
+        assert_eq!(
+            analysis.diagnostics[0].range,
+            Range::new(Position::new(1, 0), Position::new(1, 13))
+        );
+        assert!(analysis.diagnostics[0]
+            .message
+            .starts_with("LabelNotDefined"));
+
+        let analysis = Analysis::new("INP\nOUT 1 2\n", &instruction_set);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(analysis.diagnostics[0].range.start.line, 1);
+
+        // columns count UTF-16 code units, which the emoji is two of
+        let analysis = Analysis::new("INP\n  BRA nowhere ; \u{1f600}\n", &instruction_set);
+        assert_eq!(
+            analysis.diagnostics[0].range,
+            Range::new(Position::new(1, 0), Position::new(1, 18))
+        );
+        let analysis = Analysis::new("INP\n\u{1f600}\n", &instruction_set);
+        assert_eq!(
+            analysis.diagnostics[0].range,
+            Range::new(Position::new(1, 0), Position::new(1, 2))
+        );
+    }
+
+    #[test]
+    fn test_rename_and_format() {
+        let instruction_set = InstructionSet::standard().with_indirect();
+        let analysis = Analysis::new(SOURCE, &instruction_set);
+        let edits = analysis.rename(&Position::new(5, 1), "pointer").unwrap();
+        assert_eq!(edits.len(), 2);
+        assert!(analysis.rename(&Position::new(5, 1), "end").is_err());
+        assert!(analysis.rename(&Position::new(5, 1), "1a").is_err());
+        assert!(analysis.rename(&Position::new(5, 1), "a-b").is_err());
+        assert!(analysis.rename(&Position::new(5, 1), "ptr_2").is_ok());
+
+        let analysis = Analysis::new("a:INP\n  out\n; done\n", &instruction_set);
+        assert_eq!(
+            analysis.formatted.as_deref(),
+            Some("a: INP\n    OUT\n; done\n")
+        );
+
+        // end-of-line comments stay on their line, and formatting again changes nothing
+        let source = "; first\nstart: ; begin\n; read it\nINP ; one\n  ; two\nOUT ; echo\n; done\n";
+        let formatted = Analysis::new(source, &instruction_set).formatted.unwrap();
+        assert_eq!(
+            formatted,
+            "; first\n; begin\n; read it\nstart: INP ; one\n    ; two\n    OUT ; echo\n; done\n"
+        );
+        let analysis = Analysis::new(&formatted, &instruction_set);
+        assert_eq!(analysis.formatted.as_deref(), Some(formatted.as_str()));
+    }
+}
@@ -0,0 +1,55 @@
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use lmc_core::instruction_set::{InstructionSet, MachineConfig};
+use lsp_server::Connection;
+
+mod analysis;
+mod server;
+
+/// Language server for LMC code, speaking LSP over stdio
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Enable the character I/O instructions (OTC, INA)
+    #[arg(long = "extended-io")]
+    pub extended_io: bool,
+    /// Enable indirect loads and stores (LDA @ptr, STA @ptr)
+    #[arg(long = "indirect", conflicts_with = "subroutines")]
+    pub indirect: bool,
+    /// Enable subroutine calls with a return stack (CAL label, RET)
+    #[arg(long = "subroutines")]
+    pub subroutines: bool,
+    /// Number of mailboxes in memory
+    #[arg(long = "memory-size", default_value_t = 100)]
+    pub memory_size: usize,
+    /// Number of decimal digits in each mailbox
+    #[arg(long = "word-digits", default_value_t = 3)]
+    pub word_digits: u32,
+    /// Accepted for clients which always pass it, stdio is the only transport
+    #[arg(long = "stdio")]
+    pub stdio: bool,
+}
+
+fn main() -> Result<(), server::ServerError> {
+    let args = Args::parse();
+    let config = MachineConfig::new(args.memory_size, args.word_digits).unwrap_or_else(|err| {
+        Args::command()
+            .error(ErrorKind::ValueValidation, err)
+            .exit()
+    });
+    let mut instruction_set = InstructionSet::standard().with_config(config);
+    if args.extended_io {
+        instruction_set = instruction_set.with_extended_io();
+    }
+    if args.indirect {
+        instruction_set = instruction_set.with_indirect();
+    }
+    if args.subroutines {
+        instruction_set = instruction_set.with_subroutines();
+    }
+
+    let (connection, io_threads) = Connection::stdio();
+    server::run(&connection, &instruction_set)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
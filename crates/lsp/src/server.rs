@@ -0,0 +1,383 @@
+//! The language server loop, answering requests from the open documents.
+use std::collections::HashMap;
+use std::error::Error;
+
+use lmc_core::instruction_set::InstructionSet;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationTrait, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, Formatting, GotoDefinition, HoverRequest, References, Rename,
+    Request as RequestTrait,
+};
+use lsp_types::{
+    CompletionOptions, GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability,
+    Location, MarkupContent, MarkupKind, OneOf, PublishDiagnosticsParams, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url, WorkspaceEdit,
+};
+use serde::Serialize;
+
+use crate::analysis::Analysis;
+
+pub type ServerError = Box<dyn Error + Sync + Send>;
+
+pub fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        rename_provider: Some(OneOf::Left(true)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+struct Server<'a> {
+    connection: &'a Connection,
+    instruction_set: &'a InstructionSet,
+    documents: HashMap<Url, String>,
+}
+
+/// Serve requests on `connection` until the client asks to shut down
+pub fn run(connection: &Connection, instruction_set: &InstructionSet) -> Result<(), ServerError> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut server = Server {
+        connection,
+        instruction_set,
+        documents: HashMap::new(),
+    };
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                server.request(request)?;
+            }
+            Message::Notification(notification) => server.notification(notification)?,
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Parameters of a notification, malformed ones are logged and the notification ignored
+fn notification_params<N: NotificationTrait>(notification: &Notification) -> Option<N::Params> {
+    serde_json::from_value(notification.params.clone())
+        .inspect_err(|err| {
+            eprintln!(
+                "ignoring {} with invalid params: {}",
+                notification.method, err
+            )
+        })
+        .ok()
+}
+
+impl Server<'_> {
+    fn analyse(&self, uri: &Url) -> Option<Analysis> {
+        self.documents
+            .get(uri)
+            .map(|text| Analysis::new(text, self.instruction_set))
+    }
+
+    fn publish_diagnostics(&self, uri: Url) -> Result<(), ServerError> {
+        let diagnostics = self
+            .analyse(&uri)
+            .map(|v| v.diagnostics)
+            .unwrap_or_default();
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+        self.connection.sender.send(notification.into())?;
+        Ok(())
+    }
+
+    fn notification(&mut self, notification: Notification) -> Result<(), ServerError> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let Some(params) = notification_params::<DidOpenTextDocument>(&notification) else {
+                    return Ok(());
+                };
+                let document = params.text_document;
+                self.documents.insert(document.uri.clone(), document.text);
+                self.publish_diagnostics(document.uri)
+            }
+            DidChangeTextDocument::METHOD => {
+                let Some(params) = notification_params::<DidChangeTextDocument>(&notification)
+                else {
+                    return Ok(());
+                };
+                let uri = params.text_document.uri;
+                // full sync, so the last change holds the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                self.publish_diagnostics(uri)
+            }
+            DidCloseTextDocument::METHOD => {
+                let Some(params) = notification_params::<DidCloseTextDocument>(&notification)
+                else {
+                    return Ok(());
+                };
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                self.publish_diagnostics(uri)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn respond<R: Serialize>(
+        &self,
+        response: Result<R, String>,
+        request: Request,
+    ) -> Result<(), ServerError> {
+        let response = match response {
+            Ok(result) => Response::new_ok(request.id, result),
+            Err(message) => Response::new_err(request.id, ErrorCode::RequestFailed as i32, message),
+        };
+        self.connection.sender.send(response.into())?;
+        Ok(())
+    }
+
+    /// Parameters of a request, replying with an error when they're malformed
+    fn request_params<R: RequestTrait>(
+        &self,
+        request: &Request,
+    ) -> Result<Option<R::Params>, ServerError> {
+        match serde_json::from_value(request.params.clone()) {
+            Ok(params) => Ok(Some(params)),
+            Err(err) => {
+                let response = Response::new_err(
+                    request.id.clone(),
+                    ErrorCode::InvalidParams as i32,
+                    err.to_string(),
+                );
+                self.connection.sender.send(response.into())?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn request(&mut self, request: Request) -> Result<(), ServerError> {
+        match request.method.as_str() {
+            GotoDefinition::METHOD => {
+                let Some(params) = self.request_params::<GotoDefinition>(&request)? else {
+                    return Ok(());
+                };
+                let position = params.text_document_position_params;
+                let uri = position.text_document.uri;
+                let result = self.analyse(&uri).and_then(|analysis| {
+                    let name = analysis.label_at(&position.position)?;
+                    let definition = analysis.definition(name)?;
+                    Some(GotoDefinitionResponse::Scalar(Location::new(
+                        uri.clone(),
+                        definition.range,
+                    )))
+                });
+                self.respond(Ok(result), request)
+            }
+            References::METHOD => {
+                let Some(params) = self.request_params::<References>(&request)? else {
+                    return Ok(());
+                };
+                let position = params.text_document_position;
+                let uri = position.text_document.uri;
+                let result = self.analyse(&uri).and_then(|analysis| {
+                    let name = analysis.label_at(&position.position)?;
+                    Some(
+                        analysis
+                            .references(name, params.context.include_declaration)
+                            .into_iter()
+                            .map(|v| Location::new(uri.clone(), v.range))
+                            .collect::<Vec<_>>(),
+                    )
+                });
+                self.respond(Ok(result), request)
+            }
+            HoverRequest::METHOD => {
+                let Some(params) = self.request_params::<HoverRequest>(&request)? else {
+                    return Ok(());
+                };
+                let position = params.text_document_position_params;
+                let result = self
+                    .analyse(&position.text_document.uri)
+                    .and_then(|v| v.hover(&position.position, self.instruction_set))
+                    .map(|value| Hover {
+                        contents: HoverContents::Markup(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value,
+                        }),
+                        range: None,
+                    });
+                self.respond(Ok(result), request)
+            }
+            Completion::METHOD => {
+                let Some(params) = self.request_params::<Completion>(&request)? else {
+                    return Ok(());
+                };
+                let uri = params.text_document_position.text_document.uri;
+                let result = self
+                    .analyse(&uri)
+                    .map(|v| v.completions(self.instruction_set));
+                self.respond(Ok(result), request)
+            }
+            Rename::METHOD => {
+                let Some(params) = self.request_params::<Rename>(&request)? else {
+                    return Ok(());
+                };
+                let position = params.text_document_position;
+                let uri = position.text_document.uri;
+                let result = match self.analyse(&uri) {
+                    Some(analysis) => analysis
+                        .rename(&position.position, &params.new_name)
+                        .map(|edits| WorkspaceEdit::new(HashMap::from([(uri.clone(), edits)]))),
+                    None => Err(format!("{} is not open", uri)),
+                };
+                self.respond(result, request)
+            }
+            Formatting::METHOD => {
+                let Some(params) = self.request_params::<Formatting>(&request)? else {
+                    return Ok(());
+                };
+                let result = self
+                    .analyse(&params.text_document.uri)
+                    .and_then(|v| v.format());
+                self.respond(Ok(result), request)
+            }
+            _ => {
+                let response = Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("unsupported method: {}", request.method),
+                );
+                self.connection.sender.send(response.into())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use lmc_core::instruction_set::InstructionSet;
+    use lsp_server::{Connection, ErrorCode, Message, Notification, Request, RequestId};
+    use lsp_types::notification::{
+        DidOpenTextDocument, Exit, Initialized, Notification as NotificationTrait,
+        PublishDiagnostics,
+    };
+    use lsp_types::request::{GotoDefinition, Initialize, Request as RequestTrait, Shutdown};
+    use serde_json::json;
+
+    use super::run;
+
+    fn request(id: i32, method: &str, params: serde_json::Value) -> Message {
+        Request::new(RequestId::from(id), method.to_string(), params).into()
+    }
+
+    fn notification(method: &str, params: serde_json::Value) -> Message {
+        Notification::new(method.to_string(), params).into()
+    }
+
+    #[test]
+    fn test_server() {
+        let (server, client) = Connection::memory();
+        let handle = thread::spawn(move || run(&server, &InstructionSet::standard()).unwrap());
+        let uri = "file:///a.lmc";
+
+        client
+            .sender
+            .send(request(
+                1,
+                Initialize::METHOD,
+                json!({ "capabilities": {} }),
+            ))
+            .unwrap();
+        let Message::Response(response) = client.receiver.recv().unwrap() else {
+            panic!("expected the initialize response");
+        };
+        assert!(response.result.unwrap()["capabilities"]["definitionProvider"] == true);
+        client
+            .sender
+            .send(notification(Initialized::METHOD, json!({})))
+            .unwrap();
+
+        client
+            .sender
+            .send(notification(
+                DidOpenTextDocument::METHOD,
+                json!({ "textDocument": {
+                    "uri": uri,
+                    "languageId": "lmc",
+                    "version": 1,
+                    "text": "INP\nBRA end\nBRA nowhere\nend: HLT\n",
+                }}),
+            ))
+            .unwrap();
+        let Message::Notification(published) = client.receiver.recv().unwrap() else {
+            panic!("expected diagnostics");
+        };
+        assert_eq!(published.method, PublishDiagnostics::METHOD);
+        let diagnostics = published.params["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 2);
+
+        client
+            .sender
+            .send(request(
+                2,
+                GotoDefinition::METHOD,
+                json!({
+                    "textDocument": { "uri": uri },
+                    "position": { "line": 1, "character": 5 },
+                }),
+            ))
+            .unwrap();
+        let Message::Response(response) = client.receiver.recv().unwrap() else {
+            panic!("expected the definition");
+        };
+        assert_eq!(
+            response.result.unwrap(),
+            json!({
+                "uri": uri,
+                "range": {
+                    "start": { "line": 3, "character": 0 },
+                    "end": { "line": 3, "character": 3 },
+                },
+            })
+        );
+
+        // malformed params are answered or ignored, and the server keeps going
+        client
+            .sender
+            .send(notification(DidOpenTextDocument::METHOD, json!({})))
+            .unwrap();
+        client
+            .sender
+            .send(request(3, GotoDefinition::METHOD, json!({ "position": 1 })))
+            .unwrap();
+        let Message::Response(response) = client.receiver.recv().unwrap() else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            response.error.unwrap().code,
+            ErrorCode::InvalidParams as i32
+        );
+
+        client
+            .sender
+            .send(request(4, Shutdown::METHOD, json!(null)))
+            .unwrap();
+        client.receiver.recv().unwrap();
+        client
+            .sender
+            .send(notification(Exit::METHOD, json!(null)))
+            .unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,17 @@
+//! Arbitrary memory images and inputs through the runtime.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lmc_core::fuzz::check_memory;
+use lmc_core::instruction_set::InstructionSet;
+
+fuzz_target!(|data: (Vec<usize>, Vec<usize>)| {
+    let instruction_set = InstructionSet::standard()
+        .with_extended_io()
+        .with_subroutines()
+        .with_indirect();
+    let (memory, inputs) = data;
+    let size = instruction_set.config().memory_size();
+    let memory = &memory[..memory.len().min(size)];
+    check_memory(memory, &inputs, &instruction_set);
+});
@@ -0,0 +1,14 @@
+//! Source text through the parser, assembler and runtime.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lmc_core::fuzz::check_source;
+use lmc_core::instruction_set::InstructionSet;
+
+fuzz_target!(|source: &str| {
+    let instruction_set = InstructionSet::standard()
+        .with_extended_io()
+        .with_subroutines()
+        .with_indirect();
+    check_source(source, &instruction_set);
+});
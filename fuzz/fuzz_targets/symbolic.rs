@@ -0,0 +1,14 @@
+//! Arbitrary memory images through the symbolic executor.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lmc_core::fuzz::check_symbolic;
+use lmc_core::instruction_set::InstructionSet;
+
+fuzz_target!(|memory: Vec<usize>| {
+    let instruction_set = InstructionSet::standard()
+        .with_subroutines()
+        .with_indirect();
+    let size = instruction_set.config().memory_size();
+    check_symbolic(&memory[..memory.len().min(size)], &instruction_set);
+});